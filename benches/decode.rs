@@ -0,0 +1,114 @@
+use std::fs::File;
+use std::io::{Read, Write};
+
+use adv2_splitter::advanced_dupe;
+use adv2_splitter::codec::{AdCodec, AdCodec5, DuplicationInfo, LuaTable, LuaValue};
+use adv2_splitter::lua_ref::LuaValueRef;
+use adv2_splitter::{lua_angle, lua_bool, lua_double, lua_string, lua_table, lua_vector};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+/// Builds a dupe of `count` props, each with the fields a saved `prop_physics` carries.
+fn synthetic_dupe(count: usize) -> Vec<u8> {
+    let mut entities = LuaTable::new();
+    for index in 0..count {
+        let position = index as f64;
+
+        let mut physics_object = LuaTable::new();
+        physics_object.insert(lua_string!("Pos"), lua_vector!(vec![position, 0.0, 12.5]));
+        physics_object.insert(lua_string!("Angle"), lua_angle!(vec![0.0, 90.0, 0.0]));
+        physics_object.insert(lua_string!("Frozen"), lua_bool!(true));
+
+        let mut physics_objects = LuaTable::new();
+        physics_objects.insert(lua_double!(0.0), lua_table!(physics_object));
+
+        let mut entity = LuaTable::new();
+        entity.insert(lua_string!("Class"), lua_string!("prop_physics"));
+        entity.insert(
+            lua_string!("Model"),
+            lua_string!("models/hunter/blocks/cube025x025x025.mdl"),
+        );
+        entity.insert(lua_string!("Pos"), lua_vector!(vec![position, 0.0, 12.5]));
+        entity.insert(lua_string!("Angle"), lua_angle!(vec![0.0, 90.0, 0.0]));
+        entity.insert(lua_string!("PhysicsObjects"), lua_table!(physics_objects));
+
+        entities.insert(lua_double!(index as f64 + 1.0), lua_table!(entity));
+    }
+
+    let mut dupe = LuaTable::new();
+    dupe.insert(lua_string!("Entities"), lua_table!(entities));
+    dupe.insert(lua_string!("Constraints"), lua_table!(LuaTable::new()));
+
+    let mut info = DuplicationInfo::default();
+    info.set_value("name", String::from("bench"));
+    info.set_value("check", String::from("\r\n\t\n"));
+
+    let mut file = Vec::new();
+    AdCodec5::default()
+        .encode(&info, &LuaValue::Table(dupe), &mut file)
+        .expect("Unable to encode dupe");
+    file
+}
+
+/// Decodes a dupe the way it was done before the data block was decompressed as it is read:
+/// the file is read a byte at a time into memory, then the data block is decompressed as a
+/// whole and decoded from that buffer.
+fn decode_byte_by_byte(reader: &mut dyn Read) -> LuaValue {
+    let mut file = Vec::new();
+    let mut byte = [0; 1];
+    while reader.read_exact(&mut byte).is_ok() {
+        file.push(byte[0]);
+    }
+
+    let data = AdCodec5::decompress_data_block(&file).expect("Unable to decompress dupe");
+    AdCodec5::decode_data_block(&data).expect("Unable to decode dupe")
+}
+
+fn decode(c: &mut Criterion) {
+    let file = synthetic_dupe(20_000);
+
+    let path = std::env::temp_dir().join("adv2-splitter-bench.txt");
+    File::create(&path)
+        .and_then(|mut output| output.write_all(&file))
+        .expect("Unable to write dupe");
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(file.len() as u64));
+    group.sample_size(20);
+
+    group.bench_function("file", |b| {
+        b.iter(|| {
+            let input = File::open(&path).expect("Unable to open dupe");
+            advanced_dupe::decode(input).expect("Unable to decode dupe")
+        })
+    });
+
+    group.bench_function("file_byte_by_byte", |b| {
+        b.iter(|| {
+            let mut input = File::open(&path).expect("Unable to open dupe");
+            decode_byte_by_byte(&mut input)
+        })
+    });
+
+    group.bench_function("memory", |b| {
+        b.iter(|| {
+            // Skip the signature and version, as `advanced_dupe::decode` does
+            AdCodec5::default()
+                .decode(&mut &file[5..])
+                .expect("Unable to decode dupe")
+        })
+    });
+
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            let data = AdCodec5::decompress_data_block(&file).expect("Unable to decompress dupe");
+            LuaValueRef::decode(&data)
+                .expect("Unable to decode dupe")
+                .type_name()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,1021 @@
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter};
+use std::io::{Cursor, Write};
+
+use serde::de::value::{MapDeserializer, SeqAccessDeserializer, SeqDeserializer};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    Unexpected, VariantAccess, Visitor,
+};
+use serde::ser::{self, SerializeMap, SerializeStruct, SerializeTupleVariant};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::codec::{AdCodec5, CodecError, LuaTable, LuaValue};
+
+// Names of the newtype structs that mark a `[f64; 3]` as a vector or an angle
+const VECTOR: &str = "$adv2::Vector";
+const ANGLE: &str = "$adv2::Angle";
+
+/// Serializes `value` into the type tagged stream of the version 5 data block, the
+/// uncompressed data that `AdCodec5` writes after the info block.
+///
+/// Rust values are mapped to Lua values as follows:
+///
+/// * Numbers become doubles, which fails for integers a double can not hold exactly.
+/// * Strings and chars become strings, bools become bools.
+/// * Sequences and tuples become arrays.
+/// * Maps and structs become tables, struct fields are keyed by their name.
+/// * `None` and `()` are nil, which Lua tables can not hold, so struct fields and map entries
+///   holding them are left out. Anywhere else they fail.
+/// * Unit variants become the variant name, other variants a table with the variant name as its
+///   only key.
+/// * `[f64; 3]` fields marked with `#[serde(with = "adv2::vector")]` or
+///   `#[serde(with = "adv2::angle")]` become vectors and angles.
+///
+/// # Example
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+///
+/// use adv2_splitter::adv2;
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct Prop {
+///     #[serde(rename = "Class")]
+///     class: String,
+///     #[serde(rename = "Pos", with = "adv2::vector")]
+///     pos: [f64; 3],
+/// }
+///
+/// let prop = Prop {
+///     class: String::from("prop_physics"),
+///     pos: [1.0, 2.0, 3.0],
+/// };
+///
+/// let mut data = Vec::new();
+/// adv2::to_writer(&mut data, &prop).unwrap();
+/// assert_eq!(adv2::from_slice::<Prop>(&data).unwrap(), prop);
+/// ```
+pub fn to_writer<W: Write, T: ?Sized + Serialize>(writer: &mut W, value: &T) -> Result<(), Error> {
+    let value = to_value(value)?;
+
+    AdCodec5::encode_stream(&value, writer).map_err(|e| match e.downcast::<std::io::Error>() {
+        Ok(e) => Error::Io(*e),
+        Err(e) => Error::Message(e.to_string()),
+    })
+}
+
+/// Serializes `value` like `to_writer`, returning the stream as a byte vector.
+pub fn to_vec<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    to_writer(&mut data, value)?;
+
+    Ok(data)
+}
+
+/// Serializes `value` into a `LuaValue`, mapped like in `to_writer`.
+pub fn to_value<T: ?Sized + Serialize>(value: &T) -> Result<LuaValue, Error> {
+    required(value.serialize(ValueSerializer)?, "the value")
+}
+
+/// Deserializes a `T` from the type tagged stream of the version 5 data block, such as written
+/// by `to_writer`. Lua values are mapped back the way `to_writer` maps them, with table
+/// back-references resolved.
+///
+/// # Errors
+///
+/// Returns an `Error::Codec` when the stream is malformed, and an `Error::Message` when it
+/// holds more than one value or one that does not match `T`.
+pub fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    let mut cursor = Cursor::new(data);
+
+    let value = AdCodec5::decode_stream(&mut cursor)
+        .map_err(Error::Codec)?
+        .ok_or_else(|| Error::Message(String::from("expected a value, found a terminator")))?;
+
+    if cursor.position() < data.len() as u64 {
+        return Err(Error::Message(format!(
+            "trailing data after the value at offset {}",
+            cursor.position()
+        )));
+    }
+
+    from_value(*value)
+}
+
+/// Deserializes a `T` from a `LuaValue`, mapped like in `from_slice`.
+pub fn from_value<T: DeserializeOwned>(value: LuaValue) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+/// Serializes and deserializes a `[f64; 3]` as a Lua vector.
+///
+/// Use it with `#[serde(with = "adv2::vector")]`. Other data formats see the three components
+/// as a sequence.
+pub mod vector {
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &[f64; 3], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(super::VECTOR, value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f64; 3], D::Error> {
+        deserializer.deserialize_newtype_struct(super::VECTOR, super::ComponentsVisitor)
+    }
+}
+
+/// Serializes and deserializes a `[f64; 3]` as a Lua angle.
+///
+/// Use it with `#[serde(with = "adv2::angle")]`. Other data formats see the three components
+/// as a sequence.
+pub mod angle {
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &[f64; 3], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(super::ANGLE, value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f64; 3], D::Error> {
+        deserializer.deserialize_newtype_struct(super::ANGLE, super::ComponentsVisitor)
+    }
+}
+
+/// An error serializing or deserializing with the AD2 data format.
+#[derive(Debug)]
+pub enum Error {
+    /// A value that can not be mapped, or that does not match the type being deserialized.
+    Message(String),
+    /// The stream being deserialized is malformed.
+    Codec(CodecError),
+    /// Writing the stream failed.
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Message(message) => write!(f, "{}", message),
+            Error::Codec(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+/// Returns the value, or an error naming `what` when it is nil.
+fn required(value: Option<LuaValue>, what: &str) -> Result<LuaValue, Error> {
+    value.ok_or_else(|| Error::Message(format!("{} can not be nil", what)))
+}
+
+/// Returns `integer` as a double, or an error if the double would not hold it exactly.
+fn integer(integer: i128) -> Result<Option<LuaValue>, Error> {
+    let double = integer as f64;
+
+    if double as i128 != integer {
+        return Err(Error::Message(format!(
+            "{} can not be stored exactly as a double",
+            integer
+        )));
+    }
+
+    Ok(Some(LuaValue::Double(double)))
+}
+
+/// Serializes Rust values into a `LuaValue`, where `None` is nil.
+struct ValueSerializer;
+
+impl Serializer for ValueSerializer {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    type SerializeSeq = ArraySerializer;
+    type SerializeTuple = ArraySerializer;
+    type SerializeTupleStruct = ArraySerializer;
+    type SerializeTupleVariant = VariantSerializer<ArraySerializer>;
+    type SerializeMap = TableSerializer;
+    type SerializeStruct = TableSerializer;
+    type SerializeStructVariant = VariantSerializer<TableSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::Bool(v)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Error> {
+        integer(v.into())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::Double(v.into())))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::Double(v)))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::String(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::String(v.to_string())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Error> {
+        v.serialize(self)
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::String(variant.to_string())))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        if name != VECTOR && name != ANGLE {
+            return value.serialize(self);
+        }
+
+        let components = match value.serialize(ValueSerializer)? {
+            Some(LuaValue::Array(items)) if items.len() == 3 => items
+                .iter()
+                .map(LuaValue::as_double)
+                .collect::<Option<Vec<f64>>>(),
+            _ => None,
+        };
+        let components =
+            components.ok_or_else(|| Error::Message(String::from("expected three doubles")))?;
+
+        Ok(Some(match name {
+            VECTOR => LuaValue::Vector(components),
+            _ => LuaValue::Angle(components),
+        }))
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        let mut table = LuaTable::new();
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            table.insert(LuaValue::String(variant.to_string()), value);
+        }
+
+        Ok(Some(LuaValue::Table(table)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(ArraySerializer {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(TableSerializer {
+            table: LuaTable::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+/// Serializes sequences and tuples into an array.
+struct ArraySerializer {
+    items: Vec<LuaValue>,
+}
+
+impl ser::SerializeSeq for ArraySerializer {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let item = required(value.serialize(ValueSerializer)?, "an array item")?;
+        self.items.push(item);
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::Array(self.items)))
+    }
+}
+
+impl ser::SerializeTuple for ArraySerializer {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ArraySerializer {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Serializes maps and structs into a table, leaving out entries whose value is nil.
+struct TableSerializer {
+    table: LuaTable,
+    key: Option<LuaValue>,
+}
+
+impl SerializeMap for TableSerializer {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(required(key.serialize(ValueSerializer)?, "a table key")?);
+
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::Message(String::from("a table value needs a key")))?;
+
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.table.insert(key, value);
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(LuaValue::Table(self.table)))
+    }
+}
+
+impl SerializeStruct for TableSerializer {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.serialize_entry(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        SerializeMap::end(self)
+    }
+}
+
+/// Serializes a tuple or struct variant into a table with the variant name as its only key.
+struct VariantSerializer<T> {
+    variant: &'static str,
+    inner: T,
+}
+
+/// Returns a table with `value` under the `variant` name, or nil when `value` is nil.
+fn wrap_variant(variant: &str, value: Option<LuaValue>) -> Option<LuaValue> {
+    let mut table = LuaTable::new();
+    table.insert(LuaValue::String(variant.to_string()), value?);
+
+    Some(LuaValue::Table(table))
+}
+
+impl SerializeTupleVariant for VariantSerializer<ArraySerializer> {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        let value = ser::SerializeSeq::end(self.inner)?;
+
+        Ok(wrap_variant(self.variant, value))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<TableSerializer> {
+    type Ok = Option<LuaValue>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        let value = SerializeMap::end(self.inner)?;
+
+        Ok(wrap_variant(self.variant, value))
+    }
+}
+
+/// Vectors and angles are written as a newtype struct around their components, which the
+/// AD2 format turns back into a vector or an angle and other formats see as a sequence.
+impl Serialize for LuaValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LuaValue::String(string) => serializer.serialize_str(string),
+            LuaValue::Double(double) => serializer.serialize_f64(*double),
+            LuaValue::Bool(bool) => serializer.serialize_bool(*bool),
+            LuaValue::Array(array) => array.serialize(serializer),
+            LuaValue::Table(table) => {
+                let mut map = serializer.serialize_map(Some(table.len()))?;
+                for (key, value) in table {
+                    map.serialize_entry(key, value)?;
+                }
+
+                map.end()
+            }
+            LuaValue::Vector(vector) => serializer.serialize_newtype_struct(VECTOR, vector),
+            LuaValue::Angle(angle) => serializer.serialize_newtype_struct(ANGLE, angle),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LuaValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(LuaValueVisitor)
+    }
+}
+
+struct LuaValueVisitor;
+
+impl<'de> Visitor<'de> for LuaValueVisitor {
+    type Value = LuaValue;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a Lua value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<LuaValue, E> {
+        Ok(LuaValue::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<LuaValue, E> {
+        Ok(LuaValue::Double(v as f64))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<LuaValue, E> {
+        Ok(LuaValue::Double(v as f64))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<LuaValue, E> {
+        Ok(LuaValue::Double(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<LuaValue, E> {
+        Ok(LuaValue::String(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<LuaValue, E> {
+        Ok(LuaValue::String(v))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<LuaValue, D::Error> {
+        LuaValue::deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<LuaValue, D::Error> {
+        LuaValue::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LuaValue, A::Error> {
+        let mut array = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            array.push(item);
+        }
+
+        Ok(LuaValue::Array(array))
+    }
+
+    /// A map with the vector or angle marker as its only key is a vector or an angle, see
+    /// `deserialize_any` of `LuaValue`.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<LuaValue, A::Error> {
+        let mut table = LuaTable::new();
+
+        while let Some(key) = map.next_key::<LuaValue>()? {
+            match key.as_str() {
+                Some(VECTOR) if table.is_empty() => return Ok(LuaValue::Vector(map.next_value()?)),
+                Some(ANGLE) if table.is_empty() => return Ok(LuaValue::Angle(map.next_value()?)),
+                _ => {
+                    let value = map.next_value()?;
+                    table.insert(key, value);
+                }
+            }
+        }
+
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Reads the three components of a vector or an angle.
+struct ComponentsVisitor;
+
+impl<'de> Visitor<'de> for ComponentsVisitor {
+    type Value = [f64; 3];
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("three doubles")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<[f64; 3], D::Error> {
+        <[f64; 3]>::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<[f64; 3], A::Error> {
+        <[f64; 3]>::deserialize(SeqAccessDeserializer::new(seq))
+    }
+}
+
+impl LuaValue {
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self {
+            LuaValue::String(string) => Unexpected::Str(string),
+            LuaValue::Double(double) => Unexpected::Float(*double),
+            LuaValue::Bool(bool) => Unexpected::Bool(*bool),
+            LuaValue::Array(_) => Unexpected::Seq,
+            LuaValue::Table(_) => Unexpected::Map,
+            LuaValue::Vector(_) => Unexpected::Other("vector"),
+            LuaValue::Angle(_) => Unexpected::Other("angle"),
+        }
+    }
+}
+
+fn visit_array<'de, V: Visitor<'de>>(array: Vec<LuaValue>, visitor: V) -> Result<V::Value, Error> {
+    let mut deserializer = SeqDeserializer::new(array.into_iter());
+    let value = visitor.visit_seq(&mut deserializer)?;
+    deserializer.end()?;
+
+    Ok(value)
+}
+
+fn visit_table<'de, V: Visitor<'de>, I>(entries: I, visitor: V) -> Result<V::Value, Error>
+where
+    I: Iterator<Item = (LuaValue, LuaValue)>,
+{
+    let mut deserializer = MapDeserializer::new(entries);
+    let value = visitor.visit_map(&mut deserializer)?;
+    deserializer.end()?;
+
+    Ok(value)
+}
+
+/// Deserializes a signed integer from a double holding a whole number.
+macro_rules! deserialize_signed {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self {
+                    LuaValue::Double(double)
+                        if double.fract() == 0.0
+                            && double >= i64::MIN as f64
+                            && double < i64::MAX as f64 =>
+                    {
+                        visitor.visit_i64(double as i64)
+                    }
+                    other => other.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+/// Deserializes an unsigned integer from a double holding a whole number.
+macro_rules! deserialize_unsigned {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self {
+                    LuaValue::Double(double)
+                        if double.fract() == 0.0 && double >= 0.0 && double < u64::MAX as f64 =>
+                    {
+                        visitor.visit_u64(double as u64)
+                    }
+                    other => other.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+/// Deserializes Rust values out of a `LuaValue`, mapped like in `from_slice`.
+impl<'de> Deserializer<'de> for LuaValue {
+    type Error = Error;
+
+    /// Vectors and angles are visited as a map with a marker key, so that deserializing a
+    /// `LuaValue` tells them apart from arrays.
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            LuaValue::String(string) => visitor.visit_string(string),
+            LuaValue::Double(double) => visitor.visit_f64(double),
+            LuaValue::Bool(bool) => visitor.visit_bool(bool),
+            LuaValue::Array(array) => visit_array(array, visitor),
+            LuaValue::Table(table) => visit_table(table.into_iter(), visitor),
+            LuaValue::Vector(vector) => visit_table(
+                std::iter::once((
+                    LuaValue::String(VECTOR.to_string()),
+                    LuaValue::Array(doubles(vector)),
+                )),
+                visitor,
+            ),
+            LuaValue::Angle(angle) => visit_table(
+                std::iter::once((
+                    LuaValue::String(ANGLE.to_string()),
+                    LuaValue::Array(doubles(angle)),
+                )),
+                visitor,
+            ),
+        }
+    }
+
+    deserialize_signed! { deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 }
+    deserialize_unsigned! { deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // Nil is never stored, so a value that is there is always some
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            LuaValue::Vector(components) | LuaValue::Angle(components) => {
+                visit_array(doubles(components), visitor)
+            }
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            LuaValue::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            LuaValue::Table(table) if table.len() == 1 => {
+                let (variant, value) = table.into_iter().next().expect("The table has an entry");
+                visitor.visit_enum(EnumDeserializer { variant, value })
+            }
+            other => Err(de::Error::invalid_type(
+                other.unexpected(),
+                &"a variant name or a table with a single entry",
+            )),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct map struct identifier
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for LuaValue {
+    type Deserializer = LuaValue;
+
+    fn into_deserializer(self) -> LuaValue {
+        self
+    }
+}
+
+fn doubles(components: Vec<f64>) -> Vec<LuaValue> {
+    components.into_iter().map(LuaValue::Double).collect()
+}
+
+/// A variant stored as a table with the variant name as its only key.
+struct EnumDeserializer {
+    variant: LuaValue,
+    value: LuaValue,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = LuaValue;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, LuaValue), Error> {
+        Ok((seed.deserialize(self.variant)?, self.value))
+    }
+}
+
+impl<'de> VariantAccess<'de> for LuaValue {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Err(de::Error::invalid_type(
+            self.unexpected(),
+            &"a unit variant",
+        ))
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::error::Error;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::adv2::{self, from_slice, from_value, to_value, to_vec};
+    use crate::codec::{AdCodec5, LuaTable, LuaValue};
+    use crate::{lua_array, lua_string, lua_table};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Prop,
+        Weld { strength: f64 },
+        Named(String),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Entity {
+        #[serde(rename = "Class")]
+        class: String,
+        #[serde(rename = "Pos", with = "adv2::vector")]
+        pos: [f64; 3],
+        #[serde(rename = "Angle", with = "adv2::angle")]
+        angle: [f64; 3],
+        #[serde(rename = "Skin")]
+        skin: Option<u8>,
+        kinds: Vec<Kind>,
+        mods: BTreeMap<u32, bool>,
+    }
+
+    fn entity() -> Entity {
+        Entity {
+            class: String::from("prop_physics"),
+            pos: [1.0, 2.0, 3.0],
+            angle: [0.0, 90.0, 0.0],
+            skin: None,
+            kinds: vec![
+                Kind::Prop,
+                Kind::Weld { strength: 2.5 },
+                Kind::Named(String::from("rope")),
+            ],
+            mods: BTreeMap::from([(1, true), (7, false)]),
+        }
+    }
+
+    #[test]
+    fn test_serialize_struct() -> Result<(), Box<dyn Error>> {
+        let value = to_value(&entity())?;
+
+        let mut weld = LuaTable::new();
+        weld.insert(lua_string!("strength"), LuaValue::Double(2.5));
+        let mut named = LuaTable::new();
+        named.insert(lua_string!("Named"), lua_string!("rope"));
+        let mut mods = LuaTable::new();
+        mods.insert(LuaValue::Double(1.0), LuaValue::Bool(true));
+        mods.insert(LuaValue::Double(7.0), LuaValue::Bool(false));
+
+        let mut expected = LuaTable::new();
+        expected.insert(lua_string!("Class"), lua_string!("prop_physics"));
+        expected.insert(lua_string!("Pos"), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+        expected.insert(lua_string!("Angle"), LuaValue::Angle(vec![0.0, 90.0, 0.0]));
+        expected.insert(
+            lua_string!("kinds"),
+            lua_array!(vec![
+                lua_string!("Prop"),
+                lua_table!(LuaTable::from([(lua_string!("Weld"), lua_table!(weld))])),
+                lua_table!(named),
+            ]),
+        );
+        expected.insert(lua_string!("mods"), lua_table!(mods));
+        let expected = lua_table!(expected);
+
+        // The skin is nil, so it is left out of the table
+        assert_eq!(value, expected);
+
+        // The stream is the one the codec writes for the same value
+        let mut data = Vec::new();
+        AdCodec5::encode_stream(&expected, &mut data)?;
+        assert_eq!(to_vec(&entity())?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_struct() -> Result<(), Box<dyn Error>> {
+        let mut with_skin = entity();
+        with_skin.skin = Some(3);
+
+        for entity in [entity(), with_skin] {
+            assert_eq!(from_slice::<Entity>(&to_vec(&entity)?)?, entity);
+        }
+
+        // A double with a fraction is not an integer
+        let mut value = to_value(&entity())?;
+        value
+            .as_table_mut()
+            .unwrap()
+            .insert(lua_string!("Skin"), LuaValue::Double(1.5));
+        assert!(from_value::<Entity>(value).is_err());
+
+        // Nil can not be stored in an array, and a stream holds a single value
+        assert!(to_vec(&vec![Some(1.0), None]).is_err());
+        assert!(from_slice::<bool>(&[253, 253]).is_err());
+        assert!(to_vec(&(1u64 << 60 | 1)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lua_value_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut table = LuaTable::new();
+        table.insert(LuaValue::Double(2.0), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+        table.insert(lua_string!("Angle"), LuaValue::Angle(vec![4.0, 5.0, 6.0]));
+        table.insert(
+            LuaValue::Bool(true),
+            lua_array!(vec![lua_string!("a"), LuaValue::Double(-0.5)]),
+        );
+        let value = lua_table!(table);
+
+        let data = to_vec(&value)?;
+        let decoded: LuaValue = from_slice(&data)?;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", value));
+
+        // Other formats see vectors and angles as sequences
+        assert_eq!(
+            serde_json::to_string(&LuaValue::Vector(vec![1.0, 2.0, 3.0]))?,
+            "[1.0,2.0,3.0]"
+        );
+
+        Ok(())
+    }
+}
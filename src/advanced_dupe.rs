@@ -1,12 +1,31 @@
 use std::error::Error;
-use std::fs::File;
+use std::fmt::{Display, Formatter};
 use std::io::{BufReader, Read, Write};
 
-use crate::codec::{AdCodec, AdCodec5, CodecError, DuplicationInfo, DuplicationResult, LuaValue};
+use chrono::Local;
 
-pub fn decode(file: File) -> Result<DuplicationResult, Box<dyn Error>> {
-    let mut reader = BufReader::new(file);
+use crate::codec::{
+    AdCodec, AdCodec4, AdCodec5, CodecError, DuplicationInfo, DuplicationResult, EncodeOptions,
+    LineEndings, LuaValue,
+};
+use crate::lua_ref::LuaValueRef;
 
+// Offset of the version byte, following the signature
+const VERSION_OFFSET: usize = 4;
+
+/// Decodes a dupe of any supported codec version from a file, stdin or anything else that can
+/// be read.
+pub fn decode<R: Read>(reader: R) -> Result<DuplicationResult, Box<dyn Error>> {
+    decode_from(&mut BufReader::new(reader), false)
+}
+
+/// Decodes a dupe like `decode`, but also fails when the info block is not exactly as the
+/// game writes it, such as after a text mode transfer translated its line endings.
+pub fn decode_strict<R: Read>(reader: R) -> Result<DuplicationResult, Box<dyn Error>> {
+    decode_from(&mut BufReader::new(reader), true)
+}
+
+fn decode_from(reader: &mut dyn Read, strict: bool) -> Result<DuplicationResult, Box<dyn Error>> {
     // First we will read the header
     let mut header = [0; 4];
     reader.read_exact(&mut header)?;
@@ -16,8 +35,25 @@ pub fn decode(file: File) -> Result<DuplicationResult, Box<dyn Error>> {
     reader.read_exact(&mut version)?;
     let version = version[0];
 
+    // Versions 1 to 3 compressed the data block with Advanced Duplicator 2's own Huffman and LZW
+    // coder, whose static code table is not implemented here, so they are not supported yet
     let codec = match version {
-        5 => Box::new(AdCodec5 {}) as Box<dyn AdCodec>,
+        1..=3 => {
+            return Err(Box::new(CodecError::UnsupportedCodec(format!(
+                "{}, whose Huffman and LZW compression is not implemented",
+                version
+            ))))
+        }
+        4 => {
+            let mut codec = AdCodec4::default();
+            codec.set_strict(strict);
+            Box::new(codec) as Box<dyn AdCodec>
+        }
+        5 => {
+            let mut codec = AdCodec5::default();
+            codec.set_strict(strict);
+            Box::new(codec) as Box<dyn AdCodec>
+        }
         _ => return Err(Box::new(CodecError::UnsupportedCodec(version.to_string()))),
     };
 
@@ -25,7 +61,7 @@ pub fn decode(file: File) -> Result<DuplicationResult, Box<dyn Error>> {
         return Err(Box::new(CodecError::InvalidHeader));
     }
 
-    let result = codec.decode(&mut reader);
+    let result = codec.decode(reader)?;
 
     Ok(result)
 }
@@ -35,22 +71,293 @@ pub fn encode(
     lua_value: &LuaValue,
     stream: &mut dyn Write,
 ) -> Result<(), Box<dyn Error>> {
-    let codec = AdCodec5 {};
+    encode_with_options(dupe_info, lua_value, &EncodeOptions::default(), stream)
+}
+
+/// Encodes a dupe with the latest codec version like `encode`, with the given options such as
+/// the compression level.
+pub fn encode_with_options(
+    dupe_info: &DuplicationInfo,
+    lua_value: &LuaValue,
+    options: &EncodeOptions,
+    stream: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let codec = AdCodec5::with_options(*options);
     codec.encode(dupe_info, lua_value, stream)?;
 
     Ok(())
 }
 
+/// Returns the info block for part `part` of `count`, counted from 1, split from the dupe file
+/// `source` with the info block `info`. The date and time are stamped with the current local
+/// time, the name is replaced when `name` is given, and the `source` and `part` keys record where
+/// the part came from, e.g. `part=2/5`. The size is set by encoding with
+/// `EncodeOptions::update_size`.
+pub fn part_info(
+    info: &DuplicationInfo,
+    source: &str,
+    part: usize,
+    count: usize,
+    name: Option<&str>,
+) -> DuplicationInfo {
+    let mut info = info.clone();
+    info.set_date_time(&Local::now());
+
+    if let Some(name) = name {
+        info.set_player_name(name.to_string());
+    }
+
+    info.set_value("source", source.to_string());
+    info.set_value("part", format!("{}/{}", part, count));
+
+    info
+}
+
+/// Decodes a dupe of any supported codec version and writes it back out with the latest one.
+pub fn upgrade<R: Read>(reader: R, stream: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let result = decode(reader)?;
+    AdCodec5::default().encode_result(&result, stream)
+}
+
+/// Decodes a dupe of any supported codec version and returns its info block along with the
+/// decompressed data block, which can be inspected or edited and then given to `pack`.
+pub fn unpack<R: Read>(mut reader: R) -> Result<(DuplicationInfo, Vec<u8>), Box<dyn Error>> {
+    let mut original = Vec::new();
+    reader.read_to_end(&mut original)?;
+    let result = decode_from(&mut original.as_slice(), false)?;
+
+    // Every supported version lays out the info and data blocks the same way
+    let data = AdCodec5::decompress_data_block(&original)?;
+
+    Ok((result.info().clone(), data))
+}
+
+/// Writes an uncompressed data block, as returned by `unpack`, as a version 5 dupe with the
+/// given info block. The data block is written as it is once it is known to decode.
+pub fn pack(
+    dupe_info: &DuplicationInfo,
+    data: &[u8],
+    options: &EncodeOptions,
+    stream: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    LuaValueRef::decode(data)?;
+
+    AdCodec5::with_options(*options).encode_data_block(dupe_info, data, stream)
+}
+
+/// Undoes the line ending translation of a text mode transfer, which shows in the check value
+/// of the info block.
+///
+/// # Arguments
+///
+/// * `reader` - The dupe to repair.
+///
+/// # Returns
+///
+/// The repaired file, or `None` if the file was not damaged.
+///
+/// # Errors
+///
+/// Fails if the file is damaged in any other way, or if the translation lost bytes. Only a
+/// translation from LF to CRLF can be undone, one from CRLF to LF drops CR bytes from the
+/// compressed data that can not be told apart from the LF bytes that were already there.
+pub fn repair<R: Read>(mut reader: R) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut original = Vec::new();
+    reader.read_to_end(&mut original)?;
+
+    let error = match decode_from(&mut original.as_slice(), true) {
+        Ok(_) => return Ok(None),
+        Err(error) => error,
+    };
+
+    match error.downcast_ref::<CodecError>() {
+        Some(CodecError::LineEndings {
+            translation: LineEndings::LfToCrlf,
+            ..
+        }) => {}
+        _ => return Err(error),
+    }
+
+    // Every LF was preceded by an added CR, other CR bytes were there to begin with
+    let mut repaired = Vec::with_capacity(original.len());
+    for (i, byte) in original.iter().enumerate() {
+        if *byte != b'\r' || original.get(i + 1) != Some(&b'\n') {
+            repaired.push(*byte);
+        }
+    }
+
+    decode_from(&mut repaired.as_slice(), true)?;
+
+    Ok(Some(repaired))
+}
+
+/// A difference between a dupe file and the file written by encoding it again.
+#[derive(Debug, PartialEq)]
+pub enum Divergence {
+    /// The file was written with an older codec version, so the data block differs too
+    Version(u8),
+    /// The first byte at which the info blocks differ, counted from the start of the file
+    Info { offset: usize },
+    /// The first value that differs once both data blocks are decoded
+    Structure { path: String },
+    /// The first byte at which the decompressed data blocks differ and the value it is part of
+    Data { offset: usize, path: String },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Divergence::Version(version) => {
+                write!(f, "version {} is written as version 5", version)
+            }
+            Divergence::Info { offset } => write!(f, "info block differs at offset {}", offset),
+            Divergence::Structure { path } => write!(f, "value differs at ({})", path),
+            Divergence::Data { offset, path } => {
+                write!(f, "data block differs at offset {} ({})", offset, path)
+            }
+        }
+    }
+}
+
+/// Decodes a dupe, encodes it again and compares the two files. The info blocks and the
+/// decompressed data blocks are compared byte by byte, and the data blocks are also decoded
+/// and compared value by value. The compressed data itself is not compared, since the same
+/// data can be compressed in more than one way.
+///
+/// # Arguments
+///
+/// * `reader` - The dupe to verify.
+///
+/// # Returns
+///
+/// The first difference found by each comparison, which is empty when the dupe round trips
+/// exactly.
+pub fn verify<R: Read>(mut reader: R) -> Result<Vec<Divergence>, Box<dyn Error>> {
+    let mut original = Vec::new();
+    reader.read_to_end(&mut original)?;
+    let result = decode_from(&mut original.as_slice(), false)?;
+
+    let mut encoded = Vec::new();
+    AdCodec5::default().encode_result(&result, &mut encoded)?;
+    let encoded_result = decode_from(&mut encoded.as_slice(), false)?;
+
+    let mut divergences = Vec::new();
+
+    let version = original[VERSION_OFFSET];
+    if version != encoded[VERSION_OFFSET] {
+        divergences.push(Divergence::Version(version));
+    }
+
+    // Both files decoded, so both have a data block
+    let original_start = AdCodec5::data_block_offset(&original).unwrap();
+    let encoded_start = AdCodec5::data_block_offset(&encoded).unwrap();
+
+    let info_start = VERSION_OFFSET + 1;
+    if let Some(offset) = first_mismatch(
+        &original[info_start..original_start],
+        &encoded[info_start..encoded_start],
+    ) {
+        divergences.push(Divergence::Info {
+            offset: info_start + offset,
+        });
+    }
+
+    if let Some(path) = first_difference(result.value(), encoded_result.value(), &mut Vec::new()) {
+        divergences.push(Divergence::Structure { path });
+    }
+
+    let original_data = AdCodec5::decompress_data_block(&original)?;
+    let encoded_data = AdCodec5::decompress_data_block(&encoded)?;
+
+    if let Some(offset) = first_mismatch(&original_data, &encoded_data) {
+        // Version 4 data blocks have no short strings
+        let path = AdCodec5::path_at(&original_data, offset, version != 4);
+        divergences.push(Divergence::Data { offset, path });
+    }
+
+    Ok(divergences)
+}
+
+/// Returns the first offset at which `a` and `b` differ, including where the shorter ends.
+fn first_mismatch(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| a != b)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+/// Returns the path of the first value that differs between `a` and `b`. Doubles are compared
+/// by their bits so that NaN equals itself, and tables must also have their keys in the same
+/// order.
+fn first_difference(a: &LuaValue, b: &LuaValue, path: &mut Vec<String>) -> Option<String> {
+    let bits = |values: &[f64]| {
+        values
+            .iter()
+            .map(|value| value.to_bits())
+            .collect::<Vec<_>>()
+    };
+
+    let same = match (a, b) {
+        (LuaValue::Array(a), LuaValue::Array(b)) => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                // Lua arrays are indexed from 1
+                path.push((i + 1).to_string());
+                let difference = first_difference(a, b, path);
+                path.pop();
+
+                if difference.is_some() {
+                    return difference;
+                }
+            }
+
+            a.len() == b.len()
+        }
+        (LuaValue::Table(a), LuaValue::Table(b)) => {
+            for ((a_key, a_value), (b_key, b_value)) in a.iter().zip(b) {
+                path.push(AdCodec5::path_segment(a_key));
+                if a_key != b_key {
+                    return Some(path.join("."));
+                }
+
+                let difference = first_difference(a_value, b_value, path);
+                path.pop();
+
+                if difference.is_some() {
+                    return difference;
+                }
+            }
+
+            a.len() == b.len()
+        }
+        (LuaValue::Double(a), LuaValue::Double(b)) => a.to_bits() == b.to_bits(),
+        (LuaValue::Vector(a), LuaValue::Vector(b)) | (LuaValue::Angle(a), LuaValue::Angle(b)) => {
+            bits(a) == bits(b)
+        }
+        (a, b) => a == b,
+    };
+
+    (!same).then(|| path.join("."))
+}
+
+/// Returns every key and value of the info block on its own indented line, in the order they are
+/// written, with control characters such as those of the check value escaped.
+pub fn format_info(info: &DuplicationInfo) -> String {
+    info.values()
+        .map(|(key, value)| format!("\t{}: {}\n", key, value.escape_debug()))
+        .collect()
+}
+
+/// Prints every key and value of the info block as given by [`format_info`].
+pub fn print_info(info: &DuplicationInfo) {
+    print!("{}", format_info(info));
+}
+
 pub fn print_result(result: &DuplicationResult) {
     // First we will display the info
     let info = result.info();
 
     println!("Results for duplication:");
-    println!("\tSize: {}", info.size());
-    println!("\tTimeZone: {}", info.time_zone());
-    println!("\tDate: {}", info.date());
-    println!("\tTime: {}", info.time());
-    println!("\tPlayerName: {}", info.player_name());
+    print_info(info);
 
     // Then we will display the result
     println!("\tDupe data: ");
@@ -83,13 +390,13 @@ fn print_value(value: &LuaValue, tab: usize) {
         }
         LuaValue::Vector(vector) => {
             println!("{}Vector: ", tab_str);
-            println!("{}\tX: {:.4}", tab_str, vector.get(0).unwrap());
+            println!("{}\tX: {:.4}", tab_str, vector.first().unwrap());
             println!("{}\tY: {:.4}", tab_str, vector.get(1).unwrap());
             println!("{}\tZ: {:.4}", tab_str, vector.get(2).unwrap());
         }
         LuaValue::Angle(angle) => {
             println!("{}Angle: ", tab_str);
-            println!("{}\tPitch: {:.4}", tab_str, angle.get(0).unwrap());
+            println!("{}\tPitch: {:.4}", tab_str, angle.first().unwrap());
             println!("{}\tYaw: {:.4}", tab_str, angle.get(1).unwrap());
             println!("{}\tRoll: {:.4}", tab_str, angle.get(2).unwrap());
         }
@@ -98,3 +405,181 @@ fn print_value(value: &LuaValue, tab: usize) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::Write;
+
+    use crate::advanced_dupe::{decode, pack, part_info, repair, unpack, verify, Divergence};
+    use crate::codec::{
+        AdCodec, AdCodec5, CodecError, DuplicationInfo, EncodeOptions, LuaTable, LuaValue,
+    };
+    use crate::{lua_string, lua_table};
+
+    fn write_dupe(name: &str, version: u8, data: &[u8]) -> Result<File, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("adv2-splitter-{}.txt", name));
+
+        let mut file = File::create(&path)?;
+        file.write_all(b"AD2F")?;
+        file.write_all(&[version, 10])?;
+        file.write_all(b"name\x01test\x01check\x01\r\n\t\n\x01")?;
+        file.write_all(&[2, 10])?;
+        file.write_all(&gmod_lzma::compress(data, 9).expect("Unable to compress"))?;
+
+        Ok(File::open(path)?)
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), Box<dyn Error>> {
+        // { Key = { 1.5 }, Other = <reference to the array> }
+        let mut data: Vec<u8> = vec![255, 3, b'K', b'e', b'y', 254, 251];
+        data.extend(1.5f64.to_le_bytes());
+        data.extend([246, 5, b'O', b't', b'h', b'e', b'r', 247, 2, 0, 246]);
+
+        assert_eq!(verify(write_dupe("verify-5", 5, &data)?)?, vec![]);
+
+        // Version 4 writes the same strings in the long form
+        let mut data: Vec<u8> = vec![255, 248, 3, 0, 0, 0, b'K', b'e', b'y', 254, 251];
+        data.extend(1.5f64.to_le_bytes());
+        data.extend([246, 246]);
+
+        assert_eq!(
+            verify(write_dupe("verify-4", 4, &data)?)?,
+            vec![
+                Divergence::Version(4),
+                Divergence::Data {
+                    offset: 1,
+                    path: String::new(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_versions_are_unsupported() {
+        for version in 1..=3 {
+            let mut file = b"AD2F".to_vec();
+            file.push(version);
+
+            match decode(file.as_slice()) {
+                Err(error) => assert!(matches!(
+                    error.downcast_ref::<CodecError>(),
+                    Some(CodecError::UnsupportedCodec(_))
+                )),
+                Ok(_) => panic!("Decoded a version {} dupe", version),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_from_memory() -> Result<(), Box<dyn Error>> {
+        let mut table = LuaTable::new();
+        table.insert(lua_string!("Key"), LuaValue::Double(1.5));
+
+        let mut info = DuplicationInfo::default();
+        info.set_value("check", String::from("\r\n\t\n"));
+
+        let mut file = Vec::new();
+        AdCodec5::default().encode(&info, &lua_table!(table.clone()), &mut file)?;
+
+        // Anything that can be read is decoded, such as a slice or stdin
+        let result = decode(file.as_slice())?;
+        assert_eq!(result.value(), &lua_table!(table));
+        assert_eq!(verify(file.as_slice())?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_and_pack() -> Result<(), Box<dyn Error>> {
+        // { Key = "Value" }, with a long string written where a short one would do
+        let data: Vec<u8> = vec![
+            255, 3, b'K', b'e', b'y', 248, 5, 0, 0, 0, b'V', b'a', b'l', b'u', b'e', 246,
+        ];
+
+        let (info, unpacked) = unpack(write_dupe("unpack", 5, &data)?)?;
+        assert_eq!(unpacked, data);
+        assert_eq!(info.player_name(), "test");
+
+        let options = EncodeOptions {
+            compression_level: 0,
+            ..Default::default()
+        };
+        let mut file = Vec::new();
+        pack(&info, &unpacked, &options, &mut file)?;
+
+        // The data block is packed as it was, not re-encoded
+        assert_eq!(AdCodec5::decompress_data_block(&file)?, data);
+        let result = AdCodec5::default().decode(&mut &file[5..])?;
+        assert_eq!(
+            result.info().values().collect::<Vec<_>>(),
+            info.values().collect::<Vec<_>>()
+        );
+
+        // A data block that does not decode is not packed
+        assert!(pack(&info, &data[..4], &options, &mut Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair() -> Result<(), Box<dyn Error>> {
+        // { Key = "\r\n" }
+        let data: Vec<u8> = vec![255, 3, b'K', b'e', b'y', 2, b'\r', b'\n', 246];
+
+        let path = std::env::temp_dir().join("adv2-splitter-repair.txt");
+        write_dupe("repair", 5, &data)?;
+        let original = std::fs::read(&path)?;
+
+        assert_eq!(repair(File::open(&path)?)?, None);
+
+        // Sent from a Linux server to Windows in text mode
+        let mut translated = Vec::new();
+        for byte in &original {
+            if *byte == b'\n' {
+                translated.push(b'\r');
+            }
+            translated.push(*byte);
+        }
+        std::fs::write(&path, &translated)?;
+        assert_eq!(repair(File::open(&path)?)?, Some(original.clone()));
+
+        // Sent the other way, which drops every CR before a LF
+        let mut translated = Vec::new();
+        for (i, byte) in original.iter().enumerate() {
+            if *byte != b'\r' || original.get(i + 1) != Some(&b'\n') {
+                translated.push(*byte);
+            }
+        }
+        std::fs::write(&path, &translated)?;
+        assert!(repair(File::open(&path)?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_part_info() {
+        let mut info = DuplicationInfo::default();
+        info.set_value("name", String::from("test"));
+        info.set_value("date", String::from("01 January 2020"));
+        info.set_value("check", String::from("\r\n\t\n"));
+
+        let part = part_info(&info, "build.txt", 2, 5, None);
+        let values: Vec<(&str, &str)> = part.values().collect();
+        assert_eq!(values[0], ("name", "test"));
+        assert_ne!(values[1], ("date", "01 January 2020"));
+        assert_eq!(values[2], ("check", "\r\n\t\n"));
+        assert_eq!(
+            values[values.len() - 2..],
+            [("source", "build.txt"), ("part", "2/5")]
+        );
+
+        let part = part_info(&info, "build.txt", 1, 5, Some("friend"));
+        assert_eq!(part.player_name(), "friend");
+        assert_eq!(info.player_name(), "test");
+    }
+}
@@ -1,45 +1,78 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, LinkedList};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
-use std::io::{Cursor, Read, Write};
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 
+use chrono::{DateTime, TimeZone};
+use indexmap::IndexMap;
+use lzma_rust2::LzmaReader;
+
+/// A version of the dupe file format, able to decode and encode everything that follows the
+/// signature and version at the start of a file.
 pub trait AdCodec {
-    /// Returns a Duplication Result that will contain the parsed info block and
+    /// Returns a Duplication Result that will contain the parsed info block and the decoded
+    /// data block
     ///
     /// # Arguments
-    /// * `reader` - A reader to decode and parse into a result struct
+    /// * `reader` - A reader positioned after the signature and version, to decode and parse
+    ///   into a result struct
     ///
     /// # Examples
-    /// ```
-    /// use codec;
+    /// ```no_run
+    /// use std::fs::File;
+    /// use std::io::{BufReader, Read};
+    ///
+    /// use adv2_splitter::advanced_dupe;
+    /// use adv2_splitter::codec::{AdCodec, AdCodec5};
+    ///
     /// let codec = AdCodec5::default();
     ///
     /// let input_file = File::open("test.txt").expect("Unable to open file");
     /// let mut reader = BufReader::new(input_file);
-    /// let result = codec.decode(input_file)
-    /// advanced_dupe::print_result(result);
+    ///
+    /// // Skip the signature and version
+    /// let mut header = [0; 5];
+    /// reader.read_exact(&mut header).expect("Unable to read header");
+    ///
+    /// let result = codec.decode(&mut reader).expect("Unable to decode file");
+    /// advanced_dupe::print_result(&result);
     /// ```
-    fn decode(&self, reader: &mut dyn Read) -> DuplicationResult;
+    fn decode(&self, reader: &mut dyn Read) -> Result<DuplicationResult, CodecError>;
 
+    /// Writes a whole dupe file, including the signature and version
+    ///
     /// # Arguments
     /// * `dupe_info` - Duplication metadata, note the map must be populated
     /// * `lua_value` - This is the LuaValue as a table
     /// * `stream` - This is the output stream to write to, typically a file
     ///
     /// # Examples
-    /// ```
-    /// use codec;
+    /// ```no_run
+    /// use std::fs::File;
+    /// use std::io::{BufReader, BufWriter, Read};
+    ///
+    /// use adv2_splitter::codec::{AdCodec, AdCodec5};
+    ///
     /// let codec = AdCodec5::default();
     ///
     /// let input_file = File::open("test.txt").expect("Unable to open file");
     /// let mut reader = BufReader::new(input_file);
-    /// let result = codec.decode(input_file)
+    ///
+    /// // Skip the signature and version
+    /// let mut header = [0; 5];
+    /// reader.read_exact(&mut header).expect("Unable to read header");
+    ///
+    /// let result = codec.decode(&mut reader).expect("Unable to decode file");
     ///
     /// let output_file = File::create("test2.txt").expect("Unable to open output file");
     /// let mut out_stream = BufWriter::new(output_file);
     ///
-    /// codec.encode(result.info(), result.value(), out_stream).expect("Unable to encode table");
+    /// codec
+    ///     .encode(result.info(), result.value(), &mut out_stream)
+    ///     .expect("Unable to encode table");
     /// ```
     fn encode(
         &self,
@@ -57,9 +90,20 @@ pub trait AdCodec {
 pub struct DuplicationResult {
     info: DuplicationInfo,
     value: LuaValue,
+    /// The back-references read from the data block, by the number of tables and arrays
+    /// visited before them, so that encoding the value again can write them in the same places
+    table_references: HashMap<u32, u16>,
 }
 
 impl DuplicationResult {
+    pub fn new(info: DuplicationInfo, value: LuaValue) -> Self {
+        DuplicationResult {
+            info,
+            value,
+            table_references: HashMap::new(),
+        }
+    }
+
     pub fn info(&self) -> &DuplicationInfo {
         &self.info
     }
@@ -77,7 +121,7 @@ impl DuplicationResult {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct DuplicationInfo {
     size: u32,
     time_zone: String,
@@ -88,22 +132,39 @@ pub struct DuplicationInfo {
 }
 
 impl DuplicationInfo {
+    // The setters also update the entry of the info block that is written when encoding
     pub fn set_size(&mut self, size: u32) {
         self.size = size;
+        self.set_entry("size", size.to_string());
     }
     pub fn set_time_zone(&mut self, time_zone: String) {
+        self.set_entry("timezone", time_zone.clone());
         self.time_zone = time_zone;
     }
     pub fn set_date(&mut self, date: String) {
+        self.set_entry("date", date.clone());
         self.date = date;
     }
     pub fn set_time(&mut self, time: String) {
+        self.set_entry("time", time.clone());
         self.time = time;
     }
     pub fn set_player_name(&mut self, player_name: String) {
+        self.set_entry("name", player_name.clone());
         self.player_name = player_name;
     }
 
+    /// Sets the date, time and time zone to `date_time`, formatted the way the game stamps a
+    /// dupe it saves, e.g. `28 January 2023`, `03:45 PM` and `+0100`.
+    pub fn set_date_time<Tz: TimeZone>(&mut self, date_time: &DateTime<Tz>)
+    where
+        Tz::Offset: Display,
+    {
+        self.set_date(date_time.format("%d %B %Y").to_string());
+        self.set_time(date_time.format("%I:%M %p").to_string());
+        self.set_time_zone(date_time.format("%z").to_string());
+    }
+
     pub fn size(&self) -> u32 {
         self.size
     }
@@ -120,17 +181,77 @@ impl DuplicationInfo {
         &self.player_name
     }
 
+    /// Returns every key and value of the info block in the order they are written.
+    pub fn values(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Sets the info block entry `key` to `value`, replacing the existing entry or adding a new
+    /// one. The known keys also update their field, a `size` that is not a number leaves the
+    /// field as it was.
+    pub fn set_value(&mut self, key: &str, value: String) {
+        self.set_field(key, &value);
+        self.set_entry(key, value);
+    }
+
+    /// Adds the info block entry `key` after the existing entries, even when there already is
+    /// one with the same key, as the info block of a file can repeat a key. The known keys
+    /// update their field like with `set_value`, so the last entry of a key wins.
+    pub fn push_value(&mut self, key: &str, value: String) {
+        self.set_field(key, &value);
+        self.add_value(key.to_string(), value);
+    }
+
+    fn set_field(&mut self, key: &str, value: &str) {
+        match key {
+            "size" => {
+                if let Ok(size) = value.parse() {
+                    self.size = size;
+                }
+            }
+            "timezone" => self.time_zone = value.to_string(),
+            "date" => self.date = value.to_string(),
+            "time" => self.time = value.to_string(),
+            "name" => self.player_name = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn set_entry(&mut self, key: &str, value: String) {
+        match self.values.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.values.push_back((key.to_string(), value)),
+        }
+    }
+
     fn add_value(&mut self, key: String, value: String) {
-        self.values.push_front((key, value));
+        self.values.push_back((key, value));
     }
 }
 
 // Static bytes used in codec decoding and encoding
-static HEADER: &'static [u8] = &[65, 68, 50, 70];
-static VERSION: &'static [u8] = &[5];
-static CRLF: &'static [u8] = &[10];
-static INFO_SPLIT: &'static [u8] = &[1];
-static INFO_END: &'static [u8] = &[2];
+static HEADER: &[u8] = &[65, 68, 50, 70];
+static VERSION: &[u8] = &[5];
+static CRLF: &[u8] = &[10];
+static INFO_SPLIT: &[u8] = &[1];
+static INFO_END: &[u8] = &[2];
+
+/// The value of the check pair, which a text mode transfer of the file would change
+pub const CHECK_VALUE: &str = "\r\n\t\n";
+
+// Strings shorter than this are written with a single length byte
+const SHORT_STRING_LIMIT: usize = 246;
+
+/// The most values that back-references may expand into while decoding a data block. Every
+/// reference is decoded again in full, so a chain of tables referencing the one before it
+/// twice would otherwise expand exponentially.
+pub const MAX_REFERENCED_VALUES: usize = 1 << 20;
+
+/// A Lua table, which keeps its entries in the order they were inserted or decoded so that
+/// encoding an unmodified value writes the same bytes every time.
+pub type LuaTable = IndexMap<LuaValue, LuaValue>;
 
 /// The LuaValue enum is used to represent supported values that are
 /// serializable and deserializable.
@@ -138,7 +259,7 @@ static INFO_END: &'static [u8] = &[2];
 pub enum LuaValue {
     String(String),
     Array(Vec<LuaValue>),
-    Table(HashMap<LuaValue, LuaValue>),
+    Table(LuaTable),
     Double(f64),
     Vector(Vec<f64>),
     Angle(Vec<f64>),
@@ -150,16 +271,44 @@ impl Hash for LuaValue {
         match self {
             LuaValue::String(s) => s.hash(state),
             LuaValue::Array(a) => a.hash(state),
-            LuaValue::Table(_) => {}
+            LuaValue::Table(t) => {
+                // Tables have no order, so the entry hashes are combined with a commutative sum
+                let mut sum: u64 = 0;
+                for (key, value) in t {
+                    let mut hasher = DefaultHasher::new();
+                    key.hash(&mut hasher);
+                    value.hash(&mut hasher);
+                    sum = sum.wrapping_add(hasher.finish());
+                }
+
+                t.len().hash(state);
+                sum.hash(state);
+            }
             LuaValue::Double(d) => d.to_bits().hash(state),
-            LuaValue::Vector(_) => {}
-            LuaValue::Angle(_) => {}
+            LuaValue::Vector(v) | LuaValue::Angle(v) => {
+                for component in v {
+                    component.to_bits().hash(state);
+                }
+            }
             LuaValue::Bool(b) => b.hash(state),
         }
     }
 }
 
 impl LuaValue {
+    /// Returns the name of the value's type
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::String(_) => "string",
+            LuaValue::Array(_) => "array",
+            LuaValue::Table(_) => "table",
+            LuaValue::Double(_) => "double",
+            LuaValue::Vector(_) => "vector",
+            LuaValue::Angle(_) => "angle",
+            LuaValue::Bool(_) => "bool",
+        }
+    }
+
     pub fn is_array(&self) -> bool {
         self.as_array().is_some()
     }
@@ -190,100 +339,159 @@ impl LuaValue {
     }
 
     pub fn is_double(&self) -> bool {
-        match *self {
-            LuaValue::Double(_) => true,
-            _ => false,
-        }
+        matches!(self, LuaValue::Double(_))
     }
 
     pub fn as_double(&self) -> Option<f64> {
         match *self {
             LuaValue::Double(double) => Some(double),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn as_double_mut(&mut self) -> Option<&mut f64> {
         match self {
             LuaValue::Double(double) => Some(double),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn is_table(&self) -> bool {
-        match *self {
-            LuaValue::Table(_) => true,
-            _ => false,
-        }
+        matches!(self, LuaValue::Table(_))
     }
 
-    pub fn as_table(&self) -> Option<&HashMap<LuaValue, LuaValue>> {
+    pub fn as_table(&self) -> Option<&LuaTable> {
         match self {
             LuaValue::Table(table) => Some(table),
-            _ => None
+            _ => None,
         }
     }
 
-    pub fn as_table_mut(&mut self) -> Option<&mut HashMap<LuaValue, LuaValue>> {
+    pub fn as_table_mut(&mut self) -> Option<&mut LuaTable> {
         match self {
             LuaValue::Table(table) => Some(table),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn is_vector(&self) -> bool {
-        match *self {
-            LuaValue::Vector(_) => true,
-            _ => false,
-        }
+        matches!(self, LuaValue::Vector(_))
     }
 
     pub fn as_vector(&self) -> Option<&Vec<f64>> {
         match self {
             LuaValue::Vector(vector) => Some(vector),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn as_vector_mut(&mut self) -> Option<&mut Vec<f64>> {
         match self {
             LuaValue::Vector(vector) => Some(vector),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn is_angle(&self) -> bool {
-        match *self {
-            LuaValue::Angle(_) => true,
-            _ => false,
-        }
+        matches!(self, LuaValue::Angle(_))
     }
 
     pub fn as_angle(&self) -> Option<&Vec<f64>> {
         match self {
             LuaValue::Angle(angle) => Some(angle),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn as_angle_mut(&mut self) -> Option<&mut Vec<f64>> {
         match self {
             LuaValue::Angle(angle) => Some(angle),
-            _ => None
+            _ => None,
         }
     }
 
     pub fn is_bool(&self) -> bool {
-        match *self {
-            LuaValue::Bool(_) => true,
-            _ => false,
-        }
+        matches!(self, LuaValue::Bool(_))
     }
 
     pub fn as_bool(&self) -> Option<bool> {
         match *self {
             LuaValue::Bool(boolean) => Some(boolean),
-            _ => None
+            _ => None,
+        }
+    }
+
+    /// Compares two values in the canonical order used to sort table keys.
+    ///
+    /// Values of different types are ordered doubles, strings, bools, vectors, angles, arrays
+    /// and then tables. Values of the same type are compared by their contents, with tables
+    /// compared entry by entry in their current order.
+    pub fn canonical_cmp(&self, other: &LuaValue) -> Ordering {
+        fn rank(value: &LuaValue) -> u8 {
+            match value {
+                LuaValue::Double(_) => 0,
+                LuaValue::String(_) => 1,
+                LuaValue::Bool(_) => 2,
+                LuaValue::Vector(_) => 3,
+                LuaValue::Angle(_) => 4,
+                LuaValue::Array(_) => 5,
+                LuaValue::Table(_) => 6,
+            }
+        }
+
+        fn cmp_doubles(a: &[f64], b: &[f64]) -> Ordering {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| a.total_cmp(b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        }
+
+        match (self, other) {
+            (LuaValue::Double(a), LuaValue::Double(b)) => a.total_cmp(b),
+            (LuaValue::String(a), LuaValue::String(b)) => a.cmp(b),
+            (LuaValue::Bool(a), LuaValue::Bool(b)) => a.cmp(b),
+            (LuaValue::Vector(a), LuaValue::Vector(b))
+            | (LuaValue::Angle(a), LuaValue::Angle(b)) => cmp_doubles(a, b),
+            (LuaValue::Array(a), LuaValue::Array(b)) => a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| a.canonical_cmp(b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            (LuaValue::Table(a), LuaValue::Table(b)) => a
+                .iter()
+                .zip(b)
+                .map(|((a_key, a_value), (b_key, b_value))| {
+                    a_key
+                        .canonical_cmp(b_key)
+                        .then_with(|| a_value.canonical_cmp(b_value))
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            (a, b) => rank(a).cmp(&rank(b)),
+        }
+    }
+
+    /// Sorts the keys of this table, and of every table inside it, into the order of
+    /// `canonical_cmp`. Values that are not tables or arrays are left as they are.
+    pub fn sort_keys(&mut self) {
+        match self {
+            LuaValue::Array(array) => array.iter_mut().for_each(LuaValue::sort_keys),
+            LuaValue::Table(table) => {
+                // Keys can be tables too, so they are sorted before the entries are
+                *table = std::mem::take(table)
+                    .into_iter()
+                    .map(|(mut key, mut value)| {
+                        key.sort_keys();
+                        value.sort_keys();
+                        (key, value)
+                    })
+                    .collect();
+
+                table.sort_by(|a, _, b, _| a.canonical_cmp(b));
+            }
+            _ => {}
         }
     }
 }
@@ -297,398 +505,1417 @@ impl Eq for LuaValue {}
 /// and the data block.
 ///
 /// # Header:
-///     - Signature: The header will contain a signature of AD2F in ascii format
-///     - Version: The version will be a single byte to determinate which codec encoder was used.
+/// ```text
+/// - Signature: The header will contain a signature of AD2F in ascii format
+/// - Version: The version will be a single byte to determinate which codec encoder was used.
+/// ```
 ///
 /// # Info Block:
-///     - Start: The start is representing by a single CRLF byte (0xA)
-///     - Body: The body consists of a key value pair delimited by single byte (0x1)
-///              For example, say we have a name key and a value of Fozie it would store like this:
-///             name[0x1]Fozie[0x1]date[0x1]28 January 2023
-///             Note there could be any number of these.
-///     - Validation Pair: One special validation pair in the info block is a strain of characters.
-///                        Key: check
-///                        Value: "\r\n\t\n"
-///     - Termination: There is one terminating byte that will represent the end of the Info Block
-///                     0x2.
-///      - CRLF Flag: Another CRLF byte will be present here (0xA)
+/// ```text
+/// - Start: The start is representing by a single CRLF byte (0xA)
+/// - Body: The body consists of a key value pair delimited by single byte (0x1)
+///          For example, say we have a name key and a value of Fozie it would store like this:
+///         name[0x1]Fozie[0x1]date[0x1]28 January 2023
+///         Note there could be any number of these.
+/// - Validation Pair: One special validation pair in the info block is a strain of characters.
+///                    Key: check
+///                    Value: "\r\n\t\n"
+/// - Termination: There is one terminating byte that will represent the end of the Info Block
+///                 0x2.
+/// - CRLF Flag: Another CRLF byte will be present here (0xA)
+/// ```
 ///
 /// # Data Block:
-///     The data block is representing by a Lua Table that could have any number of nested tables/arrays.
-///     When encoded the data block is compressed in a LZMA format. This library will use a create called
-///     `gmod_lzma` which is a binding of what is used in Garry's Mod.
+/// ```text
+/// The data block is representing by a Lua Table that could have any number of nested tables/arrays.
+/// When encoded the data block is compressed in a LZMA format. This library will use a create called
+/// `gmod_lzma` which is a binding of what is used in Garry's Mod.
 ///
-///     Once decoded for each supported Lua type they will be directly serialized into a byte array.
-///     - Data Type: This is the first byte will determine the data type
-///     - Serialized value: This will be the serialized value of the data type
-///     - Termination: Only two data types will have a termination block, and that is tables and arrays
-///                 once this byte is hit, it is same to assume this is the end of that particular object.
-///
-///     Special Use cases:
-///         - Strings : For strings if the length is below 246 then two things will be written.
-///             - 1 byte length of the string
-///             - String value
-///           If the string is longer than that then the following will be written:
-///             - Data type of string (248)
-///             - A 32bit integer representing the length
-///             - String value
+/// Once decoded for each supported Lua type they will be directly serialized into a byte array.
+/// - Data Type: This is the first byte will determine the data type
+/// - Serialized value: This will be the serialized value of the data type
+/// - Termination: Only two data types will have a termination block, and that is tables and arrays
+///             once this byte is hit, it is same to assume this is the end of that particular object.
 ///
+/// Special Use cases:
+///     - Strings : For strings if the length is below 246 then two things will be written.
+///         - 1 byte length of the string
+///         - String value
+///       If the string is longer than that then the following will be written:
+///         - Data type of string (248)
+///         - A 32bit integer representing the length
+///         - String value
+///     - Table References: Every table and array is given an id in the order it is started,
+///       beginning at 1. When the same table appears again it can be written as:
+///         - Data type of reference (247)
+///         - A 16bit integer holding the id of the table it repeats
+/// ```
 #[derive(Default)]
-pub struct AdCodec5 {}
+pub struct AdCodec5 {
+    options: EncodeOptions,
+    strict: bool,
+}
 
-impl AdCodec5 {
-    /// Returns the duplication information that was stored in the metadata
-    ///
-    /// # Arguments
-    /// * `data` - The info block from the file
-    fn get_duplication_info(data: &[u8]) -> DuplicationInfo {
-        let mut duplication_info = DuplicationInfo {
-            size: 0,
-            time_zone: "".to_string(),
-            date: "".to_string(),
-            time: "".to_string(),
-            player_name: "".to_string(),
-            values: Default::default(),
-        };
+/// How `AdCodec5` writes a dupe. The defaults match what the game itself writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodeOptions {
+    /// Whether the back-references of a decoded dupe are written back where it had them
+    pub table_references: bool,
+    /// Whether any table equal to one already written is also written as a back-reference.
+    /// Off by default, as the game only references a table that is the same table twice and
+    /// would paste the tables as one.
+    pub dedupe_tables: bool,
+    /// Whether table keys are written in the canonical order of `LuaValue::canonical_cmp`
+    pub sort_keys: bool,
+    /// LZMA compression level of the data block, from 0 for the fastest to 9 for the smallest.
+    /// The game reads a data block compressed at any level.
+    pub compression_level: u32,
+    /// Whether the `size` of the info block is set to the length of the compressed data block,
+    /// as the game does when it saves a dupe. Off by default so that an unmodified dupe is
+    /// written back with the info block it was read with.
+    pub update_size: bool,
+}
 
-        // Convert the whole info block into a string and split it based off of
-        // the separation character
-        let data_str = String::from_utf8_lossy(data);
-        let split_data: Vec<&str> = data_str.split('\u{1}').collect();
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            table_references: true,
+            dedupe_tables: false,
+            sort_keys: false,
+            compression_level: MAX_COMPRESSION_LEVEL,
+            update_size: false,
+        }
+    }
+}
 
-        // For every odd number we will get the key value
-        for i in 0..split_data.len() {
-            if i % 2 != 0 {
-                continue;
-            }
+/// The highest LZMA compression level
+pub const MAX_COMPRESSION_LEVEL: u32 = 9;
 
-            // Ensure there is a value for the key
-            if i + 1 >= split_data.len() {
-                break;
-            }
+/// Position of a table or array inside the data block, used to resolve back-references
+#[derive(Clone, Copy)]
+struct TableOffset {
+    offset: u64,
+    complete: bool,
+}
 
-            let key = split_data[i];
-            let value = split_data[i + 1];
+/// State threaded through the recursive stream decoder
+struct DecodeState {
+    /// Offsets of the tables decoded so far, where index `id - 1` holds table `id`
+    tables: Vec<TableOffset>,
+    /// Keys leading from the root to the value being decoded, used when reporting errors
+    path: Vec<String>,
+    /// Whether tags below 246 are short strings, which version 4 did not have
+    short_strings: bool,
+    /// Number of tables, arrays and back-references read so far
+    visits: u32,
+    /// The id of every back-reference read, by the value of `visits` when it was read
+    references: HashMap<u32, u16>,
+    /// Whether a referenced table is being decoded again, which must not count as a visit
+    replaying: bool,
+    /// Number of values decoded again for back-references so far
+    referenced_values: usize,
+    /// Whether `path` is kept up to date. The borrowed decoder works out the path of an error
+    /// once it happened instead, keeping it while decoding would allocate for every key.
+    track_path: bool,
+}
 
-            // We will insert the known keys as well as insert it into the value list
-            match key {
-                "size" => duplication_info.set_size(value.parse().unwrap()),
-                "timezone" => duplication_info.set_time_zone(value.to_string()),
-                "date" => duplication_info.set_date(value.to_string()),
-                "time" => duplication_info.set_time(value.to_string()),
-                "name" => duplication_info.set_player_name(value.to_string()),
-                "check" => {}
-                _ => {}
-            }
+impl DecodeState {
+    fn new(short_strings: bool) -> Self {
+        DecodeState {
+            tables: Vec::new(),
+            path: Vec::new(),
+            short_strings,
+            visits: 0,
+            references: HashMap::new(),
+            replaying: false,
+            referenced_values: 0,
+            track_path: true,
+        }
+    }
 
-            // Insert the value into the list
-            duplication_info.add_value(key.to_string(), value.to_string());
+    /// Enters the value under the key or index `segment` of the table being decoded
+    fn enter(&mut self, segment: impl FnOnce() -> String) {
+        if self.track_path {
+            self.path.push(segment());
         }
+    }
 
-        duplication_info
+    /// Leaves the value last entered with `enter`
+    fn leave(&mut self) {
+        if self.track_path {
+            self.path.pop();
+        }
     }
 
-    /// Decodes a stream of binary data into a `LuaValue` object.
-    ///
-    /// # Parameters
-    ///
-    /// * `cursor`: A mutable reference to a `Cursor` object pointing to the start of the binary
-    /// data to be decoded.
-    ///
-    /// # Returns
-    ///
-    /// A `Option` object containing a `Box` of a `LuaValue` representing the decoded data, or `None`
-    /// if the data is not a valid `LuaValue`.
-    fn decode_stream(cursor: &mut Cursor<&[u8]>) -> Option<Box<LuaValue>> {
-        let mut data_type = [0u8; 1];
+    /// Counts a table, array or back-reference that was read from the data block
+    fn visit(&mut self) {
+        if !self.replaying {
+            self.visits += 1;
+        }
+    }
+}
 
-        cursor.read_exact(&mut data_type).expect("Unexpected EOF");
-        let data_type = data_type[0];
+/// A value that a data block can be decoded into. The owned `LuaValue` and the borrowed
+/// `LuaValueRef` share the decoder of `AdCodec5`, and only differ in how their strings are read
+/// and their tables are built.
+pub(crate) trait DecodeTarget<R>: Sized {
+    /// Entries of a table while it is being decoded
+    type Entries: Default;
 
-        match data_type {
-            // Lua Table data type
-            255 => {
-                let mut table: HashMap<LuaValue, LuaValue> = HashMap::new();
+    /// Reads a string of `length` bytes, reporting the string value at `offset` and `path`
+    /// when the data runs out or is not UTF-8.
+    fn read_string(
+        reader: &mut R,
+        length: usize,
+        offset: u64,
+        path: &[String],
+    ) -> Result<Self, CodecError>;
 
-                loop {
-                    match Self::decode_stream(cursor) {
-                        Some(key) => {
-                            let value = Self::decode_stream(cursor).unwrap();
+    fn insert(table: &mut Self::Entries, key: Self, value: Self);
 
-                            table.insert(*key, *value);
-                        }
-                        _ => {
-                            return Some(Box::new(LuaValue::Table(table)));
-                        }
-                    }
-                }
-            }
-            // Lua array data type
-            254 => {
-                let mut array: Vec<LuaValue> = Vec::new();
+    fn table(table: Self::Entries) -> Self;
 
-                loop {
-                    match Self::decode_stream(cursor) {
-                        Some(value) => {
-                            array.push(*value);
-                        }
-                        _ => {
-                            return Some(Box::new(LuaValue::Array(array)));
-                        }
-                    }
-                }
-            }
-            // Lua Vector data type
-            250 => {
-                let mut vector: Vec<f64> = Vec::new();
+    fn array(array: Vec<Self>) -> Self;
 
-                vector.push(Self::read_double(cursor));
-                vector.push(Self::read_double(cursor));
-                vector.push(Self::read_double(cursor));
+    fn double(double: f64) -> Self;
 
-                Some(Box::new(LuaValue::Vector(vector)))
-            }
-            // Lua angle data type
-            249 => {
-                let mut vector: Vec<f64> = Vec::new();
+    fn vector(vector: [f64; 3]) -> Self;
 
-                vector.push(Self::read_double(cursor));
-                vector.push(Self::read_double(cursor));
-                vector.push(Self::read_double(cursor));
+    fn angle(angle: [f64; 3]) -> Self;
 
-                Some(Box::new(LuaValue::Angle(vector)))
-            }
-            // Lua double data type
-            251 => Some(Box::new(LuaValue::Double(Self::read_double(cursor)))),
-            // Lua false data type
-            252 => Some(Box::new(LuaValue::Bool(false))),
-            // Lua true data type
-            253 => Some(Box::new(LuaValue::Bool(true))),
-            // Null data type
-            246 => None,
-            // String under 246 data type
-            _ => {
-                let length = data_type;
-                if length == 0 {
-                    return Some(Box::new(LuaValue::String(String::from(""))));
-                }
+    fn bool(boolean: bool) -> Self;
+
+    /// Returns the text used for this value as a table key inside an error path
+    fn path_segment(&self) -> String;
+}
 
-                let mut data = vec![0; length as usize];
-                cursor.read_exact(&mut data).expect("Unexpected EOF");
-                let data = String::from_utf8_lossy(data.as_slice()).to_string();
+impl<R: Read> DecodeTarget<R> for LuaValue {
+    type Entries = LuaTable;
 
-                return Some(Box::new(LuaValue::String(data)));
-            }
-        }
+    fn read_string(
+        reader: &mut R,
+        length: usize,
+        offset: u64,
+        path: &[String],
+    ) -> Result<Self, CodecError> {
+        AdCodec5::read_string(reader, length, offset, path).map(LuaValue::String)
     }
 
-    /// Serializes a `LuaValue` into a binary stream.
-    ///
-    /// The function takes in a `LuaValue` and a mutable reference to a type `T` that implements the
-    /// `Write` trait, and serializes the `LuaValue` into the binary stream.
-    ///
-    /// # Arguments
-    ///
-    /// * `value` - The `LuaValue` to be serialized.
-    /// * `stream` - A mutable reference to a type that implements the `Write` trait, representing the
-    /// binary stream to serialize `value` into.
-    ///
-    /// # Returns
-    ///
-    /// Returns a `Result` of type `Result<(), Box<dyn Error>>`. If the serialization is successful,
-    /// the `Ok` variant is returned. If an error occurs during serialization, the `Err` variant is
-    /// returned with an error message boxed in a `Box<dyn Error>`.
-    fn encode_stream<T: Write>(value: &LuaValue, stream: &mut T) -> Result<(), Box<dyn Error>> {
-        match value {
-            LuaValue::String(str) => {
-                if str.len() < 246 {
-                    let buff = [str.len() as u8];
-                    stream.write(&buff)?;
+    fn insert(table: &mut LuaTable, key: Self, value: Self) {
+        table.insert(key, value);
+    }
 
-                    let buff = str.as_bytes();
-                    stream.write(buff)?;
-                }
-            }
-            LuaValue::Array(arr) => {
-                let buff = [254; 1];
-                stream.write(&buff)?;
+    fn table(table: LuaTable) -> Self {
+        LuaValue::Table(table)
+    }
 
-                for value in arr {
-                    Self::encode_stream(value, stream)?;
-                }
+    fn array(array: Vec<Self>) -> Self {
+        LuaValue::Array(array)
+    }
 
-                let buff: [u8; 1] = [246; 1];
-                stream.write(&buff)?;
-            }
-            LuaValue::Table(tbl) => {
-                let buff = [255; 1];
-                stream.write(&buff)?;
+    fn double(double: f64) -> Self {
+        LuaValue::Double(double)
+    }
 
-                for (key, value) in tbl {
-                    Self::encode_stream(key, stream)?;
-                    Self::encode_stream(value, stream)?;
-                }
+    fn vector(vector: [f64; 3]) -> Self {
+        LuaValue::Vector(vector.to_vec())
+    }
 
-                let buff: [u8; 1] = [246; 1];
-                stream.write(&buff)?;
-            }
-            LuaValue::Double(double) => {
-                let buff = [251; 1];
-                stream.write(&buff)?;
-                stream.write(&Self::double_as_bytes(&double))?;
-            }
-            LuaValue::Vector(vector) => {
-                let buff = [250; 1];
-                stream.write(&buff)?;
+    fn angle(angle: [f64; 3]) -> Self {
+        LuaValue::Angle(angle.to_vec())
+    }
 
-                stream.write(&Self::double_as_bytes(vector.get(0).unwrap()))?;
-                stream.write(&Self::double_as_bytes(vector.get(1).unwrap()))?;
-                stream.write(&Self::double_as_bytes(vector.get(2).unwrap()))?;
-            }
-            LuaValue::Angle(angle) => {
-                let buff = [249; 1];
-                stream.write(&buff)?;
+    fn bool(boolean: bool) -> Self {
+        LuaValue::Bool(boolean)
+    }
 
-                stream.write(&Self::double_as_bytes(angle.get(0).unwrap()))?;
-                stream.write(&Self::double_as_bytes(angle.get(1).unwrap()))?;
-                stream.write(&Self::double_as_bytes(angle.get(2).unwrap()))?;
-            }
-            LuaValue::Bool(boolean) => match boolean {
-                true => {
-                    let buff = [253; 1];
-                    stream.write(&buff)?;
-                }
-                false => {
-                    let buff = [252; 1];
-                    stream.write(&buff)?;
-                }
-            },
+    fn path_segment(&self) -> String {
+        AdCodec5::path_segment(self)
+    }
+}
+
+/// Reads the data block of a file while decompressing the LZMA stream that holds it, so the
+/// compressed data is never read into memory as a whole. The decompressed bytes are kept, as a
+/// back-reference seeks back to decode the table it refers to again.
+struct DataBlockReader<R> {
+    input: LzmaReader<R>,
+    data: Vec<u8>,
+    position: usize,
+    /// Offset of the compressed data block in the file, used when reporting errors
+    offset: u64,
+    /// Why decompressing failed, which is reported instead of the read error it caused
+    error: Option<CodecError>,
+}
+
+// Amount of data decompressed at a time
+const DATA_BLOCK_CHUNK: usize = 64 * 1024;
+
+impl<R: Read> DataBlockReader<R> {
+    /// Starts decompressing the LZMA stream of `input`, reading its header
+    fn new(input: R, offset: u64) -> Result<Self, CodecError> {
+        let input = LzmaReader::new_mem_limit(input, u32::MAX, None)
+            .map_err(|error| Self::lzma_error(&error, offset))?;
+
+        Ok(DataBlockReader {
+            input,
+            data: Vec::new(),
+            position: 0,
+            offset,
+            error: None,
+        })
+    }
+
+    /// Decompresses the next chunk of the data block, returning `false` once it has all been
+    /// decompressed.
+    fn fill(&mut self) -> Result<bool, CodecError> {
+        let length = self.data.len();
+        self.data.resize(length + DATA_BLOCK_CHUNK, 0);
+
+        let read = self.input.read(&mut self.data[length..]);
+        self.data.truncate(length + *read.as_ref().unwrap_or(&0));
+
+        match read {
+            Ok(read) => Ok(read > 0),
+            Err(error) => Err(Self::lzma_error(&error, self.offset)),
         }
+    }
+
+    /// Decompresses whatever is left of the data block
+    fn finish(&mut self) -> Result<(), CodecError> {
+        while self.fill()? {}
 
         Ok(())
     }
 
-    /// Returns deserialized double
-    ///
-    /// # Arguments
-    /// `input` - Stream that will contain the serialized double
-    fn read_double(input: &mut dyn Read) -> f64 {
-        let mut data = [0u8; 8];
-        input.read_exact(&mut data).unwrap();
-        let val = f64::from_bits(u64::from_le_bytes(data));
-        return val;
+    /// Returns the error for a failure of the LZMA decoder, with the `gmod_lzma` error code
+    /// closest to it
+    fn lzma_error(error: &std::io::Error, offset: u64) -> CodecError {
+        let code = match error.kind() {
+            ErrorKind::UnexpectedEof => gmod_lzma::SZ_ERROR_INPUT_EOF,
+            ErrorKind::InvalidInput => gmod_lzma::SZ_ERROR_UNSUPPORTED,
+            ErrorKind::OutOfMemory => gmod_lzma::SZ_ERROR_MEM,
+            _ => gmod_lzma::SZ_ERROR_DATA,
+        };
+
+        CodecError::Lzma { offset, code }
     }
+}
 
-    /// Returns serialized double represented as a byte array
-    ///
-    /// # Arguments
-    /// `value` - Double value to be serialized
-    fn double_as_bytes(value: &f64) -> [u8; 8] {
-        value.to_le_bytes()
+impl<R: Read> Read for DataBlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.data.len() {
+            match self.fill() {
+                Ok(true) => {}
+                Ok(false) => return Ok(0),
+                Err(error) => {
+                    let message = error.to_string();
+                    self.error = Some(error);
+                    return Err(std::io::Error::other(message));
+                }
+            }
+        }
+
+        let count = (&self.data[self.position..]).read(buf)?;
+        self.position += count;
+
+        Ok(count)
     }
 }
 
-impl AdCodec for AdCodec5 {
-    /// Decodes the `reader` into a `DuplicationResult` by reading the steam into a byte array,
-    /// splitting the info and data block, parsing the info block into a `DuplicationInfo` struct,
-    /// decompressing the data block with  `gmod_lzma`, and finally decoding the decompressed
-    /// data block into a `LuaValue`.
+impl<R> Seek for DataBlockReader<R> {
+    /// Moves within the part of the data block that was already read
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => (self.position as u64).checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+
+        match position.filter(|position| *position <= self.data.len() as u64) {
+            Some(position) => {
+                self.position = position as usize;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "can only seek within the data read so far",
+            )),
+        }
+    }
+}
+
+/// Keeps track of the tables and arrays that were written to a stream, so that repeated
+/// ones can be written as a back-reference instead.
+///
+/// Without a plan any table equal to one already written is referenced, which is only done
+/// when `EncodeOptions::dedupe_tables` asks for it. With the plan of back-references recorded
+/// when the value was decoded, references are only written where the decoded data had them,
+/// as long as the table still equals the one it refers to.
+#[derive(Default)]
+struct TableReferences<'a> {
+    count: u32,
+    written: HashMap<&'a LuaValue, u16>,
+    ids: HashMap<u16, &'a LuaValue>,
+    visits: u32,
+    plan: Option<&'a HashMap<u32, u16>>,
+}
+
+impl<'a> TableReferences<'a> {
+    /// Returns the id for the next table written, or `None` once ids no longer fit in the
+    /// signed 16 bits the game reads them as
+    fn reserve(&mut self) -> Option<u16> {
+        self.count += 1;
+        i16::try_from(self.count).ok().map(|id| id as u16)
+    }
+
+    fn with_plan(plan: Option<&'a HashMap<u32, u16>>) -> Self {
+        TableReferences {
+            plan,
+            ..Default::default()
+        }
+    }
+
+    /// Visits a value about to be written and returns the id of the table to reference in
+    /// its place, if any. Without a plan, empty tables are never referenced since the
+    /// reference would be larger than the table itself.
+    fn visit(&mut self, value: &LuaValue) -> Option<u16> {
+        if !matches!(value, LuaValue::Array(_) | LuaValue::Table(_)) {
+            return None;
+        }
+        self.visits += 1;
+
+        if let Some(plan) = self.plan {
+            let id = *plan.get(&self.visits)?;
+            return self
+                .ids
+                .get(&id)
+                .filter(|table| **table == value)
+                .map(|_| id);
+        }
+
+        match value {
+            LuaValue::Array(array) if array.is_empty() => None,
+            LuaValue::Table(table) if table.is_empty() => None,
+            _ => self.written.get(value).copied(),
+        }
+    }
+
+    fn insert(&mut self, value: &'a LuaValue, id: Option<u16>) {
+        if let Some(id) = id {
+            self.written.entry(value).or_insert(id);
+            self.ids.insert(id, value);
+        }
+    }
+}
+
+impl AdCodec5 {
+    pub fn with_options(options: EncodeOptions) -> Self {
+        AdCodec5 {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Sets whether decoding checks the info block strictly. A strict decode also fails when
+    /// the check pair is missing, the check value or the line feeds around the info block are
+    /// not as written by the game, when a key has no value, when the size is not a number, or
+    /// when the info block is not valid UTF-8.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn options(&self) -> &EncodeOptions {
+        &self.options
+    }
+
+    /// Sets whether the back-references of a decoded dupe are written back when encoding it
+    /// with `encode_result`
+    pub fn set_table_references(&mut self, table_references: bool) {
+        self.options.table_references = table_references;
+    }
+
+    pub fn table_references(&self) -> bool {
+        self.options.table_references
+    }
+
+    /// Sets whether any table equal to one already written is written as a back-reference
+    /// when encoding, which makes the game paste the equal tables as one shared table
+    pub fn set_dedupe_tables(&mut self, dedupe_tables: bool) {
+        self.options.dedupe_tables = dedupe_tables;
+    }
+
+    pub fn dedupe_tables(&self) -> bool {
+        self.options.dedupe_tables
+    }
+
+    /// Sets whether table keys are written in the canonical order of `LuaValue::canonical_cmp`
+    /// instead of the order they were decoded or inserted in when encoding
+    pub fn set_sort_keys(&mut self, sort_keys: bool) {
+        self.options.sort_keys = sort_keys;
+    }
+
+    pub fn sort_keys(&self) -> bool {
+        self.options.sort_keys
+    }
+
+    /// Sets the LZMA compression level of the data block, from 0 to `MAX_COMPRESSION_LEVEL`
+    pub fn set_compression_level(&mut self, compression_level: u32) {
+        self.options.compression_level = compression_level;
+    }
+
+    pub fn compression_level(&self) -> u32 {
+        self.options.compression_level
+    }
+
+    /// Encodes a decoded dupe like `AdCodec::encode`, writing the back-references of the data
+    /// block in the same places as the file it was decoded from. A dupe that was not modified
+    /// is written back with the same info block and the same uncompressed data block.
     ///
     /// # Arguments
+    /// * `result` - The decoded dupe to encode
+    /// * `stream` - The output stream to write to
+    pub fn encode_result(
+        &self,
+        result: &DuplicationResult,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        self.encode_file(
+            result.info(),
+            result.value(),
+            Some(&result.table_references),
+            stream,
+        )
+    }
+
+    /// Writes the whole file, with the back-references placed by `plan` when there is one.
+    fn encode_file(
+        &self,
+        dupe_info: &DuplicationInfo,
+        lua_value: &LuaValue,
+        plan: Option<&HashMap<u32, u16>>,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        self.encode_version(dupe_info, lua_value, plan, VERSION[0], stream)
+    }
+
+    /// Writes the whole file like `encode_file` as codec `version`, version 4 writing every
+    /// string with the 248 data type since it had no short strings.
+    fn encode_version(
+        &self,
+        dupe_info: &DuplicationInfo,
+        lua_value: &LuaValue,
+        plan: Option<&HashMap<u32, u16>>,
+        version: u8,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        // Sorting works on a copy so the caller's tables keep their order, the recorded
+        // references no longer line up with the sorted tables
+        let sorted = self.options.sort_keys.then(|| {
+            let mut value = lua_value.clone();
+            value.sort_keys();
+            value
+        });
+        let lua_value = sorted.as_ref().unwrap_or(lua_value);
+        let plan = plan.filter(|_| sorted.is_none());
+
+        // Serialize the lua type
+        let mut data = Cursor::new(Vec::new());
+        let references_wanted = match plan {
+            Some(_) => self.options.table_references,
+            None => self.options.dedupe_tables,
+        };
+        let mut references = references_wanted.then(|| TableReferences::with_plan(plan));
+        AdCodec5::encode_value(lua_value, &mut data, references.as_mut(), version != 4)?;
+
+        self.write_file(dupe_info, &data.into_inner(), version, stream)
+    }
+
+    /// Writes a whole file around a data block that is already serialized, such as one written
+    /// out by `decompress_data_block`. The data block is compressed as it is, without decoding it.
     ///
-    /// * `reader` - The input stream to be decoded.
+    /// # Arguments
+    /// * `dupe_info` - The info block to write
+    /// * `data` - The uncompressed data block
+    /// * `stream` - The output stream to write to
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the compression level is above `MAX_COMPRESSION_LEVEL`, the data
+    /// block is unable to be compressed or writing to `stream` fails.
+    pub fn encode_data_block(
+        &self,
+        dupe_info: &DuplicationInfo,
+        data: &[u8],
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_file(dupe_info, data, VERSION[0], stream)
+    }
+
+    /// Writes a whole file of codec `version` around an uncompressed data block.
+    fn write_file(
+        &self,
+        dupe_info: &DuplicationInfo,
+        data: &[u8],
+        version: u8,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let level = self.options.compression_level;
+        if level > MAX_COMPRESSION_LEVEL {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "compression level {} is not between 0 and {}",
+                    level, MAX_COMPRESSION_LEVEL
+                ),
+            )));
+        }
+
+        // Compress the data block into LZMA format
+        let data = gmod_lzma::compress(data, level as i32).map_err(|code| {
+            std::io::Error::other(format!("Unable to compress LZMA data (error {})", code))
+        })?;
+
+        // The size is set on a copy so the caller's info block is left as it was
+        let sized = self.options.update_size.then(|| {
+            let mut info = dupe_info.clone();
+            info.set_size(data.len() as u32);
+            info
+        });
+        let dupe_info = sized.as_ref().unwrap_or(dupe_info);
+
+        // First thing to do is write the signature and version
+        stream.write_all(HEADER)?;
+        stream.write_all(&[version])?;
+
+        // Write CRLF byte
+        stream.write_all(CRLF)?;
+
+        for (k, v) in dupe_info.values.iter() {
+            stream.write_all(k.as_bytes())?;
+            stream.write_all(INFO_SPLIT)?;
+            stream.write_all(v.as_bytes())?;
+            stream.write_all(INFO_SPLIT)?;
+        }
+
+        // Termination of info block
+        stream.write_all(INFO_END)?;
+
+        // Write CRLF byte
+        stream.write_all(CRLF)?;
+
+        stream.write_all(&data)?;
+
+        Ok(())
+    }
+
+    /// Returns the duplication information that was stored in the metadata
+    ///
+    /// # Arguments
+    /// * `data` - The info block from the file
+    /// * `offset` - Offset of the info block within the file, used when reporting errors
+    /// * `strict` - Whether to validate the check value and reject malformed pairs and a `size`
+    ///   that is not a number
+    fn get_duplication_info(
+        data: &[u8],
+        offset: u64,
+        strict: bool,
+    ) -> Result<DuplicationInfo, CodecError> {
+        let mut duplication_info = DuplicationInfo {
+            size: 0,
+            time_zone: "".to_string(),
+            date: "".to_string(),
+            time: "".to_string(),
+            player_name: "".to_string(),
+            values: Default::default(),
+        };
+
+        if let Err(error) = std::str::from_utf8(data).map_err(|error| error.valid_up_to()) {
+            if strict {
+                return Err(CodecError::InvalidInfo {
+                    offset: offset + error as u64,
+                    path: String::new(),
+                    reason: String::from("info block is not valid UTF-8"),
+                });
+            }
+        }
+
+        // Convert the whole info block into a string and split it based off of
+        // the separation character
+        let data_str = String::from_utf8_lossy(data);
+        let split_data: Vec<&str> = data_str.split('\u{1}').collect();
+
+        // Offset of the current key within the file
+        let mut key_offset = offset;
+
+        // For every odd number we will get the key value
+        for i in 0..split_data.len() {
+            if i % 2 != 0 {
+                continue;
+            }
+
+            let value_offset = key_offset + split_data[i].len() as u64 + 1;
+
+            // Ensure there is a value for the key, the block always ends with an empty string
+            // after the last separator
+            if i + 1 >= split_data.len() {
+                if strict && !split_data[i].is_empty() {
+                    return Err(CodecError::InvalidInfo {
+                        offset: key_offset,
+                        path: split_data[i].to_string(),
+                        reason: String::from("key has no value"),
+                    });
+                }
+                break;
+            }
+
+            let key = split_data[i];
+            let value = split_data[i + 1];
+
+            if strict && i + 2 == split_data.len() {
+                return Err(CodecError::InvalidInfo {
+                    offset: value_offset + value.len() as u64,
+                    path: key.to_string(),
+                    reason: String::from("value is not terminated"),
+                });
+            }
+
+            // We will insert the known keys as well as insert it into the value list
+            match key {
+                // The size is only advisory, a hand edited one is kept as it is in the values
+                "size" => match value.parse() {
+                    Ok(size) => duplication_info.size = size,
+                    Err(_) if strict => {
+                        return Err(CodecError::InvalidInfo {
+                            offset: value_offset,
+                            path: key.to_string(),
+                            reason: format!("size \"{}\" is not a number", value),
+                        })
+                    }
+                    Err(_) => {}
+                },
+                "timezone" => duplication_info.time_zone = value.to_string(),
+                "date" => duplication_info.date = value.to_string(),
+                "time" => duplication_info.time = value.to_string(),
+                "name" => duplication_info.player_name = value.to_string(),
+                "check" if strict && value != CHECK_VALUE => {
+                    return Err(match LineEndings::from_check(value) {
+                        Some(translation) => CodecError::LineEndings {
+                            offset: value_offset,
+                            translation,
+                        },
+                        None => CodecError::InvalidInfo {
+                            offset: value_offset,
+                            path: key.to_string(),
+                            reason: format!("check value {:?} is not {:?}", value, CHECK_VALUE),
+                        },
+                    });
+                }
+                _ => {}
+            }
+
+            // Insert the value into the list
+            duplication_info.add_value(key.to_string(), value.to_string());
+
+            key_offset = value_offset + value.len() as u64 + 1;
+        }
+
+        Ok(duplication_info)
+    }
+
+    /// Decodes a stream of binary data into a `LuaValue` object.
+    ///
+    /// # Parameters
+    ///
+    /// * `reader`: The binary data to be decoded, positioned at the start of the value. Any
+    ///   reader that can seek back to the tables it has read will do, not only a `Cursor`.
     ///
     /// # Returns
     ///
-    /// A `DuplicationResult` containing the `DuplicationInfo` and the `LuaValue` decoded from the input stream.
+    /// A `Option` object containing a `Box` of a `LuaValue` representing the decoded data, or `None`
+    /// if a terminator was read instead of a value.
     ///
     /// # Errors
     ///
-    /// Returns an error if the LZMA data is unable to be decompressed.
-    fn decode(&self, reader: &mut dyn Read) -> DuplicationResult {
-        // Skip a CRLF byte
-        reader.bytes().next();
+    /// Returns a `CodecError` positioned at the offending value when the data is truncated or
+    /// malformed.
+    pub(crate) fn decode_stream<R: Read + Seek>(
+        reader: &mut R,
+    ) -> Result<Option<Box<LuaValue>>, CodecError> {
+        Self::decode_value(reader, &mut DecodeState::new(true)).map(|value| value.map(Box::new))
+    }
+
+    /// Decodes a version 5 stream into any `DecodeTarget` without keeping track of the path
+    /// of the value being decoded, every error is reported with an empty path.
+    pub(crate) fn decode_stream_unlocated<R: Read + Seek, V: DecodeTarget<R>>(
+        reader: &mut R,
+    ) -> Result<Option<V>, CodecError> {
+        let mut state = DecodeState::new(true);
+        state.track_path = false;
+
+        Self::decode_value(reader, &mut state)
+    }
+
+    /// Decodes a single value from the stream while recording where every table and array
+    /// starts, so that a back-reference can be resolved by decoding the referenced table again.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The data block positioned at the value to decode
+    /// * `state` - Tables and path seen so far by this decode
+    fn decode_value<R: Read + Seek, V: DecodeTarget<R>>(
+        reader: &mut R,
+        state: &mut DecodeState,
+    ) -> Result<Option<V>, CodecError> {
+        let offset = Self::position(reader, &state.path)?;
+        let mut data_type = [0u8; 1];
+
+        if state.replaying {
+            state.referenced_values += 1;
+        }
+
+        Self::read_data(reader, &mut data_type, offset, &state.path)?;
+        let data_type = data_type[0];
+
+        match data_type {
+            // Lua Table data type
+            255 => {
+                let mut table = V::Entries::default();
+                let id = Self::start_table(&mut state.tables, offset);
+                state.visit();
+
+                loop {
+                    match Self::decode_value::<R, V>(reader, state)? {
+                        Some(key) => {
+                            state.enter(|| key.path_segment());
+                            let value = Self::decode_required(reader, state)?;
+                            state.leave();
+
+                            V::insert(&mut table, key, value);
+                        }
+                        _ => {
+                            state.tables[id].complete = true;
+                            return Ok(Some(V::table(table)));
+                        }
+                    }
+                }
+            }
+            // Lua array data type
+            254 => {
+                let mut array: Vec<V> = Vec::new();
+                let id = Self::start_table(&mut state.tables, offset);
+                state.visit();
 
-        let mut b_output: Vec<u8> = Vec::new();
-        let mut buff = [0; 1];
-        let mut info_block_end: usize = 0;
+                loop {
+                    // Lua arrays are indexed from 1
+                    state.enter(|| (array.len() + 1).to_string());
+                    let value = Self::decode_value(reader, state)?;
+                    state.leave();
 
-        // Read the rest of the output into a byte array
-        // and note the end of the info block
-        while let Ok(_) = reader.read_exact(&mut buff) {
-            if info_block_end == 0 && buff[0] == 0x2 {
-                info_block_end = b_output.len();
+                    match value {
+                        Some(value) => {
+                            array.push(value);
+                        }
+                        _ => {
+                            state.tables[id].complete = true;
+                            return Ok(Some(V::array(array)));
+                        }
+                    }
+                }
             }
+            // Reference to a previously decoded table or array
+            247 => {
+                let mut id = [0u8; 2];
+                Self::read_data(reader, &mut id, offset, &state.path)?;
+                let id = u16::from_le_bytes(id);
+
+                // A reference may only point at a table that has been fully decoded
+                let invalid = || CodecError::InvalidReference {
+                    id,
+                    offset,
+                    path: Self::path_string(&state.path),
+                };
+                let index = (id as usize).checked_sub(1).ok_or_else(invalid)?;
+                let table = state
+                    .tables
+                    .get(index)
+                    .filter(|table| table.complete)
+                    .copied()
+                    .ok_or_else(invalid)?;
+
+                // References inside the referenced table are expanded again as well, a chain
+                // of them is stopped before it runs out of memory
+                if state.referenced_values > MAX_REFERENCED_VALUES {
+                    return Err(invalid());
+                }
 
-            b_output.push(buff[0]);
+                state.visit();
+                if !state.replaying {
+                    state.references.insert(state.visits, id);
+                }
+
+                // Decode the referenced table again, with only the tables that preceded it known
+                let resume = Self::position(reader, &state.path)?;
+                Self::seek(reader, table.offset, &state.path)?;
+
+                let following = state.tables.split_off(index);
+                let replaying = std::mem::replace(&mut state.replaying, true);
+                let value = Self::decode_value(reader, state)?;
+                state.replaying = replaying;
+                state.tables.truncate(index);
+                state.tables.extend(following);
+
+                Self::seek(reader, resume, &state.path)?;
+
+                Ok(value)
+            }
+            // Lua Vector data type
+            250 => Ok(Some(V::vector(Self::read_vector(
+                reader,
+                offset,
+                &state.path,
+            )?))),
+            // Lua angle data type
+            249 => Ok(Some(V::angle(Self::read_vector(
+                reader,
+                offset,
+                &state.path,
+            )?))),
+            // Lua double data type
+            251 => {
+                let double =
+                    Self::read_double(reader).map_err(|_| Self::truncated(offset, &state.path))?;
+
+                Ok(Some(V::double(double)))
+            }
+            // Lua false data type
+            252 => Ok(Some(V::bool(false))),
+            // Lua true data type
+            253 => Ok(Some(V::bool(true))),
+            // Null data type
+            246 => Ok(None),
+            // String of 246 bytes or longer, prefixed by a 32bit length
+            248 => {
+                let mut length = [0u8; 4];
+                Self::read_data(reader, &mut length, offset, &state.path)?;
+                let length = u32::from_le_bytes(length);
+
+                let string = V::read_string(reader, length as usize, offset, &state.path)?;
+
+                Ok(Some(string))
+            }
+            // Tags below 246 are only short strings from version 5 onwards
+            _ if !state.short_strings => Err(CodecError::UnknownType {
+                tag: data_type,
+                offset,
+                path: Self::path_string(&state.path),
+            }),
+            // String under 246 data type
+            length => {
+                let string = V::read_string(reader, length as usize, offset, &state.path)?;
+
+                Ok(Some(string))
+            }
+        }
+    }
+
+    /// Decodes a value that must be present, such as the value half of a table entry.
+    /// A terminator in that position is reported as an unknown type.
+    fn decode_required<R: Read + Seek, V: DecodeTarget<R>>(
+        reader: &mut R,
+        state: &mut DecodeState,
+    ) -> Result<V, CodecError> {
+        let offset = Self::position(reader, &state.path)?;
+
+        match Self::decode_value(reader, state)? {
+            Some(value) => Ok(value),
+            None => Err(CodecError::UnknownType {
+                tag: 246,
+                offset,
+                path: Self::path_string(&state.path),
+            }),
         }
+    }
+
+    /// Fills `buffer` from the data block, reporting the value at `offset` as truncated when
+    /// the data runs out.
+    fn read_data<R: Read>(
+        reader: &mut R,
+        buffer: &mut [u8],
+        offset: u64,
+        path: &[String],
+    ) -> Result<(), CodecError> {
+        reader
+            .read_exact(buffer)
+            .map_err(|_| Self::truncated(offset, path))
+    }
 
-        // Split the info and the data block skipping CRLF bytes
-        let info = b_output[0..info_block_end].to_vec();
-        let data = b_output[(info_block_end + 2)..].to_vec();
+    /// Returns the offset of the reader within the data block. A reader that can not tell its
+    /// position is reported as truncated at the start of the data block.
+    fn position<R: Seek>(reader: &mut R, path: &[String]) -> Result<u64, CodecError> {
+        reader
+            .stream_position()
+            .map_err(|_| Self::truncated(0, path))
+    }
+
+    /// Moves the reader to `offset` within the data block, which it has already read past.
+    fn seek<R: Seek>(reader: &mut R, offset: u64, path: &[String]) -> Result<(), CodecError> {
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map(|_| ())
+            .map_err(|_| Self::truncated(offset, path))
+    }
+
+    fn truncated(offset: u64, path: &[String]) -> CodecError {
+        CodecError::Truncated {
+            offset,
+            path: Self::path_string(path),
+        }
+    }
+
+    /// Returns the text used for a table key inside an error path
+    pub(crate) fn path_segment(key: &LuaValue) -> String {
+        match key {
+            LuaValue::String(string) => string.clone(),
+            LuaValue::Double(double) => double.to_string(),
+            LuaValue::Bool(boolean) => boolean.to_string(),
+            other => format!("[{}]", other.type_name()),
+        }
+    }
+
+    fn path_string(path: &[String]) -> String {
+        path.join(".")
+    }
+
+    /// Returns where the compressed data block starts in a whole dupe file, after the info
+    /// block terminator and the CRLF byte following it.
+    pub fn data_block_offset(file: &[u8]) -> Option<usize> {
+        let info_start = HEADER.len() + VERSION.len() + CRLF.len();
+        let info_length = file
+            .get(info_start..)?
+            .iter()
+            .position(|byte| *byte == INFO_END[0])?;
+
+        Some(info_start + info_length + INFO_END.len() + CRLF.len())
+            .filter(|start| *start <= file.len())
+    }
+
+    /// Returns the decompressed data block of a whole dupe file.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CodecError` if the file ends before the data block or the data block is unable
+    /// to be decompressed.
+    pub fn decompress_data_block(file: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let start = Self::data_block_offset(file).ok_or(CodecError::Truncated {
+            offset: file.len() as u64,
+            path: String::new(),
+        })?;
+
+        gmod_lzma::decompress(&file[start..]).map_err(|code| CodecError::Lzma {
+            offset: start as u64,
+            code,
+        })
+    }
+
+    /// Decodes a data block that was already decompressed, such as one returned by
+    /// `decompress_data_block`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CodecError` positioned at the offending value when the data block is
+    /// truncated or malformed, or holds a terminator instead of a value.
+    pub fn decode_data_block(data: &[u8]) -> Result<LuaValue, CodecError> {
+        match Self::decode_stream(&mut Cursor::new(data))? {
+            Some(value) => Ok(*value),
+            None => Err(CodecError::UnknownType {
+                tag: 246,
+                offset: 0,
+                path: String::new(),
+            }),
+        }
+    }
+
+    /// Returns the path of the value that the byte at `offset` of a decompressed data block
+    /// belongs to, found by decoding the data up to that byte.
+    ///
+    /// # Arguments
+    /// * `data` - The decompressed data block
+    /// * `offset` - Offset of the byte within `data`
+    /// * `short_strings` - Whether the data block uses the version 5 short strings
+    pub fn path_at(data: &[u8], offset: usize, short_strings: bool) -> String {
+        let mut cursor = Cursor::new(&data[..offset.min(data.len())]);
+
+        match Self::decode_value::<_, LuaValue>(&mut cursor, &mut DecodeState::new(short_strings)) {
+            Err(error) => error.path().unwrap_or_default().to_string(),
+            Ok(_) => String::new(),
+        }
+    }
+
+    /// Decodes everything following the signature and version, shared by version 4 and 5
+    /// which only differ in how strings are stored in the data block.
+    ///
+    /// # Arguments
+    /// * `reader` - The input stream positioned after the version byte
+    /// * `short_strings` - Whether the data block uses the version 5 short strings
+    /// * `strict` - Whether to validate the info block strictly
+    fn decode_file(
+        reader: &mut dyn Read,
+        short_strings: bool,
+        strict: bool,
+    ) -> Result<DuplicationResult, CodecError> {
+        // The signature and version were already read, offsets are reported from the file start
+        let info_start = (HEADER.len() + VERSION.len() + CRLF.len()) as u64;
+
+        // The info block is read through a buffer up to its terminator, everything after it is
+        // the compressed data block
+        let mut reader = BufReader::new(reader);
+
+        // Skip a CRLF byte
+        let mut crlf = [0; 1];
+        reader
+            .read_exact(&mut crlf)
+            .map_err(|_| CodecError::Truncated {
+                offset: info_start - 1,
+                path: String::new(),
+            })?;
+        if strict {
+            Self::check_line_feed(crlf[0], info_start - 1)?;
+        }
+
+        let mut info = Vec::new();
+        reader
+            .read_until(INFO_END[0], &mut info)
+            .map_err(|_| CodecError::Truncated {
+                offset: info_start + info.len() as u64,
+                path: String::new(),
+            })?;
+
+        if info.pop() != Some(INFO_END[0]) {
+            return Err(CodecError::InvalidInfo {
+                offset: info_start + info.len() as u64,
+                path: String::new(),
+                reason: String::from("info block is not terminated"),
+            });
+        }
+        let info_block_end = info.len();
+
+        // Skip the CRLF byte between the info and the data block
+        let data_start = info_block_end + INFO_END.len() + CRLF.len();
+        reader
+            .read_exact(&mut crlf)
+            .map_err(|_| CodecError::Truncated {
+                offset: info_start + (info_block_end + INFO_END.len()) as u64,
+                path: String::new(),
+            })?;
 
         // This will parse the info block into a DuplicationInfo struct
-        let dupe_info = AdCodec5::get_duplication_info(info.as_slice());
+        let dupe_info = AdCodec5::get_duplication_info(&info, info_start, strict)?;
+
+        if strict {
+            Self::check_line_feed(
+                crlf[0],
+                info_start + (info_block_end + INFO_END.len()) as u64,
+            )?;
+
+            if !dupe_info.values.iter().any(|(key, _)| key == "check") {
+                return Err(CodecError::MissingCheck {
+                    offset: info_start + info_block_end as u64,
+                });
+            }
+        }
 
-        let data = gmod_lzma::decompress(data.as_slice()).expect("Unable to decompress LZMA data");
+        // The data block is decoded while it is decompressed, an error of the decompression
+        // takes the place of the error it caused in the decode
+        let mut data = DataBlockReader::new(reader, info_start + data_start as u64)?;
+        let mut state = DecodeState::new(short_strings);
 
-        let mut data = Cursor::new(data.as_slice());
+        let value = Self::decode_value(&mut data, &mut state);
+        if let Some(error) = data.error.take() {
+            return Err(error);
+        }
 
-        let value = AdCodec5::decode_stream(&mut data).unwrap();
+        let value = match value? {
+            Some(value) => value,
+            None => {
+                return Err(CodecError::UnknownType {
+                    tag: 246,
+                    offset: 0,
+                    path: String::new(),
+                })
+            }
+        };
 
-        DuplicationResult {
+        // The rest of the data block is decompressed to check that the LZMA data is intact
+        data.finish()?;
+
+        Ok(DuplicationResult {
             info: dupe_info,
-            value: *value,
+            value,
+            table_references: state.references,
+        })
+    }
+
+    /// Checks that a byte around the info block is the line feed the game writes there. A
+    /// carriage return in its place means every LF of the file was translated to CRLF.
+    fn check_line_feed(byte: u8, offset: u64) -> Result<(), CodecError> {
+        match byte {
+            _ if byte == CRLF[0] => Ok(()),
+            b'\r' => Err(CodecError::LineEndings {
+                offset,
+                translation: LineEndings::LfToCrlf,
+            }),
+            _ => Err(CodecError::InvalidInfo {
+                offset,
+                path: String::new(),
+                reason: format!("expected a line feed, found byte {}", byte),
+            }),
         }
     }
 
-    /// Encode the `DuplicationInfo` and `LuaValue` to a binary format.
+    /// Records the start of a table or array and returns its index in `tables`
+    fn start_table(tables: &mut Vec<TableOffset>, offset: u64) -> usize {
+        tables.push(TableOffset {
+            offset,
+            complete: false,
+        });
+
+        tables.len() - 1
+    }
+
+    /// Serializes a `LuaValue` into a binary stream.
+    ///
+    /// The function takes in a `LuaValue` and a mutable reference to a type `T` that implements the
+    /// `Write` trait, and serializes the `LuaValue` into the binary stream.
     ///
     /// # Arguments
-    /// * `dupe_info` - A struct that contains information about the duplication
-    /// * `lua_value` - The Lua value that needs to be encoded
-    /// * `stream` - A mutable reference to a type that implements the `Write` trait
+    ///
+    /// * `value` - The `LuaValue` to be serialized.
+    /// * `stream` - A mutable reference to a type that implements the `Write` trait, representing the
+    ///   binary stream to serialize `value` into.
     ///
     /// # Returns
-    /// * `Result<(), Box<dyn Error>>` - Returns an error if there is any issue with writing to the `stream`
-    fn encode(
-        &self,
-        dupe_info: &DuplicationInfo,
-        lua_value: &LuaValue,
-        stream: &mut dyn Write,
+    ///
+    /// Returns a `Result` of type `Result<(), Box<dyn Error>>`. If the serialization is successful,
+    /// the `Ok` variant is returned. If an error occurs during serialization, the `Err` variant is
+    /// returned with an error message boxed in a `Box<dyn Error>`.
+    pub(crate) fn encode_stream<T: Write>(
+        value: &LuaValue,
+        stream: &mut T,
     ) -> Result<(), Box<dyn Error>> {
-        // First thing to do is write the signature and version
-        stream.write(&HEADER)?;
-        stream.write(&VERSION)?;
+        Self::encode_value(value, stream, None, true)
+    }
 
-        // Write CRLF byte
-        stream.write(&CRLF)?;
+    /// Serializes a single `LuaValue`, emitting back-references when `references` is given.
+    /// Without `short_strings` every string is written with the 248 data type, as version 4 did.
+    fn encode_value<'a, T: Write>(
+        value: &'a LuaValue,
+        stream: &mut T,
+        mut references: Option<&mut TableReferences<'a>>,
+        short_strings: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(id) = references.as_deref_mut().and_then(|r| r.visit(value)) {
+            let buff = [247; 1];
+            stream.write_all(&buff)?;
+            stream.write_all(&id.to_le_bytes())?;
 
-        for (k, v) in dupe_info.values.iter() {
-            stream.write(k.as_bytes())?;
-            stream.write(&INFO_SPLIT)?;
-            stream.write(v.as_bytes())?;
-            stream.write(&INFO_SPLIT)?;
+            return Ok(());
         }
 
-        // Termination of info block
-        stream.write(&INFO_END)?;
+        match value {
+            LuaValue::String(str) => {
+                if short_strings && str.len() < SHORT_STRING_LIMIT {
+                    let buff = [str.len() as u8];
+                    stream.write_all(&buff)?;
+                } else {
+                    let buff = [248; 1];
+                    stream.write_all(&buff)?;
 
-        // Write CRLF byte
-        stream.write(&CRLF)?;
+                    let length = u32::try_from(str.len())?;
+                    stream.write_all(&length.to_le_bytes())?;
+                }
 
-        // Serialize the lua type
-        let mut data = Cursor::new(Vec::new());
-        AdCodec5::encode_stream(lua_value, &mut data)?;
+                let buff = str.as_bytes();
+                stream.write_all(buff)?;
+            }
+            LuaValue::Array(arr) => {
+                let id = references.as_deref_mut().and_then(TableReferences::reserve);
 
-        // Compress the data block into LZMA format
-        let data = gmod_lzma::compress(&data.into_inner(), 9).expect("Unable to compress data");
+                let buff = [254; 1];
+                stream.write_all(&buff)?;
+
+                for item in arr {
+                    Self::encode_value(item, stream, references.as_deref_mut(), short_strings)?;
+                }
+
+                let buff: [u8; 1] = [246; 1];
+                stream.write_all(&buff)?;
 
-        stream.write(&data)?;
+                if let Some(references) = references {
+                    references.insert(value, id);
+                }
+            }
+            LuaValue::Table(tbl) => {
+                let id = references.as_deref_mut().and_then(TableReferences::reserve);
+
+                let buff = [255; 1];
+                stream.write_all(&buff)?;
+
+                for (key, item) in tbl {
+                    Self::encode_value(key, stream, references.as_deref_mut(), short_strings)?;
+                    Self::encode_value(item, stream, references.as_deref_mut(), short_strings)?;
+                }
+
+                let buff: [u8; 1] = [246; 1];
+                stream.write_all(&buff)?;
+
+                if let Some(references) = references {
+                    references.insert(value, id);
+                }
+            }
+            LuaValue::Double(double) => {
+                let buff = [251; 1];
+                stream.write_all(&buff)?;
+                stream.write_all(&Self::double_as_bytes(double))?;
+            }
+            LuaValue::Vector(vector) => {
+                let buff = [250; 1];
+                stream.write_all(&buff)?;
+
+                stream.write_all(&Self::double_as_bytes(vector.first().unwrap()))?;
+                stream.write_all(&Self::double_as_bytes(vector.get(1).unwrap()))?;
+                stream.write_all(&Self::double_as_bytes(vector.get(2).unwrap()))?;
+            }
+            LuaValue::Angle(angle) => {
+                let buff = [249; 1];
+                stream.write_all(&buff)?;
+
+                stream.write_all(&Self::double_as_bytes(angle.first().unwrap()))?;
+                stream.write_all(&Self::double_as_bytes(angle.get(1).unwrap()))?;
+                stream.write_all(&Self::double_as_bytes(angle.get(2).unwrap()))?;
+            }
+            LuaValue::Bool(boolean) => match boolean {
+                true => {
+                    let buff = [253; 1];
+                    stream.write_all(&buff)?;
+                }
+                false => {
+                    let buff = [252; 1];
+                    stream.write_all(&buff)?;
+                }
+            },
+        }
 
         Ok(())
     }
 
+    /// Returns deserialized double
+    ///
+    /// # Arguments
+    /// `input` - Stream that will contain the serialized double
+    fn read_double(input: &mut dyn Read) -> std::io::Result<f64> {
+        let mut data = [0u8; 8];
+        input.read_exact(&mut data)?;
+        Ok(f64::from_bits(u64::from_le_bytes(data)))
+    }
+
+    /// Returns a deserialized vector or angle, reporting the value at `offset` as truncated when
+    /// the data runs out.
+    fn read_vector<R: Read>(
+        reader: &mut R,
+        offset: u64,
+        path: &[String],
+    ) -> Result<[f64; 3], CodecError> {
+        let mut vector = [0.0; 3];
+        for component in &mut vector {
+            *component = Self::read_double(reader).map_err(|_| Self::truncated(offset, path))?;
+        }
+
+        Ok(vector)
+    }
+
+    /// Returns a string of `length` bytes read from the data block
+    ///
+    /// # Arguments
+    /// `reader` - Data block positioned at the string bytes
+    /// `length` - Number of bytes the string occupies
+    /// `offset` - Offset of the string value, used when reporting errors
+    /// `path` - Path of the string value, used when reporting errors
+    fn read_string<R: Read>(
+        reader: &mut R,
+        length: usize,
+        offset: u64,
+        path: &[String],
+    ) -> Result<String, CodecError> {
+        // Read only as much as there is rather than allocating up front, a corrupt long string
+        // length could be huge
+        let mut data = Vec::new();
+        reader
+            .take(length as u64)
+            .read_to_end(&mut data)
+            .map_err(|_| Self::truncated(offset, path))?;
+
+        if data.len() < length {
+            return Err(Self::truncated(offset, path));
+        }
+
+        String::from_utf8(data).map_err(|_| CodecError::InvalidUtf8 {
+            offset,
+            path: Self::path_string(path),
+        })
+    }
+
+    /// Returns serialized double represented as a byte array
+    ///
+    /// # Arguments
+    /// `value` - Double value to be serialized
+    fn double_as_bytes(value: &f64) -> [u8; 8] {
+        value.to_le_bytes()
+    }
+}
+
+impl AdCodec for AdCodec5 {
+    /// Decodes the `reader` into a `DuplicationResult` by reading the info block through a buffer
+    /// up to its terminator, parsing it into a `DuplicationInfo` struct, and then decoding the
+    /// data block into a `LuaValue` while the rest of the stream is decompressed.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The input stream to be decoded.
+    ///
+    /// # Returns
+    ///
+    /// A `DuplicationResult` containing the `DuplicationInfo` and the `LuaValue` decoded from the input stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CodecError` if the info block is malformed, or has no check pair when decoding
+    /// strictly, the LZMA data is unable to be decompressed, or the decompressed data block is truncated or malformed.
+    fn decode(&self, reader: &mut dyn Read) -> Result<DuplicationResult, CodecError> {
+        Self::decode_file(reader, true, self.strict)
+    }
+
+    /// Encode the `DuplicationInfo` and `LuaValue` to a binary format.
+    ///
+    /// # Arguments
+    /// * `dupe_info` - A struct that contains information about the duplication
+    /// * `lua_value` - The Lua value that needs to be encoded
+    /// * `stream` - A mutable reference to a type that implements the `Write` trait
+    ///
+    /// # Returns
+    /// * `Result<(), Box<dyn Error>>` - Returns an error if there is any issue with writing to the `stream`
+    fn encode(
+        &self,
+        dupe_info: &DuplicationInfo,
+        lua_value: &LuaValue,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        self.encode_file(dupe_info, lua_value, None, stream)
+    }
+
     /// Returns true if `signature` is a valid header signature.
     ///
     /// # Arguments
@@ -698,45 +1925,252 @@ impl AdCodec for AdCodec5 {
     /// # Example
     ///
     /// ```
-    /// use codec5::AdCodec5;
+    /// use adv2_splitter::codec::{AdCodec, AdCodec5};
     ///
     /// let codec = AdCodec5::default();
     ///
-    /// assert!(codec.is_valid_signature(&codec5::HEADER));
+    /// assert!(codec.is_valid_signature(b"AD2F"));
     /// ```
     fn is_valid_signature(&self, signature: &[u8]) -> bool {
         HEADER == signature
     }
 }
 
-// Error structs
-#[derive(Debug, Clone)]
-pub enum CodecError {
-    UnsupportedCodec(String),
-    InvalidHeader,
-}
-
+/// Version 4 of the codec, which AdCodec5 superseded.
+///
+/// The header, info block and LZMA compressed data block are laid out exactly as in version 5,
+/// and the data block uses the same type tags including table references. The only difference
+/// is that version 4 had no short strings, every string is written with the 248 data type and a
+/// 32bit length, so the tags below 246 are invalid.
+///
+/// Version 4 dupes are upgraded by encoding the result with `AdCodec5`.
+#[derive(Default)]
+pub struct AdCodec4 {
+    options: EncodeOptions,
+    strict: bool,
+}
+
+impl AdCodec4 {
+    pub fn with_options(options: EncodeOptions) -> Self {
+        AdCodec4 {
+            options,
+            ..Default::default()
+        }
+    }
+
+    pub fn options(&self) -> &EncodeOptions {
+        &self.options
+    }
+
+    /// Sets whether decoding checks the info block strictly, as with `AdCodec5::set_strict`
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Encodes a decoded dupe as version 4 like `AdCodec5::encode_result`, writing the
+    /// back-references of the data block in the same places as the file it was decoded from.
+    pub fn encode_result(
+        &self,
+        result: &DuplicationResult,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        AdCodec5::with_options(self.options).encode_version(
+            result.info(),
+            result.value(),
+            Some(&result.table_references),
+            4,
+            stream,
+        )
+    }
+}
+
+impl AdCodec for AdCodec4 {
+    fn decode(&self, reader: &mut dyn Read) -> Result<DuplicationResult, CodecError> {
+        AdCodec5::decode_file(reader, false, self.strict)
+    }
+
+    /// Encodes like `AdCodec5::encode` with the same options, but writes version 4 and every
+    /// string with the 248 data type.
+    fn encode(
+        &self,
+        dupe_info: &DuplicationInfo,
+        lua_value: &LuaValue,
+        stream: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        AdCodec5::with_options(self.options).encode_version(dupe_info, lua_value, None, 4, stream)
+    }
+
+    fn is_valid_signature(&self, signature: &[u8]) -> bool {
+        HEADER == signature
+    }
+}
+
+// Error structs
+//
+// Offsets inside the info block are counted from the start of the file, while offsets inside
+// the data block are counted from the start of the decompressed data. Paths are the keys
+// leading from the root table to the failing value joined with a dot, e.g. `Entities.12.Pos`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    UnsupportedCodec(String),
+    InvalidHeader,
+    /// The data ended in the middle of a value
+    Truncated {
+        offset: u64,
+        path: String,
+    },
+    /// A type tag that does not start a value was found
+    UnknownType {
+        tag: u8,
+        offset: u64,
+        path: String,
+    },
+    /// A string was not valid UTF-8
+    InvalidUtf8 {
+        offset: u64,
+        path: String,
+    },
+    /// A table reference pointed at a table that has not been fully decoded, or references
+    /// expanded into more values than `MAX_REFERENCED_VALUES`
+    InvalidReference {
+        id: u16,
+        offset: u64,
+        path: String,
+    },
+    /// The data block could not be decompressed, `code` is the LZMA error code
+    Lzma {
+        offset: u64,
+        code: gmod_lzma::SZ,
+    },
+    /// The info block could not be parsed, `path` is the info key at fault
+    InvalidInfo {
+        offset: u64,
+        path: String,
+        reason: String,
+    },
+    /// The info block did not contain the check pair
+    MissingCheck {
+        offset: u64,
+    },
+    /// The check value or a line feed around the info block shows that the line endings of
+    /// the whole file were translated
+    LineEndings {
+        offset: u64,
+        translation: LineEndings,
+    },
+}
+
+/// A line ending translation applied to a whole dupe file, as done by a text mode transfer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEndings {
+    /// Every LF was written as CRLF, which can be undone exactly
+    LfToCrlf,
+    /// Every CRLF was written as LF, which loses the CR bytes
+    CrlfToLf,
+}
+
+impl LineEndings {
+    /// Returns the translation that turns `CHECK_VALUE` into `value`, if it is one of them
+    pub fn from_check(value: &str) -> Option<LineEndings> {
+        match value {
+            "\r\r\n\t\r\n" => Some(LineEndings::LfToCrlf),
+            "\n\t\n" => Some(LineEndings::CrlfToLf),
+            _ => None,
+        }
+    }
+}
+
+impl CodecError {
+    /// Returns the byte offset the error occurred at, if it has one
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            CodecError::UnsupportedCodec(_) | CodecError::InvalidHeader => None,
+            CodecError::Truncated { offset, .. }
+            | CodecError::UnknownType { offset, .. }
+            | CodecError::InvalidUtf8 { offset, .. }
+            | CodecError::InvalidReference { offset, .. }
+            | CodecError::Lzma { offset, .. }
+            | CodecError::InvalidInfo { offset, .. }
+            | CodecError::MissingCheck { offset }
+            | CodecError::LineEndings { offset, .. } => Some(*offset),
+        }
+    }
+
+    /// Returns the path into the Lua tree, or the info key, where the error occurred
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            CodecError::Truncated { path, .. }
+            | CodecError::UnknownType { path, .. }
+            | CodecError::InvalidUtf8 { path, .. }
+            | CodecError::InvalidReference { path, .. }
+            | CodecError::InvalidInfo { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
 impl Display for CodecError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Unsupported Codec was passed")
+        match self {
+            CodecError::UnsupportedCodec(version) => {
+                write!(f, "Unsupported Codec was passed: version {}", version)
+            }
+            CodecError::InvalidHeader => write!(f, "Invalid header signature"),
+            CodecError::Truncated { .. } => write!(f, "Unexpected end of data"),
+            CodecError::UnknownType { tag, .. } => write!(f, "Unknown type tag {}", tag),
+            CodecError::InvalidUtf8 { .. } => write!(f, "String is not valid UTF-8"),
+            CodecError::InvalidReference { id, .. } => {
+                write!(f, "Invalid reference to table {}", id)
+            }
+            CodecError::Lzma { code, .. } => {
+                write!(f, "Unable to decompress LZMA data (error {})", code)
+            }
+            CodecError::InvalidInfo { reason, .. } => write!(f, "Invalid info block: {}", reason),
+            CodecError::MissingCheck { .. } => write!(f, "Info block has no check pair"),
+            CodecError::LineEndings { translation, .. } => {
+                let (from, to) = match translation {
+                    LineEndings::LfToCrlf => ("LF", "CRLF"),
+                    LineEndings::CrlfToLf => ("CRLF", "LF"),
+                };
+                write!(f, "Line endings were translated from {} to {}", from, to)
+            }
+        }?;
+
+        if let Some(offset) = self.offset() {
+            write!(f, " at offset {}", offset)?;
+        }
+
+        match self.path() {
+            Some(path) if !path.is_empty() => write!(f, " ({})", path),
+            _ => Ok(()),
+        }
     }
 }
 
 impl Error for CodecError {}
 
+#[cfg(test)]
 mod test {
-    use std::collections::HashMap;
     use std::error::Error;
-    use std::io::{Cursor, ErrorKind, Write};
-    use std::ptr::write;
+    use std::io::{Cursor, Read, Write};
 
+    use chrono::{FixedOffset, TimeZone};
+
+    use crate::codec::{
+        AdCodec, AdCodec4, AdCodec5, CodecError, DuplicationInfo, EncodeOptions, LineEndings,
+        LuaTable, LuaValue, TableReferences, CHECK_VALUE, HEADER, MAX_COMPRESSION_LEVEL,
+    };
+    use crate::lua_ref::LuaValueRef;
     use crate::{get_lua_value, lua_string};
-    use crate::codec::{AdCodec, AdCodec5, HEADER, LuaValue};
 
     #[test]
     fn test_signature_header() -> Result<(), Box<dyn Error>> {
         let codec = AdCodec5::default();
-        assert!(codec.is_valid_signature(&HEADER));
+        assert!(codec.is_valid_signature(HEADER));
 
         Ok(())
     }
@@ -746,7 +2180,7 @@ mod test {
         let double = 50.56;
         let double_bytes = AdCodec5::double_as_bytes(&double);
         let mut cursor = Cursor::new(double_bytes);
-        let read_double = AdCodec5::read_double(&mut cursor);
+        let read_double = AdCodec5::read_double(&mut cursor)?;
 
         assert_eq!(read_double, double);
 
@@ -758,34 +2192,110 @@ mod test {
         let duplication_info = String::from("name\u{1}test");
         let duplication_info = duplication_info.as_bytes();
 
-        let result = AdCodec5::get_duplication_info(duplication_info);
+        let result = AdCodec5::get_duplication_info(duplication_info, 0, false)?;
 
         assert_eq!(result.player_name(), "test");
         Ok(())
     }
 
+    #[test]
+    fn test_set_info_value() -> Result<(), Box<dyn Error>> {
+        let mut info = AdCodec5::get_duplication_info(b"name\x01test\x01size\x0110\x01", 0, false)?;
+
+        info.set_value("name", String::from("merged"));
+        info.set_value("size", String::from("big"));
+        info.set_value("part", String::from("1/2"));
+
+        assert_eq!(info.player_name(), "merged");
+        assert_eq!(info.size(), 10);
+
+        let values: Vec<(&str, &str)> = info
+            .values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![("name", "merged"), ("size", "big"), ("part", "1/2")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_setters() -> Result<(), Box<dyn Error>> {
+        let mut info = AdCodec5::get_duplication_info(
+            b"name\x01test\x01size\x0110\x01check\x01\r\n\t\n\x01",
+            0,
+            false,
+        )?;
+
+        info.set_size(20);
+        info.set_player_name(String::from("split"));
+        let date_time = FixedOffset::east_opt(3600)
+            .and_then(|offset| offset.with_ymd_and_hms(2023, 1, 28, 15, 45, 0).single())
+            .expect("Invalid date");
+        info.set_date_time(&date_time);
+
+        assert_eq!(info.size(), 20);
+        assert_eq!(
+            info.values().collect::<Vec<_>>(),
+            vec![
+                ("name", "split"),
+                ("size", "20"),
+                ("check", "\r\n\t\n"),
+                ("date", "28 January 2023"),
+                ("time", "03:45 PM"),
+                ("timezone", "+0100"),
+            ]
+        );
+
+        // The size is only replaced by the length of the data block when asked to
+        let value = LuaValue::Table(LuaTable::new());
+        let mut file = Vec::new();
+        AdCodec5::default().encode(&info, &value, &mut file)?;
+        assert_eq!(
+            AdCodec5::default().decode(&mut &file[5..])?.info().size(),
+            20
+        );
+
+        let codec = AdCodec5::with_options(EncodeOptions {
+            update_size: true,
+            ..Default::default()
+        });
+        let mut file = Vec::new();
+        codec.encode(&info, &value, &mut file)?;
+
+        let data_start = AdCodec5::data_block_offset(&file).expect("Missing data block");
+        let result = codec.decode(&mut &file[5..])?;
+        assert_eq!(result.info().size() as usize, file.len() - data_start);
+        assert_eq!(info.size(), 20);
+
+        Ok(())
+    }
+
     #[test]
     fn test_lua_deserialize() -> Result<(), Box<dyn Error>> {
         let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
 
         // LuaValue Table
-        stream.write(&[255; 1])?;
+        stream.write_all(&[255; 1])?;
 
         // Write a entry for "Key"
-        stream.write(&[3; 1])?;
-        stream.write("Key".as_bytes())?;
+        stream.write_all(&[3; 1])?;
+        stream.write_all("Key".as_bytes())?;
 
         // Write value for double 20.25
-        stream.write(&[251; 1])?;
-        stream.write(&AdCodec5::double_as_bytes(&20.25))?;
+        stream.write_all(&[251; 1])?;
+        stream.write_all(&AdCodec5::double_as_bytes(&20.25))?;
 
         // Write the termination block
-        stream.write(&[246; 1])?;
+        stream.write_all(&[246; 1])?;
 
         // Deserialize the stream
         let stream = stream.into_inner();
         let mut stream = Cursor::new(stream.as_slice());
-        let result = AdCodec5::decode_stream(&mut stream);
+        let result = AdCodec5::decode_stream(&mut stream)?;
 
         let result = *result.expect("An error occurred while decoding the stream");
 
@@ -798,7 +2308,7 @@ mod test {
         // Ensure the value is a double
         let value = get_lua_value!(Double, value.clone()).expect("Unable to find double");
 
-        assert_eq!(value, 20.25 as f64);
+        assert_eq!(value, 20.25_f64);
 
         Ok(())
     }
@@ -806,7 +2316,7 @@ mod test {
     #[test]
     fn test_lua_serialize() -> Result<(), Box<dyn Error>> {
         // Create the artificial lua table
-        let mut table: HashMap<LuaValue, LuaValue> = HashMap::default();
+        let mut table: LuaTable = LuaTable::default();
         table.insert(
             LuaValue::String(String::from("Key")),
             LuaValue::Double(20.25),
@@ -837,7 +2347,7 @@ mod test {
 
     #[test]
     fn test_deserialize_and_serialize() -> Result<(), Box<dyn Error>> {
-        let mut table: HashMap<LuaValue, LuaValue> = HashMap::default();
+        let mut table: LuaTable = LuaTable::default();
         table.insert(lua_string!("Key"), LuaValue::Double(20.25));
 
         let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
@@ -849,7 +2359,7 @@ mod test {
         let stream = stream.into_inner();
         let mut stream = Cursor::new(stream.as_slice());
         let result =
-            *AdCodec5::decode_stream(&mut stream).expect("Unable to deserialize lua table");
+            *AdCodec5::decode_stream(&mut stream)?.expect("Unable to deserialize lua table");
 
         // Validate the result was a table
         let result = get_lua_value!(Table, result).expect("Unable to find table");
@@ -860,8 +2370,766 @@ mod test {
         // Ensure the value is a double
         let value = get_lua_value!(Double, value.clone()).expect("Unable to find the double");
 
-        assert_eq!(value, 20.25 as f64);
+        assert_eq!(value, 20.25_f64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_length_boundaries() -> Result<(), Box<dyn Error>> {
+        for length in [245, 246, 247, 4096, 65_536] {
+            let value = lua_string!("a".repeat(length));
+
+            let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+            AdCodec5::encode_stream(&value, &mut stream).expect("Unable to serialize string");
+
+            let serialized_data = stream.into_inner();
+
+            // Short strings carry a single length byte, long strings the 248 tag and a u32 length
+            if length < 246 {
+                assert_eq!(serialized_data[0], length as u8);
+                assert_eq!(serialized_data.len(), 1 + length);
+            } else {
+                assert_eq!(serialized_data[0], 248);
+                assert_eq!(serialized_data[1..5], (length as u32).to_le_bytes());
+                assert_eq!(serialized_data.len(), 5 + length);
+            }
+
+            let mut stream = Cursor::new(serialized_data.as_slice());
+            let result =
+                *AdCodec5::decode_stream(&mut stream)?.expect("Unable to deserialize string");
+
+            assert_eq!(result, value);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_string_in_table() -> Result<(), Box<dyn Error>> {
+        let code = "print(\"hello\")\n".repeat(512);
+
+        let mut table: LuaTable = LuaTable::default();
+        table.insert(lua_string!("Code"), lua_string!(code));
+        table.insert(lua_string!("Key"), LuaValue::Double(20.25));
+
+        let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        AdCodec5::encode_stream(&LuaValue::Table(table.clone()), &mut stream)
+            .expect("Unable to serialize table");
+
+        let stream = stream.into_inner();
+        let mut stream = Cursor::new(stream.as_slice());
+        let result =
+            *AdCodec5::decode_stream(&mut stream)?.expect("Unable to deserialize lua table");
+
+        assert_eq!(result, LuaValue::Table(table));
+
+        Ok(())
+    }
+
+    /// Serializes a `LuaValue` like `encode_stream`, but any table equal to one already written
+    /// is replaced by a back-reference to it
+    fn encode_stream_deduped<T: Write>(
+        value: &LuaValue,
+        stream: &mut T,
+    ) -> Result<(), Box<dyn Error>> {
+        AdCodec5::encode_value(value, stream, Some(&mut TableReferences::default()), true)
+    }
+
+    #[test]
+    fn test_table_reference_deserialize() -> Result<(), Box<dyn Error>> {
+        let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+
+        // Outer array, table id 1
+        stream.write_all(&[254; 1])?;
+
+        // Inner table, table id 2
+        stream.write_all(&[255; 1])?;
+        stream.write_all(&[3; 1])?;
+        stream.write_all("Key".as_bytes())?;
+        stream.write_all(&[251; 1])?;
+        stream.write_all(&AdCodec5::double_as_bytes(&20.25))?;
+        stream.write_all(&[246; 1])?;
+
+        // Reference back to table id 2
+        stream.write_all(&[247; 1])?;
+        stream.write_all(&2u16.to_le_bytes())?;
+
+        stream.write_all(&[246; 1])?;
+
+        let stream = stream.into_inner();
+        let mut stream = Cursor::new(stream.as_slice());
+        let result = *AdCodec5::decode_stream(&mut stream)?.expect("Unable to decode stream");
+
+        let array = get_lua_value!(Array, result).expect("Unable to find array");
+        assert_eq!(array.len(), 2);
+        assert_eq!(array[0], array[1]);
+
+        let table = array[1]
+            .as_table()
+            .expect("Reference was not resolved to a table");
+        assert_eq!(
+            table.get(&lua_string!("Key")),
+            Some(&LuaValue::Double(20.25))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_reference_serialize() -> Result<(), Box<dyn Error>> {
+        let mut inner: LuaTable = LuaTable::default();
+        inner.insert(lua_string!("Key"), LuaValue::Double(20.25));
+
+        let value = LuaValue::Array(vec![
+            LuaValue::Table(inner.clone()),
+            LuaValue::Table(inner),
+            LuaValue::Table(LuaTable::default()),
+            LuaValue::Table(LuaTable::default()),
+        ]);
+
+        let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        encode_stream_deduped(&value, &mut stream).expect("Unable to serialize array");
+
+        let serialized_data = stream.into_inner();
+
+        let mut data: Vec<u8> = Vec::new();
+        data.push(254);
+        data.push(255);
+        data.push(3);
+        data.extend(b"Key");
+        data.push(251);
+        data.extend(AdCodec5::double_as_bytes(&20.25));
+        data.push(246);
+        data.push(247);
+        data.extend(2u16.to_le_bytes());
+        // Empty tables are cheaper to repeat than to reference
+        data.extend([255, 246, 255, 246]);
+        data.push(246);
+
+        assert_eq!(serialized_data, data);
+
+        let mut stream = Cursor::new(serialized_data.as_slice());
+        let result = *AdCodec5::decode_stream(&mut stream)?.expect("Unable to decode stream");
+
+        assert_eq!(result, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_table_reference_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut position: LuaTable = LuaTable::default();
+        position.insert(lua_string!("Pos"), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+
+        let mut entity: LuaTable = LuaTable::default();
+        entity.insert(lua_string!("Physics"), LuaValue::Table(position.clone()));
+        entity.insert(lua_string!("Extra"), LuaValue::Table(position));
+
+        let mut entities: LuaTable = LuaTable::default();
+        entities.insert(LuaValue::Double(1.0), LuaValue::Table(entity.clone()));
+        entities.insert(LuaValue::Double(2.0), LuaValue::Table(entity));
+
+        let value = LuaValue::Table(entities);
+
+        let mut plain: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        AdCodec5::encode_stream(&value, &mut plain)?;
+
+        let mut referenced: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        encode_stream_deduped(&value, &mut referenced)?;
+
+        let referenced = referenced.into_inner();
+        assert!(referenced.len() < plain.into_inner().len());
+
+        let mut stream = Cursor::new(referenced.as_slice());
+        let result = *AdCodec5::decode_stream(&mut stream)?.expect("Unable to decode stream");
+
+        assert_eq!(result, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_tables_is_opt_in() -> Result<(), Box<dyn Error>> {
+        let mut mods: LuaTable = LuaTable::default();
+        mods.insert(
+            lua_string!("Material"),
+            lua_string!("models/debug/debugwhite"),
+        );
+
+        let mut entity: LuaTable = LuaTable::default();
+        entity.insert(lua_string!("EntityMods"), LuaValue::Table(mods));
+
+        let value = LuaValue::Array(vec![
+            LuaValue::Table(entity.clone()),
+            LuaValue::Table(entity),
+        ]);
+
+        let mut info = DuplicationInfo::default();
+        info.set_value("check", String::from(CHECK_VALUE));
+
+        // Equal tables are separate tables in game, so they are written out in full
+        let mut file = Vec::new();
+        AdCodec5::default().encode(&info, &value, &mut file)?;
+        let data = AdCodec5::decompress_data_block(&file)?;
+        assert!(!data.contains(&247));
+
+        let mut codec = AdCodec5::default();
+        codec.set_dedupe_tables(true);
+        let mut file = Vec::new();
+        codec.encode(&info, &value, &mut file)?;
+        let deduped = AdCodec5::decompress_data_block(&file)?;
+        assert!(deduped.contains(&247));
+        assert!(deduped.len() < data.len());
+
+        // Ids past 32767 would be read back as negative by the game
+        let mut references = TableReferences {
+            count: 32766,
+            ..Default::default()
+        };
+        assert_eq!(references.reserve(), Some(32767));
+        assert_eq!(references.reserve(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_order_is_kept() -> Result<(), Box<dyn Error>> {
+        let mut table: LuaTable = LuaTable::default();
+        for i in (1..=50).rev() {
+            table.insert(
+                LuaValue::Double(i as f64),
+                lua_string!(format!("Entity{}", i)),
+            );
+        }
+        table.insert(lua_string!("Name"), LuaValue::Bool(true));
+
+        let mut first: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        encode_stream_deduped(&LuaValue::Table(table.clone()), &mut first)?;
+        let first = first.into_inner();
+
+        let mut stream = Cursor::new(first.as_slice());
+        let result = *AdCodec5::decode_stream(&mut stream)?.expect("Unable to decode stream");
+
+        // The keys come back in the order they were written
+        let keys: Vec<&LuaValue> = result.as_table().unwrap().keys().collect();
+        assert_eq!(keys, table.keys().collect::<Vec<_>>());
+
+        // So writing the decoded value again gives the same bytes
+        let mut second: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        encode_stream_deduped(&result, &mut second)?;
+        assert_eq!(second.into_inner(), first);
 
         Ok(())
     }
+
+    #[test]
+    fn test_sort_keys() {
+        let mut inner: LuaTable = LuaTable::default();
+        inner.insert(lua_string!("b"), LuaValue::Bool(false));
+        inner.insert(lua_string!("a"), LuaValue::Bool(true));
+
+        let mut table: LuaTable = LuaTable::default();
+        table.insert(LuaValue::Bool(true), LuaValue::Table(inner));
+        table.insert(lua_string!("Key"), LuaValue::Double(1.0));
+        table.insert(LuaValue::Double(10.0), LuaValue::Double(2.0));
+        table.insert(LuaValue::Double(2.0), LuaValue::Double(3.0));
+
+        let mut value = LuaValue::Table(table);
+        value.sort_keys();
+
+        let table = value.as_table().unwrap();
+        assert_eq!(
+            table.keys().collect::<Vec<_>>(),
+            vec![
+                &LuaValue::Double(2.0),
+                &LuaValue::Double(10.0),
+                &lua_string!("Key"),
+                &LuaValue::Bool(true),
+            ]
+        );
+
+        let inner = table[&LuaValue::Bool(true)].as_table().unwrap();
+        assert_eq!(
+            inner.keys().collect::<Vec<_>>(),
+            vec![&lua_string!("a"), &lua_string!("b")]
+        );
+    }
+
+    #[test]
+    fn test_truncated_stream_reports_path() -> Result<(), Box<dyn Error>> {
+        let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+
+        // { Entities = { 5 = <double cut short> } }
+        stream.write_all(&[255; 1])?;
+        stream.write_all(&[8; 1])?;
+        stream.write_all("Entities".as_bytes())?;
+        stream.write_all(&[255; 1])?;
+        stream.write_all(&[251; 1])?;
+        stream.write_all(&AdCodec5::double_as_bytes(&5.0))?;
+        stream.write_all(&[251, 0, 0, 0])?;
+
+        let stream = stream.into_inner();
+        let mut stream = Cursor::new(stream.as_slice());
+        let error = AdCodec5::decode_stream(&mut stream).expect_err("Stream should be truncated");
+
+        assert_eq!(
+            error,
+            CodecError::Truncated {
+                offset: 20,
+                path: String::from("Entities.5"),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_utf8_string() -> Result<(), Box<dyn Error>> {
+        let data: Vec<u8> = vec![254, 2, 0xC3, 0x28, 246];
+
+        let mut stream = Cursor::new(data.as_slice());
+        let error = AdCodec5::decode_stream(&mut stream).expect_err("String should be invalid");
+
+        assert_eq!(
+            error,
+            CodecError::InvalidUtf8 {
+                offset: 1,
+                path: String::from("1"),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_table_reference() -> Result<(), Box<dyn Error>> {
+        // An array that references itself before it has been completed
+        let data: Vec<u8> = vec![254, 247, 1, 0, 246];
+
+        let mut stream = Cursor::new(data.as_slice());
+        let error = AdCodec5::decode_stream(&mut stream).expect_err("Reference should be invalid");
+
+        assert!(matches!(
+            error,
+            CodecError::InvalidReference {
+                id: 1,
+                offset: 1,
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chained_table_references() {
+        // An array of tables, each holding two references to the one before it, which would
+        // expand into 2^40 values
+        let mut data: Vec<u8> = vec![254, 254, 253, 246];
+        for id in 2u16..=41 {
+            data.push(254);
+            for _ in 0..2 {
+                data.push(247);
+                data.extend(id.to_le_bytes());
+            }
+            data.push(246);
+        }
+        data.push(246);
+
+        let mut stream = Cursor::new(data.as_slice());
+        let error = AdCodec5::decode_stream(&mut stream).expect_err("References should be limited");
+        assert!(matches!(error, CodecError::InvalidReference { .. }));
+
+        assert!(matches!(
+            LuaValueRef::decode(&data),
+            Err(CodecError::InvalidReference { .. })
+        ));
+    }
+
+    #[test]
+    fn test_missing_table_value() -> Result<(), Box<dyn Error>> {
+        let data: Vec<u8> = vec![255, 3, b'K', b'e', b'y', 246];
+
+        let mut stream = Cursor::new(data.as_slice());
+        let error = AdCodec5::decode_stream(&mut stream).expect_err("Value should be missing");
+
+        assert_eq!(
+            error,
+            CodecError::UnknownType {
+                tag: 246,
+                offset: 5,
+                path: String::from("Key"),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_info_errors() -> Result<(), Box<dyn Error>> {
+        let codec = AdCodec5::default();
+
+        // Info block without a terminator
+        let mut data: Vec<u8> = vec![10];
+        data.extend(b"name\x01test");
+        let error = codec
+            .decode(&mut data.as_slice())
+            .err()
+            .expect("Info should be invalid");
+        assert!(matches!(error, CodecError::InvalidInfo { .. }));
+
+        // Data block that is not LZMA
+        let mut data: Vec<u8> = vec![10];
+        data.extend(b"check\x01\r\n\t\n\x01");
+        data.extend([2, 10, 1, 2, 3]);
+        let error = codec
+            .decode(&mut data.as_slice())
+            .err()
+            .expect("LZMA should be invalid");
+        assert!(matches!(error, CodecError::Lzma { offset: 19, .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_strict_info() -> Result<(), Box<dyn Error>> {
+        let mut strict = AdCodec5::default();
+        strict.set_strict(true);
+
+        let decode = |codec: &AdCodec5, info: &[u8]| {
+            let mut data: Vec<u8> = vec![10];
+            data.extend(info);
+            data.extend([2, 10]);
+            data.extend(gmod_lzma::compress(&[255, 246], 9).expect("Unable to compress"));
+            codec.decode(&mut data.as_slice())
+        };
+
+        decode(&strict, b"name\x01test\x01check\x01\r\n\t\n\x01")?;
+
+        // The check pair is only required when strict
+        decode(&AdCodec5::default(), b"name\x01test\x01")?;
+        assert_eq!(
+            decode(&strict, b"name\x01test\x01").err(),
+            Some(CodecError::MissingCheck { offset: 16 })
+        );
+
+        // A trailing key without a value is only dropped when not strict
+        let info = b"check\x01\r\n\t\n\x01name";
+        assert_eq!(
+            decode(&AdCodec5::default(), info)?.info().values().count(),
+            1
+        );
+        let error = decode(&strict, info)
+            .err()
+            .expect("Key should have no value");
+        assert!(matches!(error, CodecError::InvalidInfo { offset: 17, .. }));
+        assert_eq!(error.path(), Some("name"));
+
+        let error = decode(&strict, b"check\x01\r\n\t\n").err();
+        assert!(matches!(
+            error,
+            Some(CodecError::InvalidInfo { offset: 16, .. })
+        ));
+
+        // A size that is not a number is only rejected when strict
+        let info = b"size\x01big\x01check\x01\r\n\t\n\x01";
+        let result = decode(&AdCodec5::default(), info)?;
+        assert_eq!(result.info().size(), 0);
+        assert_eq!(result.info().values().next(), Some(("size", "big")));
+        let error = decode(&strict, info).err().expect("Size should be invalid");
+        assert!(matches!(error, CodecError::InvalidInfo { offset: 11, .. }));
+        assert_eq!(error.path(), Some("size"));
+
+        // A check value translated from CRLF to LF
+        let info = b"name\x01test\x01check\x01\n\t\n\x01";
+        decode(&AdCodec5::default(), info)?;
+        assert_eq!(
+            decode(&strict, info).err(),
+            Some(CodecError::LineEndings {
+                offset: 22,
+                translation: LineEndings::CrlfToLf,
+            })
+        );
+
+        let error = decode(&strict, b"check\x01\t\x01").err();
+        assert!(matches!(
+            error,
+            Some(CodecError::InvalidInfo { offset: 12, .. })
+        ));
+
+        // The line feed after the version translated from LF to CRLF
+        let mut data: Vec<u8> = vec![13, 10];
+        data.extend(b"check\x01\r\r\n\t\r\n\x01");
+        assert_eq!(
+            strict.decode(&mut data.as_slice()).err(),
+            Some(CodecError::LineEndings {
+                offset: 5,
+                translation: LineEndings::LfToCrlf,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_version_4() -> Result<(), Box<dyn Error>> {
+        let mut stream: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+
+        // Version 4 writes every string, including short ones, with the long string type
+        stream.write_all(&[255; 1])?;
+        stream.write_all(&[248; 1])?;
+        stream.write_all(&3u32.to_le_bytes())?;
+        stream.write_all("Key".as_bytes())?;
+        stream.write_all(&[251; 1])?;
+        stream.write_all(&AdCodec5::double_as_bytes(&20.25))?;
+        stream.write_all(&[246; 1])?;
+
+        let mut data: Vec<u8> = vec![10];
+        data.extend(b"name\x01test\x01check\x01\r\n\t\n\x01");
+        data.extend([2, 10]);
+        data.extend(gmod_lzma::compress(&stream.into_inner(), 9).expect("Unable to compress"));
+
+        let result = AdCodec4::default().decode(&mut data.as_slice())?;
+        assert_eq!(result.info().player_name(), "test");
+
+        let mut table: LuaTable = LuaTable::default();
+        table.insert(lua_string!("Key"), LuaValue::Double(20.25));
+        assert_eq!(result.value(), &LuaValue::Table(table));
+
+        // Upgrading re-encodes the dupe as version 5, which decodes to the same value
+        let mut upgraded: Vec<u8> = Vec::new();
+        let codec = AdCodec5::default();
+        codec.encode(result.info(), result.value(), &mut upgraded)?;
+
+        assert_eq!(upgraded[..5], [65, 68, 50, 70, 5]);
+        let upgraded_result = codec.decode(&mut &upgraded[5..])?;
+        assert_eq!(upgraded_result.value(), result.value());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_4_rejects_short_strings() -> Result<(), Box<dyn Error>> {
+        let mut data: Vec<u8> = vec![10];
+        data.extend(b"check\x01\r\n\t\n\x01");
+        data.extend([2, 10]);
+        data.extend(gmod_lzma::compress(&[254, 3, b'K', b'e', b'y', 246], 9).unwrap());
+
+        let error = AdCodec4::default()
+            .decode(&mut data.as_slice())
+            .err()
+            .expect("Short strings should be rejected");
+
+        assert_eq!(
+            error,
+            CodecError::UnknownType {
+                tag: 3,
+                offset: 1,
+                path: String::from("1"),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_4_file() -> Result<(), Box<dyn Error>> {
+        // { Name = "Wheel", Pos = Vector(1, 2, 3), Left = { Mass = 10 }, Right = <reference to
+        // Left> }, laid out as version 5 except that every string is a long string
+        let mut data: Vec<u8> = vec![255];
+        data.extend([248, 4, 0, 0, 0, b'N', b'a', b'm', b'e']);
+        data.extend([248, 5, 0, 0, 0, b'W', b'h', b'e', b'e', b'l']);
+        data.extend([248, 3, 0, 0, 0, b'P', b'o', b's', 250]);
+        data.extend(1f64.to_le_bytes());
+        data.extend(2f64.to_le_bytes());
+        data.extend(3f64.to_le_bytes());
+        data.extend([248, 4, 0, 0, 0, b'L', b'e', b'f', b't', 255]);
+        data.extend([248, 4, 0, 0, 0, b'M', b'a', b's', b's', 251]);
+        data.extend(10f64.to_le_bytes());
+        data.extend([
+            246, 248, 5, 0, 0, 0, b'R', b'i', b'g', b'h', b't', 247, 2, 0, 246,
+        ]);
+
+        let mut file: Vec<u8> = b"AD2F\x04\n".to_vec();
+        file.extend(b"name\x01test\x01check\x01\r\n\t\n\x01");
+        file.extend([2, 10]);
+        file.extend(gmod_lzma::compress(&data, 9).expect("Unable to compress"));
+
+        // The version dispatch picks the version 4 codec
+        let result = crate::advanced_dupe::decode_strict(file.as_slice())?;
+        assert_eq!(result.info().player_name(), "test");
+
+        let value = result.value();
+        let table = value.as_table().expect("Unable to find the table");
+        assert_eq!(table.get(&lua_string!("Name")), Some(&lua_string!("Wheel")));
+        assert_eq!(
+            table.get(&lua_string!("Right")),
+            table.get(&lua_string!("Left"))
+        );
+
+        // Encoding as version 4 writes the file back as it was
+        let mut encoded = Vec::new();
+        AdCodec4::default().encode_result(&result, &mut encoded)?;
+
+        assert_eq!(encoded[..6], file[..6]);
+        assert_eq!(
+            AdCodec5::data_block_offset(&encoded),
+            AdCodec5::data_block_offset(&file)
+        );
+        assert_eq!(AdCodec5::decompress_data_block(&encoded)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_result_round_trips() -> Result<(), Box<dyn Error>> {
+        // { a = { x = true }, b = { x = true }, c = <reference to a> }, where b is equal to a
+        // but was written out in full, so only c may become a reference again
+        let data: Vec<u8> = vec![
+            255, 1, b'a', 255, 1, b'x', 253, 246, 1, b'b', 255, 1, b'x', 253, 246, 1, b'c', 247, 2,
+            0, 246,
+        ];
+
+        let mut file: Vec<u8> = vec![10];
+        file.extend(b"name\x01test\x01check\x01\r\n\t\n\x01date\x0101/01/2024\x01");
+        file.extend([2, 10]);
+        let data_start = file.len();
+        file.extend(gmod_lzma::compress(&data, 9).expect("Unable to compress"));
+
+        let codec = AdCodec5::default();
+        let result = codec.decode(&mut file.as_slice())?;
+
+        let mut encoded: Vec<u8> = Vec::new();
+        codec.encode_result(&result, &mut encoded)?;
+
+        // The info block keeps its order and the data block its references
+        assert_eq!(encoded[5..5 + data_start], file[..data_start]);
+        let encoded_data = gmod_lzma::decompress(&encoded[5 + data_start..]).unwrap();
+        assert_eq!(encoded_data, data);
+
+        // Without the recorded references no table is referenced, unless equal tables are
+        // asked to be
+        let mut plain: Vec<u8> = Vec::new();
+        codec.encode(result.info(), result.value(), &mut plain)?;
+        let plain = gmod_lzma::decompress(&plain[5 + data_start..]).unwrap();
+        assert!(plain.len() > data.len());
+
+        let mut codec = AdCodec5::default();
+        codec.set_dedupe_tables(true);
+        let mut deduplicated: Vec<u8> = Vec::new();
+        codec.encode(result.info(), result.value(), &mut deduplicated)?;
+        let deduplicated = gmod_lzma::decompress(&deduplicated[5 + data_start..]).unwrap();
+        assert!(deduplicated.len() < data.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_level() -> Result<(), Box<dyn Error>> {
+        let mut table = LuaTable::new();
+        table.insert(lua_string!("Code"), lua_string!("print(1)\n".repeat(200)));
+        let value = LuaValue::Table(table);
+
+        let mut info = DuplicationInfo::default();
+        info.set_value("check", String::from("\r\n\t\n"));
+
+        for level in [0, MAX_COMPRESSION_LEVEL] {
+            let codec = AdCodec5::with_options(EncodeOptions {
+                compression_level: level,
+                ..Default::default()
+            });
+
+            let mut file = Vec::new();
+            codec.encode(&info, &value, &mut file)?;
+            assert_eq!(codec.decode(&mut &file[5..])?.value(), &value);
+        }
+
+        let mut codec = AdCodec5::default();
+        codec.set_compression_level(MAX_COMPRESSION_LEVEL + 1);
+        assert!(codec.encode(&info, &value, &mut Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_from_reader() -> Result<(), Box<dyn Error>> {
+        // A reader that hands out a single byte per read, like a slow network stream
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match (self.0.split_first(), buf.first_mut()) {
+                    (Some((byte, rest)), Some(first)) => {
+                        *first = *byte;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    _ => Ok(0),
+                }
+            }
+        }
+
+        // { a = { x = true }, c = <reference to a> }
+        let data: Vec<u8> = vec![
+            255, 1, b'a', 255, 1, b'x', 253, 246, 1, b'c', 247, 2, 0, 246,
+        ];
+
+        let mut file: Vec<u8> = vec![10];
+        file.extend(b"name\x01test\x01check\x01\r\n\t\n\x01");
+        file.extend([2, 10]);
+        file.extend(gmod_lzma::compress(&data, 9).expect("Unable to compress"));
+
+        let result = AdCodec5::default().decode(&mut Trickle(&file))?;
+        assert_eq!(result.info().player_name(), "test");
+
+        let table = result.value().as_table().expect("Value is not a table");
+        assert_eq!(table[&lua_string!("a")], table[&lua_string!("c")]);
+
+        // The data block parses from any reader that can seek back to a referenced table
+        let mut reader = std::io::BufReader::with_capacity(4, Cursor::new(data));
+        let value = *AdCodec5::decode_stream(&mut reader)?.expect("Unable to decode stream");
+        assert_eq!(&value, result.value());
+
+        // { a = { x = true }, b = <long string>, c = <reference to a> }, where the reference is
+        // decompressed long after the table it refers to
+        let mut data: Vec<u8> = vec![255, 1, b'a', 255, 1, b'x', 253, 246, 1, b'b', 248];
+        data.extend(200_000u32.to_le_bytes());
+        data.extend(std::iter::repeat_n(b'z', 200_000));
+        data.extend([1, b'c', 247, 2, 0, 246]);
+
+        let mut file: Vec<u8> = vec![10];
+        file.extend(b"check\x01\r\n\t\n\x01");
+        file.extend([2, 10]);
+        file.extend(gmod_lzma::compress(&data, 9).expect("Unable to compress"));
+
+        let result = AdCodec5::default().decode(&mut Trickle(&file))?;
+        let table = result.value().as_table().expect("Value is not a table");
+        assert_eq!(table[&lua_string!("a")], table[&lua_string!("c")]);
+
+        // LZMA data that ends early fails as it is decompressed
+        let error = AdCodec5::default()
+            .decode(&mut Trickle(&file[..file.len() - 20]))
+            .err()
+            .expect("LZMA data should be truncated");
+        assert!(matches!(error, CodecError::Lzma { offset: 19, .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_at() {
+        let data: Vec<u8> = vec![
+            255, 8, b'E', b'n', b't', b'i', b't', b'i', b'e', b's', 255, 251, 0, 0, 0, 0, 0, 0,
+            240, 63, 3, b'P', b'o', b's', 246, 246,
+        ];
+
+        assert_eq!(AdCodec5::path_at(&data, 0, true), "");
+        assert_eq!(AdCodec5::path_at(&data, 21, true), "Entities.1");
+        assert_eq!(
+            AdCodec5::data_block_offset(b"AD2F\x05\nkey\x01v\x01\x02\nXZ"),
+            Some(14)
+        );
+        assert_eq!(AdCodec5::data_block_offset(b"AD2F\x05\nkey\x01v\x01"), None);
+    }
 }
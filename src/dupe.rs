@@ -0,0 +1,662 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::ErrorKind;
+
+use indexmap::IndexMap;
+
+use crate::codec::{LuaTable, LuaValue};
+use crate::{lua_array, lua_string, lua_table};
+
+/// A position as its x, y and z coordinates.
+pub type Vec3 = [f64; 3];
+
+/// The index of an entity, the key it is stored under in the `Entities` table.
+pub type EntityIndex = u32;
+
+/// The entities of a dupe by their index, in the order they were read.
+pub type Entities = IndexMap<EntityIndex, Entity>;
+
+/// A typed view of the dupe data, the table held by a `DuplicationResult`.
+///
+/// Every struct of the model reads the fields it knows out of its Lua table and keeps the rest
+/// in `other`. A field is only read when it holds the expected type, otherwise it stays in
+/// `other` too. Writing a struct back puts every key where it was in the table it was read
+/// from, with new keys after them, so an unmodified dupe gives back the exact same value.
+///
+/// # Example
+///
+/// ```
+/// use adv2_splitter::codec::{LuaTable, LuaValue};
+/// use adv2_splitter::dupe::Dupe;
+/// use adv2_splitter::{lua_string, lua_table};
+///
+/// let mut entity = LuaTable::new();
+/// entity.insert(lua_string!("Class"), lua_string!("prop_physics"));
+/// entity.insert(lua_string!("Pos"), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+///
+/// let mut entities = LuaTable::new();
+/// entities.insert(LuaValue::Double(1.0), lua_table!(entity));
+///
+/// let mut table = LuaTable::new();
+/// table.insert(lua_string!("Entities"), lua_table!(entities));
+/// let value = lua_table!(table);
+///
+/// let mut dupe = Dupe::from_lua(&value).unwrap();
+/// assert_eq!(dupe.entities[&1].class.as_deref(), Some("prop_physics"));
+/// assert_eq!(dupe.to_lua(), value);
+///
+/// dupe.entities[&1].translate([1.0, 2.0, 3.0]);
+/// assert_eq!(dupe.entities[&1].pos, Some([0.0; 3]));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dupe {
+    pub entities: Entities,
+    pub constraints: Vec<Constraint>,
+    pub head_ent: Option<HeadEnt>,
+    pub other: LuaTable,
+    keys: Vec<LuaValue>,
+    constraints_form: ListForm,
+}
+
+/// An entity, an entry of the `Entities` table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entity {
+    pub class: Option<String>,
+    pub model: Option<String>,
+    pub pos: Option<Vec3>,
+    pub angle: Option<Vec3>,
+    pub physics_objects: Vec<PhysicsObject>,
+    pub entity_mods: Option<LuaTable>,
+    pub bone_mods: Option<LuaTable>,
+    pub other: LuaTable,
+    keys: Vec<LuaValue>,
+    physics_objects_form: ListForm,
+}
+
+/// A physics object of an entity, an entry of its `PhysicsObjects` table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PhysicsObject {
+    pub pos: Option<Vec3>,
+    pub angle: Option<Vec3>,
+    pub frozen: Option<bool>,
+    pub other: LuaTable,
+    keys: Vec<LuaValue>,
+}
+
+/// A constraint, an entry of the `Constraints` table.
+///
+/// The entities a constraint is attached to are stored both under `Ent1`, `Ent2`, ... keys,
+/// read into `ents`, and as the `Index` of every entry in its `Entity` table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Constraint {
+    pub kind: Option<String>,
+    pub ents: IndexMap<String, EntityIndex>,
+    pub entities: Vec<ConstraintEntity>,
+    pub other: LuaTable,
+    keys: Vec<LuaValue>,
+    entities_form: ListForm,
+}
+
+/// An entity a constraint is attached to, an entry of the constraint's `Entity` table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConstraintEntity {
+    pub index: Option<EntityIndex>,
+    pub other: LuaTable,
+    keys: Vec<LuaValue>,
+}
+
+/// The `HeadEnt` table, the entity the dupe is placed by and where it was.
+///
+/// Entity positions are relative to `pos`, the world position of the head entity, and `z` is
+/// its height above the ground.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeadEnt {
+    pub index: Option<EntityIndex>,
+    pub pos: Option<Vec3>,
+    pub z: Option<f64>,
+    pub other: LuaTable,
+    keys: Vec<LuaValue>,
+}
+
+/// How a list of the model was stored, so that it is written back the same way.
+#[derive(Clone, Debug, Default, PartialEq)]
+enum ListForm {
+    /// The key was not in the table.
+    #[default]
+    Missing,
+    Array,
+    /// A table, with the keys of the entries in order.
+    Table(Vec<LuaValue>),
+}
+
+impl Dupe {
+    /// Reads the typed view of a dupe out of its data.
+    ///
+    /// # Returns
+    ///
+    /// The dupe, or an `InvalidData` error when `value` is not a table with an `Entities`
+    /// table of entity tables keyed by their index.
+    pub fn from_lua(value: &LuaValue) -> Result<Dupe, Box<dyn Error>> {
+        let table = value
+            .as_table()
+            .ok_or_else(|| invalid("the dupe data is not a table"))?;
+        let mut fields = Fields::new(table);
+
+        let entities = fields
+            .take("Entities", |value| Some(value.clone()))
+            .ok_or_else(|| invalid("the dupe has no Entities table"))?;
+        let entities = entities
+            .as_table()
+            .ok_or_else(|| invalid("Entities is not a table"))?
+            .iter()
+            .map(|(key, entity)| {
+                let index = entity_index(key)
+                    .ok_or_else(|| invalid(&format!("{:?} is not an entity index", key)))?;
+                let entity = Entity::from_lua(entity)
+                    .ok_or_else(|| invalid(&format!("entity {} is not a table", index)))?;
+
+                Ok((index, entity))
+            })
+            .collect::<Result<Entities, Box<dyn Error>>>()?;
+
+        let (constraints, constraints_form) = fields.take_list("Constraints", Constraint::from_lua);
+        let head_ent = fields.take("HeadEnt", HeadEnt::from_lua);
+
+        Ok(Dupe {
+            entities,
+            constraints,
+            head_ent,
+            keys: fields.keys,
+            other: fields.other,
+            constraints_form,
+        })
+    }
+
+    /// Writes the dupe back into the data of a dupe.
+    pub fn to_lua(&self) -> LuaValue {
+        let entities = self
+            .entities
+            .iter()
+            .map(|(index, entity)| (index_value(*index), entity.to_lua()))
+            .collect();
+
+        let mut known = LuaTable::new();
+        known.insert(lua_string!("Entities"), lua_table!(entities));
+        insert_list(
+            &mut known,
+            "Constraints",
+            &self.constraints,
+            &self.constraints_form,
+            Constraint::to_lua,
+        );
+        if let Some(head_ent) = &self.head_ent {
+            known.insert(lua_string!("HeadEnt"), head_ent.to_lua());
+        }
+
+        lua_table!(assemble(&self.keys, known, &self.other))
+    }
+}
+
+impl Entity {
+    /// Reads an entity out of its table, or returns `None` if `value` is not a table.
+    pub fn from_lua(value: &LuaValue) -> Option<Entity> {
+        let mut fields = Fields::new(value.as_table()?);
+
+        let (physics_objects, physics_objects_form) =
+            fields.take_list("PhysicsObjects", PhysicsObject::from_lua);
+
+        Some(Entity {
+            class: fields.take("Class", string),
+            model: fields.take("Model", string),
+            pos: fields.take("Pos", vector),
+            angle: fields.take("Angle", angle),
+            physics_objects,
+            entity_mods: fields.take("EntityMods", table),
+            bone_mods: fields.take("BoneMods", table),
+            keys: fields.keys,
+            other: fields.other,
+            physics_objects_form,
+        })
+    }
+
+    pub fn to_lua(&self) -> LuaValue {
+        let mut known = LuaTable::new();
+        insert_string(&mut known, "Class", &self.class);
+        insert_string(&mut known, "Model", &self.model);
+        insert_vector(&mut known, "Pos", self.pos);
+        if let Some(angle) = self.angle {
+            known.insert(lua_string!("Angle"), LuaValue::Angle(angle.to_vec()));
+        }
+        insert_list(
+            &mut known,
+            "PhysicsObjects",
+            &self.physics_objects,
+            &self.physics_objects_form,
+            PhysicsObject::to_lua,
+        );
+        if let Some(entity_mods) = &self.entity_mods {
+            known.insert(lua_string!("EntityMods"), lua_table!(entity_mods.clone()));
+        }
+        if let Some(bone_mods) = &self.bone_mods {
+            known.insert(lua_string!("BoneMods"), lua_table!(bone_mods.clone()));
+        }
+
+        lua_table!(assemble(&self.keys, known, &self.other))
+    }
+
+    /// Moves the `pos` of the entity, and of each of its physics objects, back by `offset`.
+    pub fn translate(&mut self, offset: Vec3) {
+        let positions = std::iter::once(&mut self.pos).chain(
+            self.physics_objects
+                .iter_mut()
+                .map(|object| &mut object.pos),
+        );
+
+        for position in positions.flatten() {
+            for (value, offset) in position.iter_mut().zip(offset) {
+                *value -= offset;
+            }
+        }
+    }
+}
+
+impl PhysicsObject {
+    /// Reads a physics object out of its table, or returns `None` if `value` is not a table.
+    pub fn from_lua(value: &LuaValue) -> Option<PhysicsObject> {
+        let mut fields = Fields::new(value.as_table()?);
+
+        Some(PhysicsObject {
+            pos: fields.take("Pos", vector),
+            angle: fields.take("Angle", angle),
+            frozen: fields.take("Frozen", LuaValue::as_bool),
+            keys: fields.keys,
+            other: fields.other,
+        })
+    }
+
+    pub fn to_lua(&self) -> LuaValue {
+        let mut known = LuaTable::new();
+        insert_vector(&mut known, "Pos", self.pos);
+        if let Some(angle) = self.angle {
+            known.insert(lua_string!("Angle"), LuaValue::Angle(angle.to_vec()));
+        }
+        if let Some(frozen) = self.frozen {
+            known.insert(lua_string!("Frozen"), LuaValue::Bool(frozen));
+        }
+
+        lua_table!(assemble(&self.keys, known, &self.other))
+    }
+}
+
+impl Constraint {
+    /// Reads a constraint out of its table, or returns `None` if `value` is not a table.
+    pub fn from_lua(value: &LuaValue) -> Option<Constraint> {
+        let mut fields = Fields::new(value.as_table()?);
+
+        let ent_keys: Vec<String> = fields
+            .other
+            .keys()
+            .filter_map(LuaValue::as_str)
+            .filter(|key| {
+                key.strip_prefix("Ent")
+                    .is_some_and(|number| number.parse::<u32>().is_ok())
+            })
+            .map(String::from)
+            .collect();
+
+        let mut ents = IndexMap::new();
+        for key in ent_keys {
+            if let Some(index) = fields.take(&key, entity_index) {
+                ents.insert(key, index);
+            }
+        }
+
+        let (entities, entities_form) = fields.take_list("Entity", ConstraintEntity::from_lua);
+
+        Some(Constraint {
+            kind: fields.take("Type", string),
+            ents,
+            entities,
+            keys: fields.keys,
+            other: fields.other,
+            entities_form,
+        })
+    }
+
+    pub fn to_lua(&self) -> LuaValue {
+        let mut known = LuaTable::new();
+        insert_string(&mut known, "Type", &self.kind);
+        for (key, index) in &self.ents {
+            known.insert(lua_string!(key), index_value(*index));
+        }
+        insert_list(
+            &mut known,
+            "Entity",
+            &self.entities,
+            &self.entities_form,
+            ConstraintEntity::to_lua,
+        );
+
+        lua_table!(assemble(&self.keys, known, &self.other))
+    }
+
+    /// Returns the indices of the entities the constraint is attached to, which can include
+    /// entities that are not part of the dupe, such as the world.
+    pub fn attached(&self) -> Vec<EntityIndex> {
+        self.ents
+            .values()
+            .copied()
+            .chain(self.entities.iter().filter_map(|entity| entity.index))
+            .collect()
+    }
+
+    /// Rewrites the entity indices the constraint is attached to through `mapping`. Indices
+    /// that are not in `mapping`, such as the world, are left as they are.
+    pub fn remap(&mut self, mapping: &HashMap<EntityIndex, EntityIndex>) {
+        let indices = self.ents.values_mut().chain(
+            self.entities
+                .iter_mut()
+                .filter_map(|entity| entity.index.as_mut()),
+        );
+
+        for index in indices {
+            if let Some(new_index) = mapping.get(index) {
+                *index = *new_index;
+            }
+        }
+    }
+}
+
+impl ConstraintEntity {
+    /// Reads an attached entity out of its table, or returns `None` if `value` is not a table.
+    pub fn from_lua(value: &LuaValue) -> Option<ConstraintEntity> {
+        let mut fields = Fields::new(value.as_table()?);
+
+        Some(ConstraintEntity {
+            index: fields.take("Index", entity_index),
+            keys: fields.keys,
+            other: fields.other,
+        })
+    }
+
+    pub fn to_lua(&self) -> LuaValue {
+        let mut known = LuaTable::new();
+        if let Some(index) = self.index {
+            known.insert(lua_string!("Index"), index_value(index));
+        }
+
+        lua_table!(assemble(&self.keys, known, &self.other))
+    }
+}
+
+impl HeadEnt {
+    /// Reads the head entity out of its table, or returns `None` if `value` is not a table.
+    pub fn from_lua(value: &LuaValue) -> Option<HeadEnt> {
+        let mut fields = Fields::new(value.as_table()?);
+
+        Some(HeadEnt {
+            index: fields.take("Index", entity_index),
+            pos: fields.take("Pos", vector),
+            z: fields.take("Z", LuaValue::as_double),
+            keys: fields.keys,
+            other: fields.other,
+        })
+    }
+
+    pub fn to_lua(&self) -> LuaValue {
+        let mut known = LuaTable::new();
+        if let Some(index) = self.index {
+            known.insert(lua_string!("Index"), index_value(index));
+        }
+        insert_vector(&mut known, "Pos", self.pos);
+        if let Some(z) = self.z {
+            known.insert(lua_string!("Z"), LuaValue::Double(z));
+        }
+
+        lua_table!(assemble(&self.keys, known, &self.other))
+    }
+}
+
+/// The entries of a table that is being read into the model.
+struct Fields {
+    /// Every key of the table, in order.
+    keys: Vec<LuaValue>,
+    /// The entries that have not been read into a field.
+    other: LuaTable,
+}
+
+impl Fields {
+    fn new(table: &LuaTable) -> Self {
+        Fields {
+            keys: table.keys().cloned().collect(),
+            other: table.clone(),
+        }
+    }
+
+    /// Reads the value of `key` with `read`, taking it out of `other` only when it can be read.
+    fn take<T>(&mut self, key: &str, read: impl FnOnce(&LuaValue) -> Option<T>) -> Option<T> {
+        let key = lua_string!(key);
+        let value = read(self.other.get(&key)?)?;
+        self.other.shift_remove(&key);
+
+        Some(value)
+    }
+
+    /// Reads the array or table under `key` with `read` applied to every entry. When an entry
+    /// can not be read the whole list stays in `other`.
+    fn take_list<T>(
+        &mut self,
+        key: &str,
+        read: impl Fn(&LuaValue) -> Option<T>,
+    ) -> (Vec<T>, ListForm) {
+        let list = self.take(key, |value| match value {
+            LuaValue::Array(array) => {
+                let items = array.iter().map(&read).collect::<Option<Vec<T>>>()?;
+                Some((items, ListForm::Array))
+            }
+            LuaValue::Table(table) => {
+                let items = table.values().map(&read).collect::<Option<Vec<T>>>()?;
+                Some((items, ListForm::Table(table.keys().cloned().collect())))
+            }
+            _ => None,
+        });
+
+        list.unwrap_or_default()
+    }
+}
+
+/// Builds a table out of the `known` fields and the `other` entries. The keys that were in the
+/// table the fields were read from, `keys`, keep their place, new keys follow in order.
+fn assemble(keys: &[LuaValue], mut known: LuaTable, other: &LuaTable) -> LuaTable {
+    let mut table = LuaTable::new();
+
+    for key in keys {
+        if let Some(value) = known.shift_remove(key).or_else(|| other.get(key).cloned()) {
+            table.insert(key.clone(), value);
+        }
+    }
+
+    for (key, value) in known.into_iter().chain(other.clone()) {
+        table.entry(key).or_insert(value);
+    }
+
+    table
+}
+
+/// Inserts `items` under `key` the way the list was read.
+///
+/// A list read from a table keeps its keys as long as it has as many entries. Otherwise a list
+/// that started at 0 is numbered from 0 again and any other list is written as an array. A list
+/// that was not in the table is only written when it has entries.
+fn insert_list<T>(
+    known: &mut LuaTable,
+    key: &str,
+    items: &[T],
+    form: &ListForm,
+    write: impl Fn(&T) -> LuaValue,
+) {
+    let value = match form {
+        ListForm::Missing if items.is_empty() => return,
+        ListForm::Table(keys) if keys.len() == items.len() => {
+            lua_table!(keys.iter().cloned().zip(items.iter().map(write)).collect())
+        }
+        ListForm::Table(keys) if keys.first() == Some(&LuaValue::Double(0.0)) => {
+            lua_table!(items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (LuaValue::Double(i as f64), write(item)))
+                .collect())
+        }
+        _ => lua_array!(items.iter().map(write).collect()),
+    };
+
+    known.insert(lua_string!(key), value);
+}
+
+fn insert_string(known: &mut LuaTable, key: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        known.insert(lua_string!(key), LuaValue::String(value.clone()));
+    }
+}
+
+fn insert_vector(known: &mut LuaTable, key: &str, value: Option<Vec3>) {
+    if let Some(value) = value {
+        known.insert(lua_string!(key), LuaValue::Vector(value.to_vec()));
+    }
+}
+
+fn string(value: &LuaValue) -> Option<String> {
+    value.as_str().map(String::from)
+}
+
+fn table(value: &LuaValue) -> Option<LuaTable> {
+    value.as_table().cloned()
+}
+
+fn vector(value: &LuaValue) -> Option<Vec3> {
+    match value.as_vector()?.as_slice() {
+        [x, y, z] => Some([*x, *y, *z]),
+        _ => None,
+    }
+}
+
+fn angle(value: &LuaValue) -> Option<Vec3> {
+    match value.as_angle()?.as_slice() {
+        [pitch, yaw, roll] => Some([*pitch, *yaw, *roll]),
+        _ => None,
+    }
+}
+
+/// Reads an entity index, a double holding a whole number that is written back as the same bits.
+fn entity_index(value: &LuaValue) -> Option<EntityIndex> {
+    let double = value.as_double()?;
+    let index = double as EntityIndex;
+
+    (double.to_bits() == (index as f64).to_bits()).then_some(index)
+}
+
+fn index_value(index: EntityIndex) -> LuaValue {
+    LuaValue::Double(index as f64)
+}
+
+fn invalid(message: &str) -> Box<dyn Error> {
+    Box::new(std::io::Error::new(ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::{LuaTable, LuaValue};
+    use crate::{lua_array, lua_string, lua_table};
+
+    use super::Dupe;
+
+    #[test]
+    fn test_dupe_round_trip() {
+        let mut physics_object = LuaTable::new();
+        physics_object.insert(lua_string!("Frozen"), LuaValue::Bool(true));
+        physics_object.insert(lua_string!("Pos"), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+
+        let mut entity = LuaTable::new();
+        entity.insert(lua_string!("Skin"), LuaValue::Double(2.0));
+        entity.insert(lua_string!("Pos"), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+        entity.insert(lua_string!("Class"), lua_string!("prop_physics"));
+        // Not an angle, so it is kept as it is
+        entity.insert(lua_string!("Angle"), lua_string!("0 90 0"));
+        entity.insert(
+            lua_string!("PhysicsObjects"),
+            lua_table!(LuaTable::from([(
+                LuaValue::Double(0.0),
+                lua_table!(physics_object)
+            )])),
+        );
+
+        let mut attached = LuaTable::new();
+        attached.insert(lua_string!("World"), LuaValue::Bool(false));
+        attached.insert(lua_string!("Index"), LuaValue::Double(7.0));
+
+        let mut constraint = LuaTable::new();
+        constraint.insert(lua_string!("Ent1"), LuaValue::Double(7.0));
+        constraint.insert(lua_string!("Type"), lua_string!("Weld"));
+        constraint.insert(
+            lua_string!("Entity"),
+            lua_array!(vec![lua_table!(attached)]),
+        );
+
+        let mut entities = LuaTable::new();
+        entities.insert(LuaValue::Double(7.0), lua_table!(entity));
+        entities.insert(LuaValue::Double(3.0), lua_table!(LuaTable::new()));
+
+        let mut value = LuaTable::new();
+        value.insert(lua_string!("Description"), lua_string!("test"));
+        value.insert(
+            lua_string!("Constraints"),
+            lua_array!(vec![lua_table!(constraint)]),
+        );
+        value.insert(lua_string!("Entities"), lua_table!(entities));
+        let value = lua_table!(value);
+
+        let dupe = Dupe::from_lua(&value).unwrap();
+        assert_eq!(
+            dupe.entities.keys().copied().collect::<Vec<_>>(),
+            vec![7, 3]
+        );
+
+        let entity = &dupe.entities[&7];
+        assert_eq!(entity.class.as_deref(), Some("prop_physics"));
+        assert_eq!(entity.angle, None);
+        assert_eq!(entity.physics_objects[0].frozen, Some(true));
+        assert!(entity.other.contains_key(&lua_string!("Angle")));
+        assert_eq!(dupe.constraints[0].attached(), vec![7, 7]);
+
+        // Tables compare without their order, so the debug output is compared to check it
+        assert_eq!(format!("{:?}", dupe.to_lua()), format!("{:?}", value));
+
+        let mut value = value.as_table().unwrap().clone();
+        value.insert(lua_string!("Entities"), lua_string!("none"));
+        assert!(Dupe::from_lua(&lua_table!(value)).is_err());
+    }
+
+    #[test]
+    fn test_constraint_remap() {
+        let mut attached = LuaTable::new();
+        attached.insert(lua_string!("Index"), LuaValue::Double(2.0));
+
+        let mut constraint = LuaTable::new();
+        constraint.insert(lua_string!("Ent1"), LuaValue::Double(2.0));
+        constraint.insert(lua_string!("Ent2"), LuaValue::Double(0.0));
+        constraint.insert(
+            lua_string!("Entity"),
+            lua_array!(vec![lua_table!(attached)]),
+        );
+
+        let mut constraint = super::Constraint::from_lua(&lua_table!(constraint)).unwrap();
+        assert_eq!(constraint.attached(), vec![2, 0, 2]);
+
+        constraint.remap(&[(2, 5)].into());
+        assert_eq!(constraint.attached(), vec![5, 0, 5]);
+        assert_eq!(
+            constraint.to_lua().as_table().unwrap()[&lua_string!("Ent1")],
+            LuaValue::Double(5.0)
+        );
+    }
+}
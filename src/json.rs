@@ -0,0 +1,354 @@
+use std::error::Error;
+use std::io::ErrorKind;
+
+use serde_json::{json, Map, Value};
+
+use crate::codec::{DuplicationInfo, DuplicationResult, LuaTable, LuaValue};
+
+/// Converts a decoded dupe into a JSON document that can be converted back without losing
+/// anything.
+///
+/// The document is an object with the info block as an `info` array of `[key, value]` pairs,
+/// kept in order, and the dupe data as `value`. Lua values are mapped as follows:
+///
+/// * Strings, bools and arrays become their JSON counterparts.
+/// * Doubles become numbers, which are written with enough digits to read back the exact same
+///   bits. NaN and infinities have no JSON number, so they are written as
+///   `{"double": "0x7ff8000000000000"}` with the bits in hexadecimal.
+/// * Vectors and angles become `{"vector": [x, y, z]}` and `{"angle": [p, y, r]}`.
+/// * Tables become `{"table": [[key, value], ...]}` so keys of any type are kept.
+///
+/// # Arguments
+///
+/// * `result` - The decoded dupe to convert.
+///
+/// # Returns
+///
+/// The JSON document for the dupe.
+pub fn to_json(result: &DuplicationResult) -> Value {
+    let info: Vec<Value> = result
+        .info()
+        .values()
+        .map(|(key, value)| json!([key, value]))
+        .collect();
+
+    json!({
+        "info": info,
+        "value": value_to_json(result.value()),
+    })
+}
+
+/// Converts a JSON document written by `to_json` back into a dupe.
+///
+/// # Arguments
+///
+/// * `document` - The JSON document to convert.
+///
+/// # Returns
+///
+/// The dupe, or an `InvalidData` error naming the path of the first value that does not
+/// follow the format of `to_json`, including a key repeated in a table. A key repeated in the
+/// info block is kept in order, like the info block of a file can repeat one.
+pub fn from_json(document: &Value) -> Result<DuplicationResult, Box<dyn Error>> {
+    let mut path = vec![String::from("info")];
+
+    let entries = document
+        .get("info")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid(&path, "expected an array of [key, value] pairs"))?;
+
+    let mut info = DuplicationInfo::default();
+    for (i, entry) in entries.iter().enumerate() {
+        path.push(i.to_string());
+
+        let (key, value) = match entry.as_array().map(Vec::as_slice) {
+            Some([Value::String(key), Value::String(value)]) => (key, value),
+            _ => return Err(invalid(&path, "expected a [key, value] pair of strings")),
+        };
+        info.push_value(key, value.clone());
+
+        path.pop();
+    }
+
+    let mut path = vec![String::from("value")];
+    let value = document
+        .get("value")
+        .ok_or_else(|| invalid(&path, "missing the dupe data"))?;
+    let value = json_to_value(value, &mut path)?;
+
+    Ok(DuplicationResult::new(info, value))
+}
+
+fn value_to_json(value: &LuaValue) -> Value {
+    match value {
+        LuaValue::String(string) => Value::String(string.clone()),
+        LuaValue::Bool(bool) => Value::Bool(*bool),
+        LuaValue::Double(double) => double_to_json(*double),
+        LuaValue::Vector(vector) => json!({ "vector": doubles_to_json(vector) }),
+        LuaValue::Angle(angle) => json!({ "angle": doubles_to_json(angle) }),
+        LuaValue::Array(array) => Value::Array(array.iter().map(value_to_json).collect()),
+        LuaValue::Table(table) => {
+            let entries: Vec<Value> = table
+                .iter()
+                .map(|(key, value)| json!([value_to_json(key), value_to_json(value)]))
+                .collect();
+
+            json!({ "table": entries })
+        }
+    }
+}
+
+fn doubles_to_json(doubles: &[f64]) -> Value {
+    Value::Array(
+        doubles
+            .iter()
+            .map(|double| double_to_json(*double))
+            .collect(),
+    )
+}
+
+fn double_to_json(double: f64) -> Value {
+    match serde_json::Number::from_f64(double) {
+        Some(number) => Value::Number(number),
+        None => json!({ "double": format!("{:#018x}", double.to_bits()) }),
+    }
+}
+
+fn json_to_value(value: &Value, path: &mut Vec<String>) -> Result<LuaValue, Box<dyn Error>> {
+    let value = match value {
+        Value::String(string) => LuaValue::String(string.clone()),
+        Value::Bool(bool) => LuaValue::Bool(*bool),
+        Value::Number(_) => LuaValue::Double(json_to_double(value, path)?),
+        Value::Array(array) => {
+            let mut values = Vec::with_capacity(array.len());
+            for (i, value) in array.iter().enumerate() {
+                path.push(i.to_string());
+                values.push(json_to_value(value, path)?);
+                path.pop();
+            }
+
+            LuaValue::Array(values)
+        }
+        Value::Object(object) => tagged_to_value(object, path)?,
+        Value::Null => return Err(invalid(path, "null has no Lua value")),
+    };
+
+    Ok(value)
+}
+
+/// Converts the single key objects used for the values that have no JSON counterpart.
+fn tagged_to_value(
+    object: &Map<String, Value>,
+    path: &mut Vec<String>,
+) -> Result<LuaValue, Box<dyn Error>> {
+    let (tag, value) = match object.iter().next() {
+        Some(entry) if object.len() == 1 => entry,
+        _ => return Err(invalid(path, "expected an object with a single type key")),
+    };
+
+    path.push(tag.clone());
+
+    let value = match tag.as_str() {
+        "double" => LuaValue::Double(bits_to_double(value, path)?),
+        "vector" => LuaValue::Vector(json_to_doubles(value, path)?),
+        "angle" => LuaValue::Angle(json_to_doubles(value, path)?),
+        "table" => {
+            let entries = value
+                .as_array()
+                .ok_or_else(|| invalid(path, "expected an array of [key, value] pairs"))?;
+
+            let mut table = LuaTable::with_capacity(entries.len());
+            for (i, entry) in entries.iter().enumerate() {
+                path.push(i.to_string());
+
+                let (key, value) = match entry.as_array().map(Vec::as_slice) {
+                    Some([key, value]) => (key, value),
+                    _ => return Err(invalid(path, "expected a [key, value] pair")),
+                };
+                let key = json_to_value(key, path)?;
+                if table.contains_key(&key) {
+                    return Err(invalid(path, "duplicate key"));
+                }
+                table.insert(key, json_to_value(value, path)?);
+
+                path.pop();
+            }
+
+            LuaValue::Table(table)
+        }
+        _ => return Err(invalid(path, "unknown type key")),
+    };
+
+    path.pop();
+
+    Ok(value)
+}
+
+fn json_to_doubles(value: &Value, path: &mut Vec<String>) -> Result<Vec<f64>, Box<dyn Error>> {
+    let components = match value.as_array() {
+        Some(components) if components.len() == 3 => components,
+        _ => return Err(invalid(path, "expected an array of 3 numbers")),
+    };
+
+    let mut doubles = Vec::with_capacity(3);
+    for (i, component) in components.iter().enumerate() {
+        path.push(i.to_string());
+        doubles.push(json_to_double(component, path)?);
+        path.pop();
+    }
+
+    Ok(doubles)
+}
+
+/// Reads a double written by `double_to_json`, either a number or the bits of a double that
+/// has no JSON number.
+fn json_to_double(value: &Value, path: &[String]) -> Result<f64, Box<dyn Error>> {
+    if let Some(double) = value.as_f64() {
+        return Ok(double);
+    }
+
+    match value.get("double") {
+        Some(bits) => bits_to_double(bits, path),
+        None => Err(invalid(path, "expected a number")),
+    }
+}
+
+/// Reads the hexadecimal bits of a double that has no JSON number.
+fn bits_to_double(bits: &Value, path: &[String]) -> Result<f64, Box<dyn Error>> {
+    bits.as_str()
+        .and_then(|bits| bits.strip_prefix("0x"))
+        .and_then(|bits| u64::from_str_radix(bits, 16).ok())
+        .map(f64::from_bits)
+        .ok_or_else(|| invalid(path, "expected the bits of a double as 0x followed by hex"))
+}
+
+fn invalid(path: &[String], reason: &str) -> Box<dyn Error> {
+    Box::new(std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("{} at {}", reason, path.join(".")),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use serde_json::json;
+
+    use crate::codec::{DuplicationInfo, DuplicationResult, LuaTable, LuaValue};
+    use crate::json::{from_json, json_to_double, to_json, value_to_json};
+    use crate::{lua_array, lua_string, lua_table};
+
+    #[test]
+    fn test_json_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut entity = LuaTable::new();
+        entity.insert(lua_string!("Pos"), LuaValue::Vector(vec![0.1, -0.0, 1e300]));
+        entity.insert(lua_string!("Angle"), LuaValue::Angle(vec![0.0, 90.0, 0.0]));
+        entity.insert(lua_string!("Frozen"), LuaValue::Bool(true));
+        entity.insert(lua_string!("Mass"), LuaValue::Double(f64::INFINITY));
+        entity.insert(LuaValue::Double(1.0), lua_array!(vec![lua_string!("a")]));
+
+        let mut entities = LuaTable::new();
+        entities.insert(LuaValue::Double(4.0), lua_table!(entity));
+        entities.insert(LuaValue::Bool(false), LuaValue::Double(f64::NEG_INFINITY));
+
+        let mut value = LuaTable::new();
+        value.insert(lua_string!("Entities"), lua_table!(entities));
+        let value = lua_table!(value);
+
+        let mut info = DuplicationInfo::default();
+        info.set_value("name", String::from("test"));
+        info.set_value("check", String::from("\r\n\t\n"));
+        let result = DuplicationResult::new(info, value);
+
+        // Go through text so the numbers are parsed back like any other document
+        let text = serde_json::to_string(&to_json(&result))?;
+        let decoded = from_json(&serde_json::from_str(&text)?)?;
+
+        assert_eq!(decoded.value(), result.value());
+
+        // Zero equals negative zero, so check the sign was kept separately
+        let entity = &decoded.value().as_table().unwrap()[&lua_string!("Entities")]
+            .as_table()
+            .unwrap()[&LuaValue::Double(4.0)];
+        let position = entity.as_table().unwrap()[&lua_string!("Pos")]
+            .as_vector()
+            .unwrap();
+        assert!(position[1].is_sign_negative());
+        assert_eq!(
+            decoded.info().values().collect::<Vec<_>>(),
+            vec![("name", "test"), ("check", "\r\n\t\n")]
+        );
+        assert_eq!(decoded.info().player_name(), "test");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut table = LuaTable::new();
+        table.insert(LuaValue::Double(1.0), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+        let result = DuplicationResult::new(DuplicationInfo::default(), lua_table!(table));
+
+        assert_eq!(
+            to_json(&result),
+            json!({
+                "info": [],
+                "value": { "table": [[1.0, { "vector": [1.0, 2.0, 3.0] }]] },
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_nan() -> Result<(), Box<dyn Error>> {
+        let nan = value_to_json(&LuaValue::Double(f64::NAN));
+        assert_eq!(nan, json!({ "double": "0x7ff8000000000000" }));
+        assert_eq!(json_to_double(&nan, &[])?.to_bits(), f64::NAN.to_bits());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let error = from_json(&json!({
+            "info": [],
+            "value": { "table": [["Entities", { "vector": [1.0, 2.0] }]] },
+        }))
+        .err()
+        .expect("Vector should be invalid");
+        assert_eq!(
+            error.to_string(),
+            "expected an array of 3 numbers at value.table.0.vector"
+        );
+
+        let error = from_json(&json!({ "info": [["name"]], "value": true }))
+            .err()
+            .expect("Info should be invalid");
+        assert_eq!(
+            error.to_string(),
+            "expected a [key, value] pair of strings at info.0"
+        );
+
+        // A repeated info key is kept, as the info block of a file can hold one
+        let document = json!({
+            "info": [["name", "a"], ["check", "\r\n\t\n"], ["name", "b"]],
+            "value": true,
+        });
+        let result = from_json(&document).unwrap();
+        assert_eq!(
+            result.info().values().collect::<Vec<_>>(),
+            vec![("name", "a"), ("check", "\r\n\t\n"), ("name", "b")]
+        );
+        assert_eq!(result.info().player_name(), "b");
+        assert_eq!(to_json(&result), document);
+
+        // Keys are compared as Lua values, 1 and 1.0 are the same key
+        let error = from_json(&json!({
+            "info": [],
+            "value": { "table": [[1, "a"], [1.0, "b"]] },
+        }))
+        .err()
+        .expect("Table key should be repeated");
+        assert_eq!(error.to_string(), "duplicate key at value.table.1");
+    }
+}
@@ -0,0 +1,48 @@
+//! Reads, writes, splits and merges Advanced Duplicator 2 dupe files.
+//!
+//! A dupe is decoded into a [`codec::DuplicationResult`], which holds the info block as a
+//! [`codec::DuplicationInfo`] and the dupe data as a tree of [`codec::LuaValue`]s. A
+//! [`dupe::Dupe`] gives a typed view of the entities and constraints in that tree, which the
+//! [`split`] functions work on, and any value can be encoded again with [`codec::AdCodec5`].
+//!
+//! # Example
+//!
+//! ```
+//! use adv2_splitter::codec::{AdCodec, AdCodec5, DuplicationInfo, LuaTable, LuaValue};
+//! use adv2_splitter::{lua_string, lua_table};
+//!
+//! let mut entities = LuaTable::new();
+//! entities.insert(LuaValue::Double(1.0), lua_table!(LuaTable::new()));
+//!
+//! let mut dupe = LuaTable::new();
+//! dupe.insert(lua_string!("Entities"), lua_table!(entities));
+//!
+//! let mut info = DuplicationInfo::default();
+//! info.set_value("check", String::from("\r\n\t\n"));
+//!
+//! let codec = AdCodec5::default();
+//! let mut file = Vec::new();
+//! codec.encode(&info, &lua_table!(dupe.clone()), &mut file).unwrap();
+//!
+//! // The decoder expects the signature and version to have been read already
+//! let result = codec.decode(&mut &file[5..]).unwrap();
+//! assert_eq!(result.value(), &lua_table!(dupe));
+//! ```
+
+/// A serde data format for the data block of version 5 dupes.
+pub mod adv2;
+/// Decoding and encoding whole dupe files of any supported version.
+pub mod advanced_dupe;
+/// The dupe file format and the `LuaValue` tree it holds.
+pub mod codec;
+/// A typed view of the entities and constraints of a dupe.
+pub mod dupe;
+/// Lossless conversion of dupes to and from JSON.
+pub mod json;
+/// Read-only values that borrow their strings from a decompressed data block.
+pub mod lua_ref;
+mod r#macro;
+/// Splitting a dupe into parts and merging dupes into one.
+pub mod split;
+/// Statistics about what a dupe is made of.
+pub mod stats;
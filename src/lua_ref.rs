@@ -0,0 +1,380 @@
+use std::io::Cursor;
+
+use crate::codec::{AdCodec5, CodecError, DecodeTarget, LuaTable, LuaValue};
+
+/// A `LuaValue` that borrows from the decompressed data block it was decoded from.
+///
+/// Strings are slices of the data block and vectors and angles are kept inline, so decoding
+/// only allocates for the entries of tables and arrays. Tables keep their entries in the order
+/// they were decoded, duplicate keys included, and lookups walk the entries.
+///
+/// # Example
+///
+/// ```
+/// use adv2_splitter::codec::{AdCodec, AdCodec5, DuplicationInfo, LuaTable, LuaValue};
+/// use adv2_splitter::lua_ref::LuaValueRef;
+/// use adv2_splitter::{lua_string, lua_table};
+///
+/// let mut entity = LuaTable::new();
+/// entity.insert(lua_string!("Class"), lua_string!("prop_physics"));
+///
+/// let mut info = DuplicationInfo::default();
+/// info.set_value("check", String::from("\r\n\t\n"));
+///
+/// let mut file = Vec::new();
+/// AdCodec5::default()
+///     .encode(&info, &lua_table!(entity.clone()), &mut file)
+///     .unwrap();
+///
+/// let data = AdCodec5::decompress_data_block(&file).unwrap();
+/// let value = LuaValueRef::decode(&data).unwrap();
+///
+/// assert_eq!(value.get("Class").and_then(LuaValueRef::as_str), Some("prop_physics"));
+/// assert_eq!(value.to_owned_value(), lua_table!(entity));
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub enum LuaValueRef<'a> {
+    String(&'a str),
+    Array(Vec<LuaValueRef<'a>>),
+    Table(Vec<(LuaValueRef<'a>, LuaValueRef<'a>)>),
+    Double(f64),
+    Vector([f64; 3]),
+    Angle([f64; 3]),
+    Bool(bool),
+}
+
+impl<'a> LuaValueRef<'a> {
+    /// Decodes a version 5 data block, as returned by `AdCodec5::decompress_data_block`,
+    /// without copying any of its strings.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same `CodecError` as `AdCodec5::decode` when the data block is truncated or
+    /// malformed.
+    pub fn decode(data: &'a [u8]) -> Result<LuaValueRef<'a>, CodecError> {
+        match AdCodec5::decode_stream_unlocated(&mut Cursor::new(data)) {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(CodecError::UnknownType {
+                tag: 246,
+                offset: 0,
+                path: String::new(),
+            }),
+            // Paths are only worked out once decoding failed, keeping track of them while
+            // decoding would allocate for every key
+            Err(error) => Err(error.locate(data)),
+        }
+    }
+
+    /// Returns a copy of this value that owns its data and can be modified or encoded.
+    pub fn to_owned_value(&self) -> LuaValue {
+        match self {
+            LuaValueRef::String(string) => LuaValue::String(string.to_string()),
+            LuaValueRef::Array(array) => {
+                LuaValue::Array(array.iter().map(LuaValueRef::to_owned_value).collect())
+            }
+            LuaValueRef::Table(table) => LuaValue::Table(
+                table
+                    .iter()
+                    .map(|(key, value)| (key.to_owned_value(), value.to_owned_value()))
+                    .collect::<LuaTable>(),
+            ),
+            LuaValueRef::Double(double) => LuaValue::Double(*double),
+            LuaValueRef::Vector(vector) => LuaValue::Vector(vector.to_vec()),
+            LuaValueRef::Angle(angle) => LuaValue::Angle(angle.to_vec()),
+            LuaValueRef::Bool(boolean) => LuaValue::Bool(*boolean),
+        }
+    }
+
+    /// Returns the name of the Lua type of the value
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValueRef::String(_) => "string",
+            LuaValueRef::Array(_) => "array",
+            LuaValueRef::Table(_) => "table",
+            LuaValueRef::Double(_) => "double",
+            LuaValueRef::Vector(_) => "vector",
+            LuaValueRef::Angle(_) => "angle",
+            LuaValueRef::Bool(_) => "bool",
+        }
+    }
+
+    /// Returns the value of the last entry with the string key `key`, if this is a table
+    pub fn get(&self, key: &str) -> Option<&LuaValueRef<'a>> {
+        self.as_table()?
+            .iter()
+            .rev()
+            .find(|(entry, _)| entry.as_str() == Some(key))
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value of the last entry with the number key `key`, if this is a table
+    pub fn get_index(&self, key: f64) -> Option<&LuaValueRef<'a>> {
+        self.as_table()?
+            .iter()
+            .rev()
+            .find(|(entry, _)| entry.as_double() == Some(key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            LuaValueRef::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[LuaValueRef<'a>]> {
+        match self {
+            LuaValueRef::Array(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&[(LuaValueRef<'a>, LuaValueRef<'a>)]> {
+        match self {
+            LuaValueRef::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+
+    pub fn as_double(&self) -> Option<f64> {
+        match self {
+            LuaValueRef::Double(double) => Some(*double),
+            _ => None,
+        }
+    }
+
+    pub fn as_vector(&self) -> Option<[f64; 3]> {
+        match self {
+            LuaValueRef::Vector(vector) => Some(*vector),
+            _ => None,
+        }
+    }
+
+    pub fn as_angle(&self) -> Option<[f64; 3]> {
+        match self {
+            LuaValueRef::Angle(angle) => Some(*angle),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            LuaValueRef::Bool(boolean) => Some(*boolean),
+            _ => None,
+        }
+    }
+}
+
+impl From<&LuaValueRef<'_>> for LuaValue {
+    fn from(value: &LuaValueRef<'_>) -> Self {
+        value.to_owned_value()
+    }
+}
+
+/// Strings are sliced out of the data block the cursor reads, rather than copied
+impl<'a> DecodeTarget<Cursor<&'a [u8]>> for LuaValueRef<'a> {
+    type Entries = Vec<(LuaValueRef<'a>, LuaValueRef<'a>)>;
+
+    fn read_string(
+        reader: &mut Cursor<&'a [u8]>,
+        length: usize,
+        offset: u64,
+        path: &[String],
+    ) -> Result<Self, CodecError> {
+        let data: &'a [u8] = reader.get_ref();
+        let start = reader.position() as usize;
+        let string = start
+            .checked_add(length)
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| CodecError::Truncated {
+                offset,
+                path: path.join("."),
+            })?;
+
+        reader.set_position((start + length) as u64);
+
+        std::str::from_utf8(string)
+            .map(LuaValueRef::String)
+            .map_err(|_| CodecError::InvalidUtf8 {
+                offset,
+                path: path.join("."),
+            })
+    }
+
+    fn insert(table: &mut Self::Entries, key: Self, value: Self) {
+        table.push((key, value));
+    }
+
+    fn table(table: Self::Entries) -> Self {
+        LuaValueRef::Table(table)
+    }
+
+    fn array(array: Vec<Self>) -> Self {
+        LuaValueRef::Array(array)
+    }
+
+    fn double(double: f64) -> Self {
+        LuaValueRef::Double(double)
+    }
+
+    fn vector(vector: [f64; 3]) -> Self {
+        LuaValueRef::Vector(vector)
+    }
+
+    fn angle(angle: [f64; 3]) -> Self {
+        LuaValueRef::Angle(angle)
+    }
+
+    fn bool(boolean: bool) -> Self {
+        LuaValueRef::Bool(boolean)
+    }
+
+    fn path_segment(&self) -> String {
+        match self {
+            LuaValueRef::String(string) => string.to_string(),
+            LuaValueRef::Double(double) => double.to_string(),
+            LuaValueRef::Bool(boolean) => boolean.to_string(),
+            other => format!("[{}]", other.type_name()),
+        }
+    }
+}
+
+impl CodecError {
+    /// Fills in the path of an error from the borrowed decode, which only records offsets
+    fn locate(self, data: &[u8]) -> CodecError {
+        let path = |offset: u64| AdCodec5::path_at(data, offset as usize, true);
+
+        match self {
+            CodecError::Truncated { offset, .. } => CodecError::Truncated {
+                offset,
+                path: path(offset),
+            },
+            CodecError::UnknownType { tag, offset, .. } => CodecError::UnknownType {
+                tag,
+                offset,
+                path: path(offset),
+            },
+            CodecError::InvalidUtf8 { offset, .. } => CodecError::InvalidUtf8 {
+                offset,
+                path: path(offset),
+            },
+            CodecError::InvalidReference { id, offset, .. } => CodecError::InvalidReference {
+                id,
+                offset,
+                path: path(offset),
+            },
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::io::Cursor;
+
+    use crate::codec::{AdCodec5, CodecError, LuaTable};
+    use crate::lua_ref::LuaValueRef;
+    use crate::{lua_angle, lua_array, lua_bool, lua_double, lua_string, lua_table, lua_vector};
+
+    #[test]
+    fn test_decode_matches_owned() -> Result<(), Box<dyn Error>> {
+        let mut physics_object = LuaTable::new();
+        physics_object.insert(lua_string!("Pos"), lua_vector!(vec![1.0, 2.0, 3.0]));
+        physics_object.insert(lua_string!("Angle"), lua_angle!(vec![0.0, 90.0, 0.0]));
+        physics_object.insert(lua_string!("Frozen"), lua_bool!(true));
+
+        let mut entity = LuaTable::new();
+        entity.insert(lua_string!("Class"), lua_string!("gmod_wire_expression2"));
+        entity.insert(lua_string!("Code"), lua_string!("@name test\n".repeat(40)));
+        entity.insert(lua_string!("Empty"), lua_string!(""));
+        entity.insert(
+            lua_string!("PhysicsObjects"),
+            lua_array!(vec![lua_table!(physics_object)]),
+        );
+
+        let mut table = LuaTable::new();
+        table.insert(lua_double!(1.0), lua_table!(entity));
+        let value = lua_table!(table);
+
+        let mut data = Vec::new();
+        AdCodec5::encode_stream(&value, &mut data)?;
+
+        let borrowed = LuaValueRef::decode(&data)?;
+        assert_eq!(borrowed.to_owned_value(), value);
+        assert_eq!(
+            format!("{:?}", borrowed.to_owned_value()),
+            format!("{:?}", value)
+        );
+
+        // Strings point into the data block rather than being copied out of it
+        let code = borrowed
+            .get_index(1.0)
+            .and_then(|entity| entity.get("Code"))
+            .and_then(LuaValueRef::as_str)
+            .expect("Code is not a string");
+        assert!(data.as_ptr_range().contains(&code.as_ptr()));
+
+        let owned = AdCodec5::decode_stream(&mut Cursor::new(data.as_slice()))?;
+        assert_eq!(owned.map(|value| *value), Some(value));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_table_reference() -> Result<(), Box<dyn Error>> {
+        // { a = { x = true }, b = { <reference to a> }, c = <reference to a> }
+        let data: Vec<u8> = vec![
+            255, 1, b'a', 255, 1, b'x', 253, 246, 1, b'b', 254, 247, 2, 0, 246, 1, b'c', 247, 2, 0,
+            246,
+        ];
+
+        let borrowed = LuaValueRef::decode(&data)?;
+        let shared = borrowed.get("a").expect("Missing a");
+        assert_eq!(borrowed.get("c"), Some(shared));
+        assert_eq!(
+            borrowed.get("b").and_then(LuaValueRef::as_array),
+            Some([shared.clone()].as_slice())
+        );
+
+        let owned = AdCodec5::decode_stream(&mut Cursor::new(data.as_slice()))?;
+        assert_eq!(owned.map(|value| *value), Some(borrowed.to_owned_value()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        // { Entities = { [1] = <truncated double> } }
+        let data: Vec<u8> = vec![
+            255, 8, b'E', b'n', b't', b'i', b't', b'i', b'e', b's', 255, 251, 0, 0, 0, 0, 0, 0,
+            240, 63, 251, 0, 0,
+        ];
+
+        match LuaValueRef::decode(&data) {
+            Err(error) => {
+                assert!(matches!(error, CodecError::Truncated { offset: 20, .. }));
+                assert_eq!(error.path(), Some("Entities.1"));
+            }
+            Ok(value) => panic!("Decoded a truncated stream: {:?}", value),
+        }
+
+        // A reference to a table that is still being decoded
+        let data: Vec<u8> = vec![255, 1, b'a', 247, 1, 0, 246];
+        assert!(matches!(
+            LuaValueRef::decode(&data),
+            Err(CodecError::InvalidReference {
+                id: 1,
+                offset: 3,
+                ..
+            })
+        ));
+
+        let data: Vec<u8> = vec![2, 0xff, 0xfe];
+        assert!(matches!(
+            LuaValueRef::decode(&data),
+            Err(CodecError::InvalidUtf8 { offset: 0, .. })
+        ));
+    }
+}
@@ -2,55 +2,58 @@
 ///
 /// # Example
 /// ```
+/// use adv2_splitter::codec::LuaValue;
+/// use adv2_splitter::lua_string;
+///
 /// let value = LuaValue::String(String::from("test"));
 /// assert_eq!(value, lua_string!("test"));
 /// ```
 #[macro_export]
 macro_rules! lua_string {
     ($x:expr) => {
-        LuaValue::String($x.to_string())
+        $crate::codec::LuaValue::String($x.to_string())
     };
 }
 
 #[macro_export]
 macro_rules! lua_array {
     ($x:expr) => {
-        LuaValue::Array($x)
+        $crate::codec::LuaValue::Array($x)
     };
 }
 
 #[macro_export]
 macro_rules! lua_table {
     ($x:expr) => {
-        LuaValue::Table($x)
+        $crate::codec::LuaValue::Table($x)
     };
 }
 
 #[macro_export]
 macro_rules! lua_vector {
     ($x:expr) => {
-        LuaValue::Vector($x)
+        $crate::codec::LuaValue::Vector($x)
     };
 }
 
 #[macro_export]
 macro_rules! lua_angle {
     ($x:expr) => {
-        LuaValue::Angle($x)
+        $crate::codec::LuaValue::Angle($x)
     };
 }
 
 #[macro_export]
 macro_rules! lua_bool {
     ($x:expr) => {
-        LuaValue::Bool($x)
+        $crate::codec::LuaValue::Bool($x)
     };
 }
 
 #[macro_export]
 macro_rules! lua_double {
     ($x:expr) => {
-        LuaValue::Double($x)
+        $crate::codec::LuaValue::Double($x)
     };
 }
 
@@ -58,7 +61,10 @@ macro_rules! lua_double {
 ///
 /// # Example
 /// ```
-/// let value = LuaValue::Double(3.14);
+/// use adv2_splitter::codec::LuaValue;
+/// use adv2_splitter::get_lua_value;
+///
+/// let value = LuaValue::Double(2.5);
 ///
 /// if let Some(double) = get_lua_value!(Double, value) {
 ///     println!("Value was a double!");
@@ -68,43 +74,43 @@ macro_rules! lua_double {
 macro_rules! get_lua_value {
     (String, $x: expr) => {
         match $x {
-            LuaValue::String(val) => Some(val),
+            $crate::codec::LuaValue::String(val) => Some(val),
             _ => None,
         }
     };
     (Array, $x: expr) => {
         match $x {
-            LuaValue::Array(val) => Some(val),
+            $crate::codec::LuaValue::Array(val) => Some(val),
             _ => None,
         }
     };
     (Table, $x: expr) => {
         match $x {
-            LuaValue::Table(val) => Some(val),
+            $crate::codec::LuaValue::Table(val) => Some(val),
             _ => None,
         }
     };
     (Double, $x: expr) => {
         match $x {
-            LuaValue::Double(val) => Some(val),
+            $crate::codec::LuaValue::Double(val) => Some(val),
             _ => None,
         }
     };
     (Vector, $x: expr) => {
         match $x {
-            LuaValue::Vector(val) => Some(val),
+            $crate::codec::LuaValue::Vector(val) => Some(val),
             _ => None,
         }
     };
     (Angle, $x: expr) => {
         match $x {
-            LuaValue::Angle(val) => Some(val),
+            $crate::codec::LuaValue::Angle(val) => Some(val),
             _ => None,
         }
     };
     (Bool, $x: expr) => {
         match $x {
-            LuaValue::Bool(val) => Some(val),
+            $crate::codec::LuaValue::Bool(val) => Some(val),
             _ => None,
         }
     };
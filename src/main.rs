@@ -1,44 +1,249 @@
 extern crate core;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufWriter, ErrorKind};
-use std::panic;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use crate::codec::LuaValue;
-
-mod advanced_dupe;
-mod codec;
-mod r#macro;
+use adv2_splitter::codec::{AdCodec5, EncodeOptions, MAX_COMPRESSION_LEVEL};
+use adv2_splitter::dupe::{Dupe, Vec3};
+use adv2_splitter::split::{
+    connected_groups, merge_dupes, split_connected, split_dupe, split_map, split_max_props,
+    split_spatial,
+};
+use adv2_splitter::stats::DupeStats;
+use adv2_splitter::{advanced_dupe, json};
+use rayon::prelude::*;
 
 const HELP: &str = "\
 Advanced Duplicator Splitter
 
 USAGE:
-    adv2-splitter [OPTIONS] [FILE] 
+    adv2-splitter <COMMAND> [OPTIONS]
+
+COMMANDS:
+    info        Prints the info block of dupes
+    print       Prints the info block and data of a dupe
+    split       Splits a dupe into parts
+    merge       Merges dupes into one
+    convert     Converts a dupe to JSON, the latest codec version or its raw data block, and back
+    validate    Checks that dupes decode with a strictly valid info block
+    verify      Checks that dupes are written back exactly as they were read
+    repair      Undoes the line ending translation of a text mode transfer
+    stats       Reports what dupes are made of and what takes up their size
 
 FLAGS:
-    -h, --help          Prints help information
-    -p, --print         Prints the deserialized dupe
+    -h, --help          Prints help information, or the help of a command after it
+
+Only split, merge, convert and repair write files.
+
+Every command but print takes any number of files. A directory stands for every .txt file under it
+and a quoted pattern such as \"data/*/car*.txt\" for every path it matches. The files are worked on
+across all cores, and a file that fails is reported without stopping the others.
+
+A file named - is read from stdin, and an --output of - writes to stdout.
+";
 
-OPTIONS
-    --size SIZE         Gives the split size
+const INFO_HELP: &str = "\
+Prints every key and value of the info block of each dupe.
 
-ARGS:
-    <FILE>
+USAGE:
+    adv2-splitter info [FILE]...
+";
+
+const PRINT_HELP: &str = "\
+Prints the info block and the whole data tree of a dupe.
+
+USAGE:
+    adv2-splitter print [FILE]
+";
+
+const SPLIT_HELP: &str = "\
+Splits each dupe into parts, written next to it as <FILE STEM>-<INDEX>.txt by default with parts
+numbered from 1. A manifest listing every part and its entity count is written beside them as
+<FILE STEM>-manifest.json.
+
+USAGE:
+    adv2-splitter split [OPTIONS] [FILE]...
+
+FLAGS:
+    --ungrouped         Splits entities in table order, even when constraints join them
+    --spatial           Splits the build into regions by entity position
+    --recenter          Moves every part so its head entity sits at the origin
+    --force             Overwrites parts and a manifest that already exist
+
+OPTIONS:
+    --size SIZE         Gives the number of parts
+    --max-props N       Splits into as many parts as needed to keep every part at N entities or less
+    --name NAME         Gives the name stamped into every part instead of the original one
+    --level LEVEL       Gives the LZMA compression level, from 0 (fastest) to 9 (smallest, default)
+    --out-dir DIR       Gives the directory to write the parts to, which is created if needed
+    --template TEMPLATE Gives the file name of every part, where {stem} is the stem of FILE, {index}
+                        the number of the part from 1, {count} the number of parts and {props} the
+                        number of entities in the part. Defaults to {stem}-{index}.txt
+";
+
+/// The file name that stands for stdin as an input and stdout as an output
+const STDIO: &str = "-";
+
+/// The file name of a split part without `--template`
+const DEFAULT_TEMPLATE: &str = "{stem}-{index}.txt";
+
+const MERGE_HELP: &str = "\
+Merges dupes into one, renumbering their entities.
+
+USAGE:
+    adv2-splitter merge [OPTIONS] --output OUTPUT [FILE]...
+
+OPTIONS:
+    -o, --output OUTPUT Gives the file to write the merged dupe to, or - for stdout
+    --offset X,Y,Z      Moves every file by this vector further than the file before it
+    --level LEVEL       Gives the LZMA compression level, from 0 (fastest) to 9 (smallest, default)
+";
+
+const CONVERT_HELP: &str = "\
+Converts between a dupe, its lossless JSON document and its raw uncompressed data block. The
+format of FILE is recognised from its contents. Converting a dupe to a dupe writes it with the
+latest codec version.
+
+USAGE:
+    adv2-splitter convert [OPTIONS] [FILE]...
+
+OPTIONS:
+    --to FORMAT         Gives the format to write: json, dupe or raw. Dupes are converted to json
+                        and anything else to dupe by default
+    -o, --output OUTPUT Gives the file to write to, by default the input name with a .json, .txt or
+                        .bin extension, or with -v5 added when upgrading a dupe. Give - to
+                        write to stdout, and give it at all when reading from stdin
+    --info DUPE         Gives the dupe whose info block is written with a raw data block
+    --level LEVEL       Gives the LZMA compression level, from 0 (fastest) to 9 (smallest, default)
+";
+
+const VALIDATE_HELP: &str = "\
+Decodes each dupe strictly, reporting a damaged info block such as one whose line endings were
+translated by a text mode transfer.
+
+USAGE:
+    adv2-splitter validate [FILE]...
+";
+
+const VERIFY_HELP: &str = "\
+Decodes each dupe, encodes it again and reports the first place the two differ.
+
+USAGE:
+    adv2-splitter verify [FILE]...
+";
+
+const REPAIR_HELP: &str = "\
+Undoes a text mode transfer that turned every LF of a dupe into CRLF.
+
+USAGE:
+    adv2-splitter repair [OPTIONS] [FILE]...
+
+OPTIONS:
+    -o, --output OUTPUT Gives the file to write to, by default the input name with -repaired added.
+                        Give - to write to stdout, and give it at all when reading from stdin
+";
+
+const STATS_HELP: &str = "\
+Reports the entities of each dupe by class and model, its constraints by type, its Wiremod
+entities, the box around its entity positions, the size of its data block and the tables of the
+data that take the most bytes.
+
+USAGE:
+    adv2-splitter stats [OPTIONS] [FILE]...
+
+FLAGS:
+    --json              Prints a JSON array with an object for every file instead of text
+
+OPTIONS:
+    --top N             Gives the number of largest tables to report, 10 by default
 ";
 
 #[derive(Debug)]
-struct AppArgs {
-    file: PathBuf,
+struct SplitArgs {
+    files: Vec<PathBuf>,
     size: Option<usize>,
-    print: bool,
+    max_props: Option<usize>,
+    ungrouped: bool,
+    spatial: bool,
+    recenter: bool,
+    level: Option<u32>,
+    name: Option<String>,
+    out_dir: Option<PathBuf>,
+    template: String,
+    force: bool,
+}
+
+#[derive(Debug)]
+struct MergeArgs {
+    files: Vec<PathBuf>,
+    output: PathBuf,
+    offset: Vec3,
+    level: Option<u32>,
+}
+
+#[derive(Debug)]
+struct ConvertArgs {
+    files: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    to: Option<Format>,
+    info: Option<PathBuf>,
+    level: Option<u32>,
+}
+
+#[derive(Debug)]
+struct RepairArgs {
+    files: Vec<PathBuf>,
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct StatsArgs {
+    files: Vec<PathBuf>,
+    json: bool,
+    top: usize,
+}
+
+/// A format that `convert` reads and writes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Dupe,
+    Raw,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(Format::Json),
+            "dupe" => Ok(Format::Dupe),
+            "raw" => Ok(Format::Raw),
+            _ => Err(format!("\"{}\" is not one of json, dupe or raw", value)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Info(Vec<PathBuf>),
+    Print(PathBuf),
+    Split(SplitArgs),
+    Merge(MergeArgs),
+    Convert(ConvertArgs),
+    Validate(Vec<PathBuf>),
+    Verify(Vec<PathBuf>),
+    Repair(RepairArgs),
+    Stats(StatsArgs),
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = match parse_args() {
+    let args = match parse_args(std::env::args_os().skip(1).collect()) {
         Ok(v) => v,
         Err(e) => {
             eprintln!("Error: {}.", e);
@@ -46,201 +251,1284 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     };
 
-    perform_split(&args)?;
+    match args {
+        Command::Info(files) => run_batch(&expand_inputs(&files)?, false, perform_info)?,
+        Command::Print(file) => perform_print(&file)?,
+        Command::Split(args) => {
+            let files = skip_split_parts(&args, expand_inputs(&args.files)?);
+            check_split_outputs(&args, &files)?;
+
+            // A part may not be written over a dupe that another worker is still reading
+            let inputs: HashSet<PathBuf> = files
+                .iter()
+                .filter_map(|file| file.canonicalize().ok())
+                .collect();
+            run_batch(&files, false, |file| perform_split(&args, file, &inputs))?
+        }
+        Command::Merge(args) => perform_merge(&args, &expand_inputs(&args.files)?)?,
+        Command::Convert(args) => {
+            let files = expand_inputs(&args.files)?;
+            check_single_output(&args.output, &files)?;
+            run_batch(&files, is_stdio(&args.output), |file| {
+                perform_convert(&args, file)
+            })?
+        }
+        Command::Validate(files) => run_batch(&expand_inputs(&files)?, false, perform_validate)?,
+        Command::Verify(files) => run_batch(&expand_inputs(&files)?, false, perform_verify)?,
+        Command::Repair(args) => {
+            let files = expand_inputs(&args.files)?;
+            check_single_output(&args.output, &files)?;
+            run_batch(&files, is_stdio(&args.output), |file| {
+                perform_repair(&args, file)
+            })?
+        }
+        Command::Stats(args) => {
+            let files = expand_inputs(&args.files)?;
+            if args.json {
+                print_stats_json(&args, &files)?
+            } else {
+                run_batch(&files, false, |file| perform_stats(&args, file))?
+            }
+        }
+    }
 
     Ok(())
 }
 
-fn parse_args() -> Result<AppArgs, pico_args::Error> {
-    let mut pargs = pico_args::Arguments::from_env();
+fn parse_args(mut args: Vec<OsString>) -> Result<Command, pico_args::Error> {
+    let subcommand = match args.first().and_then(|arg| arg.to_str()) {
+        None | Some("-h") | Some("--help") => {
+            print!("{}", HELP);
+            std::process::exit(0);
+        }
+        Some(subcommand) => subcommand.to_string(),
+    };
+    let mut pargs = pico_args::Arguments::from_vec(args.split_off(1));
+
+    let help = match subcommand.as_str() {
+        "info" => INFO_HELP,
+        "print" => PRINT_HELP,
+        "split" => SPLIT_HELP,
+        "merge" => MERGE_HELP,
+        "convert" => CONVERT_HELP,
+        "validate" => VALIDATE_HELP,
+        "verify" => VERIFY_HELP,
+        "repair" => REPAIR_HELP,
+        "stats" => STATS_HELP,
+        _ => {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: format!("unknown command \"{}\", see --help", subcommand),
+            })
+        }
+    };
 
     // Help has a higher priority and should be handled separately.
     if pargs.contains(["-h", "--help"]) {
-        print!("{}", HELP);
+        print!("{}", help);
         std::process::exit(0);
     }
 
-    let args = AppArgs {
-        file: pargs.free_from_str()?,
-        size: pargs.opt_value_from_str("--size")?,
-        print: pargs.contains(["-p", "--print"]),
+    let command = match subcommand.as_str() {
+        // Every remaining argument is a file to read
+        "info" => return Ok(Command::Info(parse_files(pargs)?)),
+        "print" => Command::Print(pargs.free_from_str()?),
+        "split" => Command::Split(SplitArgs {
+            files: Vec::new(),
+            size: pargs.opt_value_from_str("--size")?,
+            max_props: pargs.opt_value_from_str("--max-props")?,
+            ungrouped: pargs.contains("--ungrouped"),
+            spatial: pargs.contains("--spatial"),
+            recenter: pargs.contains("--recenter"),
+            level: pargs.opt_value_from_fn("--level", parse_level)?,
+            name: pargs.opt_value_from_str("--name")?,
+            out_dir: pargs.opt_value_from_str("--out-dir")?,
+            template: pargs
+                .opt_value_from_str("--template")?
+                .unwrap_or_else(|| String::from(DEFAULT_TEMPLATE)),
+            force: pargs.contains("--force"),
+        }),
+        "merge" => {
+            let output = pargs.value_from_str(["-o", "--output"])?;
+            let offset = pargs
+                .opt_value_from_fn("--offset", parse_vector)?
+                .unwrap_or([0.0; 3]);
+            let level = pargs.opt_value_from_fn("--level", parse_level)?;
+
+            // Every remaining argument is a file to merge
+            return Ok(Command::Merge(MergeArgs {
+                files: parse_files(pargs)?,
+                output,
+                offset,
+                level,
+            }));
+        }
+        "convert" => Command::Convert(ConvertArgs {
+            files: Vec::new(),
+            output: pargs.opt_value_from_str(["-o", "--output"])?,
+            to: pargs.opt_value_from_str("--to")?,
+            info: pargs.opt_value_from_str("--info")?,
+            level: pargs.opt_value_from_fn("--level", parse_level)?,
+        }),
+        "validate" => return Ok(Command::Validate(parse_files(pargs)?)),
+        "verify" => return Ok(Command::Verify(parse_files(pargs)?)),
+        "repair" => Command::Repair(RepairArgs {
+            files: Vec::new(),
+            output: pargs.opt_value_from_str(["-o", "--output"])?,
+        }),
+        _ => Command::Stats(StatsArgs {
+            files: Vec::new(),
+            json: pargs.contains("--json"),
+            top: pargs.opt_value_from_str("--top")?.unwrap_or(10),
+        }),
     };
 
-    // It's up to the caller what to do with the remaining arguments.
-    let remaining = pargs.finish();
-    if !remaining.is_empty() {
-        eprintln!("Warning: unused arguments left: {:?}.", remaining);
-    }
+    // Every remaining argument of a batch command is a file to read
+    let command = match command {
+        Command::Split(args) => Command::Split(SplitArgs {
+            files: parse_files(pargs)?,
+            ..args
+        }),
+        Command::Convert(args) => Command::Convert(ConvertArgs {
+            files: parse_files(pargs)?,
+            ..args
+        }),
+        Command::Repair(args) => Command::Repair(RepairArgs {
+            files: parse_files(pargs)?,
+            ..args
+        }),
+        Command::Stats(args) => Command::Stats(StatsArgs {
+            files: parse_files(pargs)?,
+            ..args
+        }),
+        command => {
+            // It's up to the caller what to do with the remaining arguments.
+            let remaining = pargs.finish();
+            if !remaining.is_empty() {
+                eprintln!("Warning: unused arguments left: {:?}.", remaining);
+            }
 
-    Ok(args)
+            command
+        }
+    };
+
+    Ok(command)
 }
 
-fn perform_split(args: &AppArgs) -> Result<(), Box<dyn Error>> {
-    let input_file = File::open(&args.file).expect("Unable to open file");
-    let result = advanced_dupe::decode(input_file).expect("Unable to decode file");
+/// Returns every remaining argument as a file, failing on an option that was not recognised.
+/// A lone `-` is kept, as it stands for stdin.
+fn parse_files(pargs: pico_args::Arguments) -> Result<Vec<PathBuf>, pico_args::Error> {
+    let files: Vec<PathBuf> = pargs.finish().into_iter().map(PathBuf::from).collect();
 
-    if args.print {
-        advanced_dupe::print_result(&result);
+    match files.iter().find(|file| {
+        let file = file.to_string_lossy();
+        file.starts_with('-') && file != STDIO
+    }) {
+        Some(option) => Err(pico_args::Error::ArgumentParsingFailed {
+            cause: format!("unknown option \"{}\"", option.display()),
+        }),
+        None => Ok(files),
     }
+}
 
-    // Find the entities table
-    let table = result.value().as_table().expect("Invalid format for AD5");
+/// Returns the dupes named on the command line. A directory gives every `.txt` file under it and
+/// a pattern such as `data/*/car*.txt` gives every path it matches, while anything else is taken
+/// to be a file. A file named twice is only returned once.
+fn expand_inputs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
 
-    let entities = table
-        .get(&lua_string!("Entities"))
-        .expect("Unable to find entities table");
-    let entity_map = entities.as_table().expect("Entity table is not a table");
+    for input in inputs {
+        let pattern = input.to_string_lossy();
 
-    // This will be all entities in the map
-    let split_lua_values;
-    {
-        if let Some(size) = args.size {
-            let split_maps = split_map(entity_map, size);
+        if input.is_dir() {
+            collect_dupes(input, &mut files)?;
+        } else if !input.exists() && pattern.contains(['*', '?', '[']) {
+            let mut matched = false;
 
-            split_lua_values = match split_lua_tables(&result.value(), &split_maps) {
-                Ok(val) => val,
-                Err(_) => {
-                    return Err(Box::new(std::io::Error::new(
-                        ErrorKind::InvalidData,
-                        "Unable to split the table",
-                    )));
+            for path in glob::glob(&pattern)? {
+                let path = path?;
+                matched = true;
+
+                if path.is_dir() {
+                    collect_dupes(&path, &mut files)?;
+                } else {
+                    files.push(path);
                 }
-            };
+            }
+
+            if !matched {
+                return Err(Box::new(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} matches no files", pattern),
+                )));
+            }
         } else {
-            split_lua_values = vec![result.value().clone()];
+            files.push(input.clone());
         }
     }
 
-    let file_name = args.file.file_name().unwrap().to_str().unwrap();
-    let extension = args.file.extension().unwrap().to_str().unwrap();
-    let file_name = file_name.replace(extension, "");
-    let file_name = file_name.trim_end_matches(".");
+    let mut unique: Vec<PathBuf> = Vec::with_capacity(files.len());
+    for file in files {
+        if !unique.contains(&file) {
+            unique.push(file);
+        }
+    }
 
-    for i in 0..split_lua_values.len() {
-        let lua_value = split_lua_values.get(i).unwrap();
-        let file_name = format!("{}-{}.txt", file_name, i);
+    Ok(unique)
+}
 
-        let output_file = File::create(file_name)?;
-        let mut out_stream = BufWriter::new(output_file);
-        advanced_dupe::encode(result.info(), lua_value, &mut out_stream)?;
+/// Adds every `.txt` file under a directory to `files`, in name order.
+fn collect_dupes(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_dupes(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("txt"))
+        {
+            files.push(path);
+        }
     }
 
     Ok(())
 }
 
-/// Splits a Lua table into multiple tables using a provided `split_maps` vector.
-///
-/// # Arguments
-///
-/// * `base_clone` - A reference to the base Lua table that needs to be split
-/// * `split_maps` - A vector of hash maps, each representing a split of the base table.
-///
-/// # Returns
-///
-/// A vector of `LuaValue` representing the split tables.
-///
-/// # Errors
-///
-/// If the `base_clone` is not a `LuaValue::Table`, this function will panic with the message "".
-fn split_lua_tables(
-    base_clone: &LuaValue,
-    split_maps: &Vec<HashMap<LuaValue, LuaValue>>,
-) -> Result<Vec<LuaValue>, Box<dyn Error>> {
-    let mut splits: Vec<LuaValue> = Vec::new();
-
-    for split in split_maps {
-        let mut split_value =
-            get_lua_value!(Table, base_clone.clone()).expect("base_clone must be a table");
-
-        split_value.insert(lua_string!("Entities"), lua_table!(split.clone()));
-
-        // We need to reset the head entity to the first entity in the table
-        let val = get_lua_value!(
-            Table,
-            split_value
-                .get(&lua_string!("HeadEnt"))
-                .expect("Unable to find HeadEnt")
-        )
-        .expect("HeadEnt is not a table");
-
-        // We will get the first value and grab the index
-        let first = split.keys().next().unwrap();
-        let mut val = val.clone();
-        val.insert(lua_string!("Index"), first.clone()).unwrap();
-
-        // Reset the head ent
-        split_value.insert(lua_string!("HeadEnt"), lua_table!(val));
-
-        // Push the Table into the split value
-        splits.push(lua_table!(split_value));
-    }
-
-    Ok(splits)
-}
-
-/// Splits the given `map` into `n` chunks.
-///
-/// # Arguments
-///
-/// * `map` - A reference to a `HashMap<LuaValue, LuaValue>` to be split.
-/// * `n` - The number of chunks to split `map` into.
-///
-/// # Returns
-///
-/// A `Vec` of `HashMap`s, where each `HashMap` is a chunk of the original `map`.
-///
-/// # Example
-///
-/// ```
-/// let mut map = HashMap::new();
-/// map.insert(LuaValue::String(String::from("Key1")), LuaValue::Number(1.0));
-/// map.insert(LuaValue::String(String::from("Key2")), LuaValue::Number(2.0));
-/// map.insert(LuaValue::String(String::from("Key3")), LuaValue::Number(3.0));
-///
-/// let chunks = split_map(&map, 2);
-/// assert_eq!(chunks.len(), 2);
-/// ```
-fn split_map(map: &HashMap<LuaValue, LuaValue>, n: usize) -> Vec<HashMap<LuaValue, LuaValue>> {
-    // Calculate the chunk size as the length of the map divided by n
-    let chunk_size = map.len() / n;
-
-    // Create a vec to store the chunks
-    let mut chunks = Vec::new();
-
-    // Create a HashMap to store the current chunk
-    let mut chunk = HashMap::new();
-
-    // Counter to keep track of how many items are in the current chunk
-    let mut chunk_count = 0;
-
-    // Iterate through each key-value pair in the map
-    for (key, value) in map.iter() {
-        // Insert the key-value pair into the current chunk
-        chunk.insert(key.clone(), value.clone());
-
-        // Increment the chunk count
-        chunk_count += 1;
-
-        // If the chunk count equals the chunk size, add the chunk to the chunks vec and reset the chunk and chunk count
-        if chunk_count == chunk_size {
-            chunks.push(chunk);
-            chunk = HashMap::new();
-            chunk_count = 0;
-        }
-    }
-
-    // If there are items remaining in the chunk, add the chunk to the chunks vec
-    if !chunk.is_empty() {
-        if chunks.is_empty() {
-            chunks.push(chunk);
+/// Runs `action` on every file across all cores, then prints the summary it gave for each file
+/// in order. A file that fails is reported without stopping the others. The summaries are
+/// printed to stderr when `to_stdout` is set, as stdout then carries the output itself.
+fn run_batch<F>(files: &[PathBuf], to_stdout: bool, action: F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(&Path) -> Result<String, Box<dyn Error>> + Sync,
+{
+    if files.is_empty() {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "no files were given",
+        )));
+    }
+
+    // Errors are turned into text as they can not be sent between threads
+    let results: Vec<Result<String, String>> = files
+        .par_iter()
+        .map(|file| action(file).map_err(|e| e.to_string()))
+        .collect();
+
+    let mut failed = 0;
+    for (file, result) in files.iter().zip(results) {
+        let line = match result {
+            Ok(summary) => format!("{}: {}", file.display(), summary),
+            Err(error) => {
+                failed += 1;
+                format!("{}: error: {}", file.display(), error)
+            }
+        };
+
+        if to_stdout {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    if failed > 0 {
+        return Err(Box::new(std::io::Error::other(format!(
+            "{} of {} files failed",
+            failed,
+            files.len()
+        ))));
+    }
+
+    Ok(())
+}
+
+/// Fails when `--output` is given with anything other than a single file, or when it is not
+/// given for stdin, as the output is otherwise named after the input.
+fn check_single_output(output: &Option<PathBuf>, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    if output.is_some() && files.len() != 1 {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "--output can only be used with a single file",
+        )));
+    }
+
+    if output.is_none() && files.iter().any(|file| is_stdio(&Some(file))) {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "reading from stdin needs --output",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns whether a file is `-`, which stands for stdin as an input and stdout as an output.
+fn is_stdio<P: AsRef<Path>>(file: &Option<P>) -> bool {
+    file.as_ref()
+        .is_some_and(|file| file.as_ref() == Path::new(STDIO))
+}
+
+/// Opens a file to read, or stdin for `-`.
+fn open_input(file: &Path) -> std::io::Result<Box<dyn Read>> {
+    if file == Path::new(STDIO) {
+        Ok(Box::new(std::io::stdin().lock()))
+    } else {
+        Ok(Box::new(File::open(file)?))
+    }
+}
+
+/// Creates a file to write, or writes to stdout for `-`.
+fn create_output(file: &Path) -> std::io::Result<Box<dyn Write>> {
+    if file == Path::new(STDIO) {
+        Ok(Box::new(BufWriter::new(std::io::stdout().lock())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(file)?)))
+    }
+}
+
+/// Parses a vector given as `X,Y,Z`.
+fn parse_vector(value: &str) -> Result<Vec3, String> {
+    let components: Vec<f64> = value
+        .split(',')
+        .map(|component| component.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("\"{}\" is not a vector: {}", value, e))?;
+
+    match components.as_slice() {
+        [x, y, z] => Ok([*x, *y, *z]),
+        _ => Err(format!("\"{}\" is not a vector of 3 components", value)),
+    }
+}
+
+/// Parses an LZMA compression level, which must be between 0 and 9.
+fn parse_level(value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|level| *level <= MAX_COMPRESSION_LEVEL)
+        .ok_or_else(|| {
+            format!(
+                "\"{}\" is not a compression level between 0 and {}",
+                value, MAX_COMPRESSION_LEVEL
+            )
+        })
+}
+
+/// Returns the options to encode with, which are the defaults apart from `--level`.
+fn encode_options(level: Option<u32>) -> EncodeOptions {
+    let mut options = EncodeOptions::default();
+    if let Some(level) = level {
+        options.compression_level = level;
+    }
+
+    options
+}
+
+fn perform_info(file: &Path) -> Result<String, Box<dyn Error>> {
+    let result = advanced_dupe::decode(open_input(file)?)?;
+    let info = result.info();
+
+    Ok(format!(
+        "{} keys\n{}",
+        info.values().count(),
+        advanced_dupe::format_info(info).trim_end()
+    ))
+}
+
+fn perform_print(file: &Path) -> Result<(), Box<dyn Error>> {
+    let result = advanced_dupe::decode(open_input(file)?)?;
+    advanced_dupe::print_result(&result);
+
+    Ok(())
+}
+
+fn perform_split(
+    args: &SplitArgs,
+    file: &Path,
+    inputs: &HashSet<PathBuf>,
+) -> Result<String, Box<dyn Error>> {
+    let result = advanced_dupe::decode(open_input(file)?)?;
+
+    let dupe = Dupe::from_lua(result.value())?;
+    let entities = &dupe.entities;
+    let constraints = dupe.constraints.as_slice();
+
+    let split_maps = match (args.size, args.max_props) {
+        (Some(_), Some(_)) => {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "--size and --max-props cannot be used together",
+            )));
+        }
+        (Some(size), None) if args.spatial => {
+            let constraints = if args.ungrouped { &[] } else { constraints };
+            let groups = connected_groups(entities, constraints);
+
+            split_spatial(entities, &groups, size)
+        }
+        (Some(size), None) if args.ungrouped => split_map(entities, size),
+        (Some(size), None) => split_connected(entities, constraints, size),
+        (None, Some(max_props)) => split_max_props(
+            entities,
+            constraints,
+            max_props,
+            args.ungrouped,
+            args.spatial,
+        )?,
+        (None, None) => {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "split needs --size or --max-props",
+            )));
+        }
+    };
+
+    let parts = split_dupe(&dupe, &split_maps, args.recenter);
+    let count = parts.len();
+
+    let source = split_source(file);
+    let stem = Path::new(&source).file_stem().unwrap().to_string_lossy();
+    let out_dir = split_out_dir(args, file);
+
+    // Every name is worked out before anything is written, so nothing is left half split
+    let mut outputs: Vec<PathBuf> = Vec::with_capacity(count);
+    for (i, part) in parts.iter().enumerate() {
+        // Parts are numbered from 1, like the part info key and the manifest
+        let name = render_template(&args.template, &stem, i + 1, count, part.entities.len())?;
+        let output = out_dir.join(name);
+
+        if outputs.contains(&output) {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "every part would be written to {}, put {{index}} in --template",
+                    output.display()
+                ),
+            )));
+        }
+        outputs.push(output);
+    }
+
+    let manifest = out_dir.join(format!("{}-manifest.json", stem));
+    let input = outputs
+        .iter()
+        .chain([&manifest])
+        .find(|path| path.canonicalize().is_ok_and(|path| inputs.contains(&path)));
+    if let Some(input) = input {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} is also being split and can not be written",
+                input.display()
+            ),
+        )));
+    }
+
+    if !args.force {
+        if let Some(existing) = outputs.iter().chain([&manifest]).find(|path| path.exists()) {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists, use --force", existing.display()),
+            )));
+        }
+    }
+
+    if !out_dir.as_os_str().is_empty() {
+        std::fs::create_dir_all(&out_dir)?;
+    }
+
+    // Every part is stamped as a freshly saved dupe
+    let mut options = encode_options(args.level);
+    options.update_size = true;
+
+    for (i, (part, output)) in parts.iter().zip(&outputs).enumerate() {
+        let info =
+            advanced_dupe::part_info(result.info(), &source, i + 1, count, args.name.as_deref());
+
+        let output_file = create_split_output(output, args.force)?;
+        let mut out_stream = BufWriter::new(output_file);
+        advanced_dupe::encode_with_options(&info, &part.to_lua(), &options, &mut out_stream)?;
+    }
+
+    // The manifest lists the parts relative to itself, in the order they were split
+    let manifest_parts: Vec<_> = parts
+        .iter()
+        .zip(&outputs)
+        .map(|(part, output)| {
+            serde_json::json!({
+                "file": output.file_name().unwrap().to_string_lossy(),
+                "entities": part.entities.len(),
+                "constraints": part.constraints.len(),
+            })
+        })
+        .collect();
+    let document = serde_json::json!({
+        "source": source,
+        "entities": entities.len(),
+        "parts": manifest_parts,
+    });
+
+    let mut out_stream = BufWriter::new(create_split_output(&manifest, args.force)?);
+    serde_json::to_writer_pretty(&mut out_stream, &document)?;
+    out_stream.write_all(b"\n")?;
+
+    Ok(format!(
+        "split {} entities into {} parts, listed in {}",
+        entities.len(),
+        count,
+        manifest.display()
+    ))
+}
+
+/// Returns the file name of the dupe that split parts are stamped with and named after. Parts
+/// of a dupe read from stdin are named as if it was stdin.txt.
+fn split_source(file: &Path) -> String {
+    if file == Path::new(STDIO) {
+        String::from("stdin.txt")
+    } else {
+        file.file_name().unwrap().to_string_lossy().into_owned()
+    }
+}
+
+/// Returns the directory that the parts of `file` are written to
+fn split_out_dir(args: &SplitArgs, file: &Path) -> PathBuf {
+    match &args.out_dir {
+        Some(out_dir) => out_dir.clone(),
+        None => file.parent().unwrap_or(Path::new("")).to_path_buf(),
+    }
+}
+
+/// Leaves out the files that are parts written by an earlier split, so splitting a directory
+/// again does not split its parts. A file is a part when a manifest next to it lists it, or its
+/// name is given by the template for another of the files being split into the same directory.
+/// Every file left out is reported.
+fn skip_split_parts(args: &SplitArgs, files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut manifests: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    let mut stems: HashMap<PathBuf, Vec<(&Path, String)>> = HashMap::new();
+    for file in &files {
+        let source = split_source(file);
+        let stem = Path::new(&source).file_stem().unwrap().to_string_lossy();
+        stems
+            .entry(split_out_dir(args, file))
+            .or_default()
+            .push((file, stem.into_owned()));
+    }
+
+    let mut kept = Vec::with_capacity(files.len());
+    for file in &files {
+        let dir = file.parent().unwrap_or(Path::new("")).to_path_buf();
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+
+        let listed = manifests
+            .entry(dir.clone())
+            .or_insert_with(|| manifest_parts(&dir));
+        let rendered = stems.get(&dir).is_some_and(|stems| {
+            stems.iter().any(|(source, stem)| {
+                *source != file.as_path()
+                    && name
+                        .as_ref()
+                        .is_some_and(|name| matches_template(&args.template, stem, name))
+            })
+        });
+
+        if name.is_some_and(|name| listed.contains(&name)) || rendered {
+            println!(
+                "{}: skipped, it is a part of an earlier split",
+                file.display()
+            );
         } else {
-            let last_chunk = chunks.last_mut().unwrap();
+            kept.push(file.clone());
+        }
+    }
+
+    kept
+}
+
+/// Returns the file names of the parts listed by every split manifest in `dir`. A manifest
+/// that can not be read lists nothing.
+fn manifest_parts(dir: &Path) -> HashSet<String> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return HashSet::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with("-manifest.json"))
+        })
+        .filter_map(|path| std::fs::read(path).ok())
+        .filter_map(|data| serde_json::from_slice::<serde_json::Value>(&data).ok())
+        .flat_map(|manifest| {
+            let parts = manifest["parts"].as_array().cloned().unwrap_or_default();
+            parts
+                .into_iter()
+                .filter_map(|part| part["file"].as_str().map(String::from))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Fails before anything is split when two of the files would be split into the same parts or
+/// manifest, such as files with the same stem from different directories under one `--out-dir`.
+/// The files are split in parallel, so they would otherwise overwrite each other.
+fn check_split_outputs(args: &SplitArgs, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    // Without {stem} in the template every file names its parts the same
+    let parts_named_by_stem = args.template.contains("{stem}");
+    let mut stems: HashMap<(PathBuf, String), &Path> = HashMap::new();
+    let mut dirs: HashMap<PathBuf, &Path> = HashMap::new();
+
+    for file in files {
+        let out_dir = split_out_dir(args, file);
+        let source = split_source(file);
+        let stem = Path::new(&source).file_stem().unwrap().to_string_lossy();
 
-            for (key, value) in chunk.iter() {
-                last_chunk.insert(key.clone(), value.clone());
+        let same_parts = if parts_named_by_stem {
+            None
+        } else {
+            dirs.insert(out_dir.clone(), file)
+        };
+        let same_stem = stems.insert((out_dir.clone(), stem.into_owned()), file);
+
+        if let Some(other) = same_stem.or(same_parts) {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} and {} would both be split into the same files in {}",
+                    other.display(),
+                    file.display(),
+                    out_dir.display()
+                ),
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a split part or manifest. An existing file is only overwritten when `force` is given, and
+/// is otherwise reported even when it was created after the outputs were first checked.
+fn create_split_output(path: &Path, force: bool) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    if force {
+        options.write(true).create(true).truncate(true);
+    } else {
+        options.write(true).create_new(true);
+    }
+
+    options.open(path).map_err(|error| match error.kind() {
+        ErrorKind::AlreadyExists => std::io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists, use --force", path.display()),
+        ),
+        _ => error,
+    })
+}
+
+/// Returns the file name of a split part from a template, replacing `{stem}` with the stem of the
+/// split file, `{index}` with the number of the part from 1, `{count}` with the number of parts and
+/// `{props}` with the number of entities in the part.
+fn render_template(
+    template: &str,
+    stem: &str,
+    index: usize,
+    count: usize,
+    props: usize,
+) -> Result<String, String> {
+    let mut name = String::with_capacity(template.len());
+
+    for part in parse_template(template)? {
+        match part {
+            TemplatePart::Text(text) => name.push_str(text),
+            TemplatePart::Stem => name.push_str(stem),
+            TemplatePart::Index => name.push_str(&index.to_string()),
+            TemplatePart::Count => name.push_str(&count.to_string()),
+            TemplatePart::Props => name.push_str(&props.to_string()),
+        }
+    }
+
+    Ok(name)
+}
+
+/// Returns whether `name` is a file name that `render_template` gives for the split file with
+/// the stem `stem`, for any numbers. A template that is not valid matches nothing.
+fn matches_template(template: &str, stem: &str, name: &str) -> bool {
+    fn matches(parts: &[TemplatePart], stem: &str, name: &str) -> bool {
+        match parts.split_first() {
+            None => name.is_empty(),
+            Some((TemplatePart::Text(text), rest)) => name
+                .strip_prefix(text)
+                .is_some_and(|name| matches(rest, stem, name)),
+            Some((TemplatePart::Stem, rest)) => name
+                .strip_prefix(stem)
+                .is_some_and(|name| matches(rest, stem, name)),
+            // A number is any run of digits
+            Some((_, rest)) => {
+                let digits = name.bytes().take_while(u8::is_ascii_digit).count();
+                (1..=digits).any(|length| matches(rest, stem, &name[length..]))
             }
         }
     }
 
-    chunks
+    parse_template(template).is_ok_and(|parts| matches(&parts, stem, name))
+}
+
+/// A piece of a `--template`
+enum TemplatePart<'a> {
+    Text(&'a str),
+    Stem,
+    Index,
+    Count,
+    Props,
+}
+
+/// Splits a `--template` into its text and placeholders.
+fn parse_template(template: &str) -> Result<Vec<TemplatePart<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            parts.push(TemplatePart::Text(&rest[..open]));
+        }
+        rest = &rest[open..];
+
+        let close = rest
+            .find('}')
+            .ok_or_else(|| format!("\"{}\" has an unclosed placeholder", template))?;
+        parts.push(match &rest[1..close] {
+            "stem" => TemplatePart::Stem,
+            "index" => TemplatePart::Index,
+            "count" => TemplatePart::Count,
+            "props" => TemplatePart::Props,
+            placeholder => {
+                return Err(format!(
+                    "\"{{{}}}\" is not one of {{stem}}, {{index}}, {{count}} or {{props}}",
+                    placeholder
+                ))
+            }
+        });
+        rest = &rest[close + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Text(rest));
+    }
+
+    Ok(parts)
+}
+
+fn perform_merge(args: &MergeArgs, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    if files.is_empty() {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "merge needs at least one file",
+        )));
+    }
+
+    let mut results = Vec::new();
+    let mut dupes = Vec::new();
+    for file in files {
+        let result = advanced_dupe::decode(open_input(file)?)?;
+        dupes.push(Dupe::from_lua(result.value())?);
+        results.push(result);
+    }
+
+    // Every file is moved one more offset along than the file before it
+    let dupes: Vec<&Dupe> = dupes.iter().collect();
+    let offsets: Vec<Vec3> = (0..results.len())
+        .map(|i| args.offset.map(|component| component * i as f64))
+        .collect();
+
+    let merged = merge_dupes(&dupes, &offsets)?.to_lua();
+
+    // The info block is taken from the first file and credits every player
+    let mut info = results[0].info().clone();
+    let mut names: Vec<&str> = Vec::new();
+    for result in &results {
+        let name = result.info().player_name();
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    info.set_value("name", names.join(", "));
+
+    // The merged dupe can be written to stdout, so this goes to stderr
+    eprintln!("Merged {} dupes", results.len());
+
+    // The size of the first file would be stale, it is set to that of the merged data block
+    let mut options = encode_options(args.level);
+    options.update_size = true;
+
+    let mut out_stream = create_output(&args.output)?;
+    advanced_dupe::encode_with_options(&info, &merged, &options, &mut out_stream)?;
+    out_stream.flush()?;
+
+    Ok(())
+}
+
+/// Returns the format of a file from its contents: dupes start with their signature and JSON
+/// documents with an object, anything else is taken to be a raw data block.
+fn detect_format(contents: &[u8]) -> Format {
+    if contents.starts_with(b"AD2F") {
+        Format::Dupe
+    } else if contents.trim_ascii_start().starts_with(b"{") {
+        Format::Json
+    } else {
+        Format::Raw
+    }
+}
+
+fn perform_convert(args: &ConvertArgs, file: &Path) -> Result<String, Box<dyn Error>> {
+    // The input is read once, as stdin can not be read again once its format is known
+    let mut input = Vec::new();
+    open_input(file)?.read_to_end(&mut input)?;
+
+    let from = detect_format(&input);
+    let to = args.to.unwrap_or(match from {
+        Format::Dupe => Format::Json,
+        _ => Format::Dupe,
+    });
+
+    let options = encode_options(args.level);
+
+    let output = match (from, to) {
+        (Format::Dupe, Format::Json) => {
+            let result = advanced_dupe::decode(input.as_slice())?;
+
+            let output = convert_output(args, file, "json")?;
+            let mut out_stream = create_output(&output)?;
+            serde_json::to_writer_pretty(&mut out_stream, &json::to_json(&result))?;
+            out_stream.write_all(b"\n")?;
+            out_stream.flush()?;
+            output
+        }
+        (Format::Json, Format::Dupe) => {
+            let document = serde_json::from_slice(&input)?;
+            let result = json::from_json(&document)?;
+
+            let output = convert_output(args, file, "txt")?;
+            let mut out_stream = create_output(&output)?;
+            advanced_dupe::encode_with_options(
+                result.info(),
+                result.value(),
+                &options,
+                &mut out_stream,
+            )?;
+            out_stream.flush()?;
+            output
+        }
+        (Format::Dupe, Format::Dupe) => {
+            let result = advanced_dupe::decode(input.as_slice())?;
+
+            let output = convert_output(args, file, "txt")?;
+            let mut out_stream = create_output(&output)?;
+            AdCodec5::with_options(options).encode_result(&result, &mut out_stream)?;
+            out_stream.flush()?;
+            output
+        }
+        (Format::Dupe, Format::Raw) => {
+            let (_, data) = advanced_dupe::unpack(input.as_slice())?;
+
+            let output = convert_output(args, file, "bin")?;
+            let mut out_stream = create_output(&output)?;
+            out_stream.write_all(&data)?;
+            out_stream.flush()?;
+            output
+        }
+        (Format::Raw, Format::Dupe) => {
+            let info = args.info.as_ref().ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "a raw data block needs --info to give the dupe to take the info block from",
+                )
+            })?;
+            let (info, _) = advanced_dupe::unpack(File::open(info)?)?;
+            let output = convert_output(args, file, "txt")?;
+            let mut out_stream = create_output(&output)?;
+            advanced_dupe::pack(&info, &input, &options, &mut out_stream)?;
+            out_stream.flush()?;
+            output
+        }
+        (from, to) => {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("unable to convert {:?} to {:?}", from, to),
+            )));
+        }
+    };
+
+    Ok(format!("converted into {}", output.display()))
+}
+
+fn perform_validate(file: &Path) -> Result<String, Box<dyn Error>> {
+    advanced_dupe::decode_strict(open_input(file)?)?;
+
+    Ok(String::from("valid"))
+}
+
+fn perform_verify(file: &Path) -> Result<String, Box<dyn Error>> {
+    let divergences = advanced_dupe::verify(open_input(file)?)?;
+
+    if !divergences.is_empty() {
+        let divergences: Vec<String> = divergences.iter().map(ToString::to_string).collect();
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("does not round trip exactly, {}", divergences.join(", ")),
+        )));
+    }
+
+    Ok(String::from("round trips exactly"))
+}
+
+fn perform_repair(args: &RepairArgs, file: &Path) -> Result<String, Box<dyn Error>> {
+    let repaired = match advanced_dupe::repair(open_input(file)?)? {
+        Some(repaired) => repaired,
+        None => return Ok(String::from("nothing to repair")),
+    };
+
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => {
+            let file_stem = file.file_stem().unwrap().to_str().unwrap();
+            file.with_file_name(format!("{}-repaired.txt", file_stem))
+        }
+    };
+
+    let mut out_stream = create_output(&output)?;
+    out_stream.write_all(&repaired)?;
+    out_stream.flush()?;
+
+    Ok(format!("repaired into {}", output.display()))
+}
+
+fn perform_stats(args: &StatsArgs, file: &Path) -> Result<String, Box<dyn Error>> {
+    let mut input = Vec::new();
+    open_input(file)?.read_to_end(&mut input)?;
+
+    Ok(DupeStats::from_file(&input, args.top)?.to_string())
+}
+
+/// Prints the statistics of every file as a JSON array, in which a file that fails has its
+/// error in place of its statistics.
+fn print_stats_json(args: &StatsArgs, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    if files.is_empty() {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "no files were given",
+        )));
+    }
+
+    let results: Vec<serde_json::Value> = files
+        .par_iter()
+        .map(|file| {
+            let stats = open_input(file)
+                .and_then(|mut reader| {
+                    let mut input = Vec::new();
+                    reader.read_to_end(&mut input).map(|_| input)
+                })
+                .map_err(|e| e.to_string())
+                .and_then(|input| {
+                    DupeStats::from_file(&input, args.top).map_err(|e| e.to_string())
+                });
+
+            match stats {
+                Ok(stats) => serde_json::json!({ "file": file, "stats": stats }),
+                Err(error) => serde_json::json!({ "file": file, "error": error }),
+            }
+        })
+        .collect();
+
+    let mut out_stream = create_output(Path::new(STDIO))?;
+    serde_json::to_writer_pretty(&mut out_stream, &results)?;
+    out_stream.write_all(b"\n")?;
+    out_stream.flush()?;
+
+    let failed = results
+        .iter()
+        .filter(|result| result.get("error").is_some())
+        .count();
+    if failed > 0 {
+        return Err(Box::new(std::io::Error::other(format!(
+            "{} of {} files failed",
+            failed,
+            files.len()
+        ))));
+    }
+
+    Ok(())
+}
+
+/// Returns the file a conversion writes to. Without `--output` it is the input file with the
+/// `extension` of the new format, which must not exist yet so that a dupe converted back and
+/// forth never replaces the original. A dupe written again as a dupe gets `-v5` added instead.
+fn convert_output(
+    args: &ConvertArgs,
+    file: &Path,
+    extension: &str,
+) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(output) = &args.output {
+        return Ok(output.clone());
+    }
+
+    let mut output = file.with_extension(extension);
+    if output == file {
+        let file_stem = file.file_stem().unwrap().to_str().unwrap();
+        output = file.with_file_name(format!("{}-v5.{}", file_stem, extension));
+    }
+
+    if output.exists() {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists, use --output", output.display()),
+        )));
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+
+    use super::{
+        check_split_outputs, detect_format, expand_inputs, matches_template, parse_args,
+        parse_level, parse_vector, render_template, skip_split_parts, Command, Format, SplitArgs,
+        DEFAULT_TEMPLATE,
+    };
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("0"), Ok(0));
+        assert_eq!(parse_level("9"), Ok(9));
+        assert!(parse_level("10").is_err());
+        assert!(parse_level("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_vector() {
+        assert_eq!(parse_vector("1, -2.5,3"), Ok([1.0, -2.5, 3.0]));
+        assert!(parse_vector("1,2").is_err());
+        assert!(parse_vector("1,2,z").is_err());
+    }
+
+    #[test]
+    fn test_render_template() {
+        assert_eq!(
+            render_template("{stem}-{index}.txt", "build.txt", 2, 5, 40),
+            Ok(String::from("build.txt-2.txt"))
+        );
+        assert_eq!(
+            render_template(
+                "part {index} of {count} ({props} props).txt",
+                "build",
+                1,
+                3,
+                7
+            ),
+            Ok(String::from("part 1 of 3 (7 props).txt"))
+        );
+        assert!(render_template("{name}.txt", "build", 0, 1, 1).is_err());
+        assert!(render_template("{stem.txt", "build", 0, 1, 1).is_err());
+
+        assert!(matches_template(
+            "{stem}-{index}.txt",
+            "build",
+            "build-12.txt"
+        ));
+        assert!(matches_template(
+            "part {index} of {count}.txt",
+            "build",
+            "part 1 of 3.txt"
+        ));
+        assert!(!matches_template(
+            "{stem}-{index}.txt",
+            "build",
+            "build-.txt"
+        ));
+        assert!(!matches_template(
+            "{stem}-{index}.txt",
+            "build",
+            "tank-1.txt"
+        ));
+        assert!(!matches_template("{name}.txt", "build", "build.txt"));
+    }
+
+    fn split(out_dir: Option<&str>, template: &str) -> SplitArgs {
+        SplitArgs {
+            files: Vec::new(),
+            size: Some(2),
+            max_props: None,
+            ungrouped: false,
+            spatial: false,
+            recenter: false,
+            level: None,
+            name: None,
+            out_dir: out_dir.map(PathBuf::from),
+            template: String::from(template),
+            force: false,
+        }
+    }
+
+    #[test]
+    fn test_check_split_outputs() {
+        let files = [PathBuf::from("a/car.txt"), PathBuf::from("b/car.txt")];
+
+        // Next to their dupes the parts of both are kept apart
+        assert!(check_split_outputs(&split(None, DEFAULT_TEMPLATE), &files).is_ok());
+
+        // Under one directory the parts and manifests of both would be car-*
+        let error = check_split_outputs(&split(Some("parts"), DEFAULT_TEMPLATE), &files)
+            .expect_err("Both dupes were split into the same files");
+        assert!(error.to_string().contains("a/car.txt and b/car.txt"));
+
+        // Without {stem} every dupe in a directory names its parts the same
+        let files = [PathBuf::from("car.txt"), PathBuf::from("truck.txt")];
+        assert!(check_split_outputs(&split(None, DEFAULT_TEMPLATE), &files).is_ok());
+        assert!(check_split_outputs(&split(None, "part-{index}.txt"), &files).is_err());
+    }
+
+    #[test]
+    fn test_parse_args() {
+        match parse_args(args(&[
+            "split",
+            "--max-props",
+            "50",
+            "--spatial",
+            "build.txt",
+        ])) {
+            Ok(Command::Split(split)) => {
+                assert_eq!(split.files, [PathBuf::from("build.txt")]);
+                assert_eq!(split.max_props, Some(50));
+                assert!(split.spatial);
+                assert!(!split.ungrouped);
+                assert_eq!(split.template, "{stem}-{index}.txt");
+                assert!(!split.force);
+            }
+            other => panic!("Not a split: {:?}", other),
+        }
+
+        match parse_args(args(&["convert", "--to", "raw", "build.txt"])) {
+            Ok(Command::Convert(convert)) => assert_eq!(convert.to, Some(Format::Raw)),
+            other => panic!("Not a convert: {:?}", other),
+        }
+
+        match parse_args(args(&["stats", "--json", "--top", "3", "a.txt"])) {
+            Ok(Command::Stats(stats)) => {
+                assert!(stats.json);
+                assert_eq!(stats.top, 3);
+                assert_eq!(stats.files, [PathBuf::from("a.txt")]);
+            }
+            other => panic!("Not a stats: {:?}", other),
+        }
+
+        match parse_args(args(&["info", "a.txt", "b.txt"])) {
+            Ok(Command::Info(files)) => assert_eq!(files.len(), 2),
+            other => panic!("Not an info: {:?}", other),
+        }
+
+        assert!(parse_args(args(&["convert", "--to", "lua", "build.txt"])).is_err());
+        assert!(parse_args(args(&["validate", "--strict", "build.txt"])).is_err());
+
+        match parse_args(args(&["convert", "-o", "-", "-"])) {
+            Ok(Command::Convert(convert)) => {
+                assert_eq!(convert.files, [PathBuf::from("-")]);
+                assert_eq!(convert.output, Some(PathBuf::from("-")));
+            }
+            other => panic!("Not a convert: {:?}", other),
+        }
+        assert!(parse_args(args(&["build.txt", "--size", "2"])).is_err());
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(b"AD2F\x05\n"), Format::Dupe);
+        assert_eq!(detect_format(b"\n  {\"info\": {}}"), Format::Json);
+        assert_eq!(detect_format(&[255, 246]), Format::Raw);
+    }
+
+    #[test]
+    fn test_skip_split_parts() {
+        let dir = std::env::temp_dir().join("adv2-splitter-skip-parts");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in [
+            "car.txt",
+            "car-1.txt",
+            "car-2.txt",
+            "tank.txt",
+            "renamed.txt",
+        ] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        std::fs::write(
+            dir.join("old-manifest.json"),
+            br#"{ "parts": [{ "file": "renamed.txt" }] }"#,
+        )
+        .unwrap();
+
+        // Parts named by the template for car.txt and the parts listed by a manifest are left out
+        let files = skip_split_parts(
+            &split(None, DEFAULT_TEMPLATE),
+            expand_inputs(std::slice::from_ref(&dir)).unwrap(),
+        );
+        assert_eq!(files, [dir.join("car.txt"), dir.join("tank.txt")]);
+
+        // Parts written elsewhere do not hide the files next to them
+        let files = skip_split_parts(
+            &split(Some("parts"), DEFAULT_TEMPLATE),
+            vec![dir.join("car.txt"), dir.join("car-1.txt")],
+        );
+        assert_eq!(files, [dir.join("car.txt"), dir.join("car-1.txt")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let dir = std::env::temp_dir().join("adv2-splitter-batch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("alice")).unwrap();
+        std::fs::create_dir_all(dir.join("bob")).unwrap();
+        for file in [
+            "alice/car.txt",
+            "alice/tank.TXT",
+            "alice/notes.json",
+            "bob/car.txt",
+        ] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+
+        let files = expand_inputs(std::slice::from_ref(&dir)).unwrap();
+        assert_eq!(
+            files,
+            [
+                dir.join("alice/car.txt"),
+                dir.join("alice/tank.TXT"),
+                dir.join("bob/car.txt")
+            ]
+        );
+
+        // A file matched twice is only given once
+        let files = expand_inputs(&[dir.join("*/car.txt"), dir.join("bob/car.txt")]).unwrap();
+        assert_eq!(files, [dir.join("alice/car.txt"), dir.join("bob/car.txt")]);
+
+        assert!(expand_inputs(&[dir.join("*/plane.txt")]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
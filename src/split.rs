@@ -0,0 +1,994 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::Hash;
+use std::io::ErrorKind;
+
+use indexmap::IndexMap;
+
+use crate::dupe::{Constraint, Dupe, Entities, EntityIndex, Vec3};
+
+/// Splits a dupe into one dupe for every part of its entities.
+///
+/// # Arguments
+///
+/// * `dupe` - The dupe that needs to be split
+/// * `parts` - The entities of every part, such as returned by `split_map`.
+/// * `recenter` - Whether to translate the entity positions so the new head entity is at the origin.
+///
+/// # Returns
+///
+/// A vector of `Dupe` holding the parts, which keep every value of `dupe` other than the
+/// entities, the constraints and the head entity.
+///
+/// A constraint is kept in a part when it is attached to at least one entity of the part and
+/// every other entity of the dupe it is attached to is in the part too. References to entities
+/// that are not part of the dupe, such as the world, are ignored.
+///
+/// The `head_ent` of every part is set to the entity closest to the centroid of the part's
+/// entity positions. When the part is recentered, its `pos` becomes the world position of that
+/// entity and its `z`, the height above the ground, is moved by the same amount, so every entity
+/// keeps its world position either way.
+pub fn split_dupe(dupe: &Dupe, parts: &[Entities], recenter: bool) -> Vec<Dupe> {
+    let mut splits: Vec<Dupe> = Vec::new();
+
+    for part in parts {
+        let mut split = dupe.clone();
+
+        // We will use the entity closest to the centre of the part as the head
+        let head = head_entity(part);
+        let offset = head.and_then(|head| part[&head].pos).unwrap_or([0.0; 3]);
+
+        split.entities = part.clone();
+        if recenter {
+            for entity in split.entities.values_mut() {
+                entity.translate(offset);
+            }
+        }
+
+        // Only keep the constraints whose entities all ended up in this part
+        split.constraints.retain(|constraint| {
+            let attached: Vec<EntityIndex> = constraint
+                .attached()
+                .into_iter()
+                .filter(|index| dupe.entities.contains_key(index))
+                .collect();
+
+            !attached.is_empty() && attached.iter().all(|index| part.contains_key(index))
+        });
+
+        // We need to reset the head entity to an entity in this part
+        if let Some(head_ent) = &mut split.head_ent {
+            head_ent.index = head.or(head_ent.index);
+
+            // Entity positions are relative to the head, so its world position moves by the
+            // offset the entities were moved back by
+            if recenter {
+                if let Some(position) = &mut head_ent.pos {
+                    for (value, offset) in position.iter_mut().zip(offset) {
+                        *value += offset;
+                    }
+                }
+                if let Some(z) = &mut head_ent.z {
+                    *z += offset[2];
+                }
+            }
+        }
+
+        splits.push(split);
+    }
+
+    splits
+}
+
+/// Merges several dupes into one.
+///
+/// The entities of every dupe are renumbered so that their indices follow on from the dupe
+/// before it, and the references to them in the constraints are rewritten to match. The
+/// constraints of all dupes are combined into one list. Every other value, including the
+/// `head_ent`, is taken from the first dupe.
+///
+/// # Arguments
+///
+/// * `dupes` - The dupes to merge, in order.
+/// * `offsets` - The vector every dupe is moved by, the same length as `dupes`.
+///
+/// # Returns
+///
+/// The merged dupe, or an error if there are no dupes or there is not an offset for every dupe.
+pub fn merge_dupes(dupes: &[&Dupe], offsets: &[Vec3]) -> Result<Dupe, Box<dyn Error>> {
+    if offsets.len() != dupes.len() {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} offsets were given to merge {} dupes",
+                offsets.len(),
+                dupes.len()
+            ),
+        )));
+    }
+
+    let mut merged = match dupes.first() {
+        Some(first) => (*first).clone(),
+        None => {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "There are no dupes to merge",
+            )))
+        }
+    };
+    let mut entities = Entities::new();
+    let mut constraints = Vec::new();
+    let mut head_mapping = HashMap::new();
+
+    for (i, (dupe, offset)) in dupes.iter().zip(offsets).enumerate() {
+        // Keep the entities in their original order so that the numbering stays predictable
+        let mut indices: Vec<EntityIndex> = dupe.entities.keys().copied().collect();
+        indices.sort();
+
+        let mut mapping = HashMap::new();
+        for index in indices {
+            let new_index = (entities.len() + 1) as EntityIndex;
+
+            // Translating moves entities back, so the offset is negated to move them forward
+            let mut entity = dupe.entities[&index].clone();
+            entity.translate(offset.map(|component| -component));
+
+            entities.insert(new_index, entity);
+            mapping.insert(index, new_index);
+        }
+
+        for constraint in &dupe.constraints {
+            let mut constraint = constraint.clone();
+            constraint.remap(&mapping);
+            constraints.push(constraint);
+        }
+
+        if i == 0 {
+            head_mapping = mapping;
+        }
+    }
+
+    merged.entities = entities;
+    merged.constraints = constraints;
+
+    if let Some(head_ent) = &mut merged.head_ent {
+        if let Some(index) = head_ent.index.and_then(|index| head_mapping.get(&index)) {
+            head_ent.index = Some(*index);
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Splits the `entities` into at most `n` chunks without separating entities that are joined
+/// by a constraint.
+///
+/// The entities are grouped into connected components using the entities each constraint is
+/// attached to. Every group is placed wholly into one chunk, largest groups first, into
+/// whichever chunk currently holds the fewest entities. Chunks that end up empty, because
+/// there are fewer groups than `n`, are left out.
+///
+/// # Arguments
+///
+/// * `entities` - The entities to be split.
+/// * `constraints` - The constraints of the dupe.
+/// * `n` - The number of chunks to split `entities` into.
+pub fn split_connected(entities: &Entities, constraints: &[Constraint], n: usize) -> Vec<Entities> {
+    let groups = connected_groups(entities, constraints);
+
+    pack_groups(entities, &groups, n)
+}
+
+/// Splits the `entities` into as few chunks as possible such that no chunk holds more than
+/// `max_props` entities, with the chunk sizes balanced against each other.
+///
+/// Unless `ungrouped` is set, entities joined by constraints are kept together like in
+/// `split_connected`, which fails when a single group is larger than `max_props`. When
+/// `spatial` is set the chunks are regions of the build like in `split_spatial`, where every
+/// region holding more than `max_props` entities is cut in two again.
+///
+/// # Arguments
+///
+/// * `entities` - The entities to be split.
+/// * `constraints` - The constraints of the dupe.
+/// * `max_props` - The most entities a single chunk may hold.
+/// * `ungrouped` - Whether to ignore constraints and split in table order.
+/// * `spatial` - Whether to split into regions by entity position.
+pub fn split_max_props(
+    entities: &Entities,
+    constraints: &[Constraint],
+    max_props: usize,
+    ungrouped: bool,
+    spatial: bool,
+) -> Result<Vec<Entities>, Box<dyn Error>> {
+    if max_props == 0 {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "--max-props must be at least 1",
+        )));
+    }
+
+    let minimum = entities.len().div_ceil(max_props).max(1);
+
+    if ungrouped && !spatial {
+        return Ok(split_map(entities, minimum));
+    }
+
+    // Without constraints every entity is a group of its own
+    let groups = connected_groups(entities, if ungrouped { &[] } else { constraints });
+
+    // Groups are sorted largest first
+    if let Some(largest) = groups.first().filter(|group| group.len() > max_props) {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} entities are joined by constraints, which is more than --max-props {}",
+                largest.len(),
+                max_props
+            ),
+        )));
+    }
+
+    if spatial {
+        let mut units = group_centroids(entities, &groups);
+        let mut regions = Vec::new();
+        bisect_max_props(&mut units, max_props, &mut regions);
+
+        return Ok(region_chunks(entities, regions));
+    }
+
+    // Packing whole groups may need more chunks than the entity count alone suggests, but it
+    // always fits once every group has its own chunk
+    for n in minimum..groups.len().max(minimum) {
+        let chunks = pack_groups(entities, &groups, n);
+
+        if chunks.iter().all(|chunk| chunk.len() <= max_props) {
+            return Ok(chunks);
+        }
+    }
+
+    Ok(pack_groups(entities, &groups, groups.len().max(minimum)))
+}
+
+/// Returns the indices of the `entities` grouped into the connected components formed by the
+/// `constraints`, largest group first.
+pub fn connected_groups(entities: &Entities, constraints: &[Constraint]) -> Vec<Vec<EntityIndex>> {
+    // Union find over the entity positions, every constraint joins its entities together
+    let mut parents: Vec<usize> = (0..entities.len()).collect();
+
+    for constraint in constraints {
+        let attached: Vec<usize> = constraint
+            .attached()
+            .iter()
+            .filter_map(|index| entities.get_index_of(index))
+            .collect();
+
+        for pair in attached.windows(2) {
+            let a = find_root(&mut parents, pair[0]);
+            let b = find_root(&mut parents, pair[1]);
+            parents[a] = b;
+        }
+    }
+
+    // Groups are kept in the order of their first entity so that equal sized groups always
+    // come out in the same order
+    let mut groups: IndexMap<usize, Vec<EntityIndex>> = IndexMap::new();
+    for (i, index) in entities.keys().enumerate() {
+        groups
+            .entry(find_root(&mut parents, i))
+            .or_default()
+            .push(*index);
+    }
+
+    let mut groups: Vec<Vec<EntityIndex>> = groups.into_values().collect();
+    groups.sort_by_key(|group| std::cmp::Reverse(group.len()));
+
+    groups
+}
+
+/// Places every group wholly into one of `n` chunks, each into whichever chunk currently holds
+/// the fewest entities. Chunks that end up empty are left out.
+fn pack_groups(entities: &Entities, groups: &[Vec<EntityIndex>], n: usize) -> Vec<Entities> {
+    let mut chunks: Vec<Entities> = vec![Entities::new(); n.max(1)];
+
+    for group in groups {
+        let chunk = chunks
+            .iter_mut()
+            .min_by_key(|chunk| chunk.len())
+            .expect("There is always at least one chunk");
+
+        for index in group {
+            chunk.insert(*index, entities[index].clone());
+        }
+    }
+
+    chunks.retain(|chunk| !chunk.is_empty());
+
+    chunks
+}
+
+/// Splits the `entities` into at most `n` spatial regions, so that every chunk is a
+/// contiguous section of the build.
+///
+/// The regions are found by recursive bisection: the groups are sorted along the axis in which
+/// their centroids are spread the furthest and cut where the entity counts match the share of
+/// regions each half still has to make. Groups are never split, so passing the result of
+/// `connected_groups` keeps constrained entities together.
+///
+/// # Arguments
+///
+/// * `entities` - The entities to be split.
+/// * `groups` - The indices of the `entities`, grouped by what must stay together.
+/// * `n` - The number of regions to split `entities` into.
+pub fn split_spatial(entities: &Entities, groups: &[Vec<EntityIndex>], n: usize) -> Vec<Entities> {
+    let mut units = group_centroids(entities, groups);
+
+    let mut regions = Vec::new();
+    bisect(&mut units, n.max(1), &mut regions);
+
+    region_chunks(entities, regions)
+}
+
+/// A group of entities that is never split, with the centroid of its entity positions.
+type Unit<'a> = (Vec3, &'a Vec<EntityIndex>);
+
+/// Returns every group with the centroid of its entity positions, or the origin when none of its
+/// entities have a position.
+fn group_centroids<'a>(entities: &Entities, groups: &'a [Vec<EntityIndex>]) -> Vec<Unit<'a>> {
+    groups
+        .iter()
+        .map(|group| {
+            let positions: Vec<Vec3> = group
+                .iter()
+                .filter_map(|index| entities[index].pos)
+                .collect();
+
+            (centroid(&positions).unwrap_or([0.0; 3]), group)
+        })
+        .collect()
+}
+
+/// Returns the entities of every region, leaving out empty ones.
+fn region_chunks(entities: &Entities, regions: Vec<Vec<&Vec<EntityIndex>>>) -> Vec<Entities> {
+    regions
+        .into_iter()
+        .map(|region| {
+            region
+                .iter()
+                .flat_map(|group| group.iter())
+                .map(|index| (*index, entities[index].clone()))
+                .collect::<Entities>()
+        })
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
+/// Recursively cuts `units` in two until `n` regions have been pushed onto `regions`.
+fn bisect<'a>(units: &mut [Unit<'a>], n: usize, regions: &mut Vec<Vec<&'a Vec<EntityIndex>>>) {
+    if n <= 1 || units.len() <= 1 {
+        regions.push(units.iter().map(|(_, group)| *group).collect());
+        return;
+    }
+
+    let left_n = n / 2;
+    let cut = cut_units(units, left_n, n);
+    let (left, right) = units.split_at_mut(cut);
+    bisect(left, left_n, regions);
+    bisect(right, n - left_n, regions);
+}
+
+/// Recursively cuts `units` in two until every region pushed onto `regions` holds at most
+/// `max_props` entities, or is a single group. Every cut aims for as few regions as the entity
+/// count of its side needs.
+fn bisect_max_props<'a>(
+    units: &mut [Unit<'a>],
+    max_props: usize,
+    regions: &mut Vec<Vec<&'a Vec<EntityIndex>>>,
+) {
+    let total: usize = units.iter().map(|(_, group)| group.len()).sum();
+    if total <= max_props || units.len() <= 1 {
+        regions.push(units.iter().map(|(_, group)| *group).collect());
+        return;
+    }
+
+    let n = total.div_ceil(max_props);
+    let cut = cut_units(units, n / 2, n);
+    let (left, right) = units.split_at_mut(cut);
+    bisect_max_props(left, max_props, regions);
+    bisect_max_props(right, max_props, regions);
+}
+
+/// Sorts `units` along the axis their centroids are spread the furthest in and returns where to
+/// cut them, so that the left side holds the share of the entities of `left_n` out of `n`
+/// regions. Both sides keep at least one unit when there are two or more.
+fn cut_units(units: &mut [Unit], left_n: usize, n: usize) -> usize {
+    // Cut along the axis the centroids are spread the furthest in
+    let extent = |axis: usize| {
+        let values = units.iter().map(|(position, _)| position[axis]);
+        let max = values.clone().fold(f64::MIN, f64::max);
+        let min = values.fold(f64::MAX, f64::min);
+
+        max - min
+    };
+    let axis = (0..3)
+        .max_by(|a, b| extent(*a).total_cmp(&extent(*b)))
+        .unwrap();
+
+    units.sort_by(|a, b| a.0[axis].total_cmp(&b.0[axis]));
+
+    // The left half makes left_n regions, so it takes that share of the entities
+    let total: usize = units.iter().map(|(_, group)| group.len()).sum();
+    let target = total * left_n / n;
+
+    let mut cut = 0;
+    let mut weight = 0;
+    while cut < units.len() - 1 && weight + units[cut].1.len() / 2 < target {
+        weight += units[cut].1.len();
+        cut += 1;
+    }
+    cut.max(1)
+}
+
+/// Returns the average of `positions`, or `None` if there are none.
+fn centroid(positions: &[Vec3]) -> Option<Vec3> {
+    if positions.is_empty() {
+        return None;
+    }
+
+    let mut sum = [0.0; 3];
+    for position in positions {
+        for axis in 0..3 {
+            sum[axis] += position[axis];
+        }
+    }
+
+    let count = positions.len() as f64;
+    Some([sum[0] / count, sum[1] / count, sum[2] / count])
+}
+
+/// Returns the index of the entity in `part` that is closest to the centroid of the part's
+/// entity positions. Entities without a position are only picked when none have one.
+pub fn head_entity(part: &Entities) -> Option<EntityIndex> {
+    let positioned: Vec<(EntityIndex, Vec3)> = part
+        .iter()
+        .filter_map(|(index, entity)| Some((*index, entity.pos?)))
+        .collect();
+
+    let positions: Vec<Vec3> = positioned.iter().map(|(_, position)| *position).collect();
+    let centre = match centroid(&positions) {
+        Some(centre) => centre,
+        None => return part.keys().next().copied(),
+    };
+
+    let distance = |position: &Vec3| {
+        (0..3)
+            .map(|axis| (position[axis] - centre[axis]).powi(2))
+            .sum::<f64>()
+    };
+
+    positioned
+        .into_iter()
+        .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+        .map(|(index, _)| index)
+}
+
+/// Returns the root of `i` in the union find `parents`, compressing the path along the way.
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+
+    i
+}
+
+/// Splits the given `map` into `n` chunks.
+///
+/// # Arguments
+///
+/// * `map` - A reference to a map, such as a `LuaTable` or the `Entities` of a dupe, to be split.
+/// * `n` - The number of chunks to split `map` into.
+///
+/// # Returns
+///
+/// A `Vec` of maps, where each map is a chunk of the original `map`.
+///
+/// # Example
+///
+/// ```
+/// use adv2_splitter::codec::{LuaTable, LuaValue};
+/// use adv2_splitter::split::split_map;
+///
+/// let mut map = LuaTable::new();
+/// map.insert(LuaValue::String(String::from("Key1")), LuaValue::Double(1.0));
+/// map.insert(LuaValue::String(String::from("Key2")), LuaValue::Double(2.0));
+/// map.insert(LuaValue::String(String::from("Key3")), LuaValue::Double(3.0));
+///
+/// let chunks = split_map(&map, 2);
+/// assert_eq!(chunks.len(), 2);
+/// ```
+pub fn split_map<K: Clone + Hash + Eq, V: Clone>(
+    map: &IndexMap<K, V>,
+    n: usize,
+) -> Vec<IndexMap<K, V>> {
+    // Never make more chunks than there are items, and always make at least one
+    let n = n.clamp(1, map.len().max(1));
+
+    // Calculate the chunk size as the length of the map divided by n, the first `remainder`
+    // chunks take one extra item so that the sizes never differ by more than one
+    let chunk_size = map.len() / n;
+    let remainder = map.len() % n;
+
+    // Create a vec to store the chunks
+    let mut chunks = Vec::new();
+
+    // Create a map to store the current chunk
+    let mut chunk = IndexMap::new();
+
+    // Iterate through each key-value pair in the map
+    for (key, value) in map.iter() {
+        // Insert the key-value pair into the current chunk
+        chunk.insert(key.clone(), value.clone());
+
+        // Once the chunk is full, add it to the chunks vec and start a new one
+        let target = chunk_size + usize::from(chunks.len() < remainder);
+        if chunk.len() == target {
+            chunks.push(chunk);
+            chunk = IndexMap::new();
+        }
+    }
+
+    // An empty map still produces a single, empty chunk
+    if chunks.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::{LuaTable, LuaValue};
+    use crate::dupe::{Dupe, Entities};
+    use crate::{lua_array, lua_string, lua_table};
+
+    use super::{
+        connected_groups, head_entity, merge_dupes, split_connected, split_dupe, split_map,
+        split_max_props, split_spatial,
+    };
+
+    fn entity() -> LuaValue {
+        let mut entity = LuaTable::new();
+        entity.insert(lua_string!("Class"), lua_string!("prop_physics"));
+
+        lua_table!(entity)
+    }
+
+    fn constraint(kind: &str, ent1: f64, ent2: f64) -> LuaValue {
+        let mut attached_1 = LuaTable::new();
+        attached_1.insert(lua_string!("Index"), LuaValue::Double(ent1));
+        let mut attached_2 = LuaTable::new();
+        attached_2.insert(lua_string!("Index"), LuaValue::Double(ent2));
+
+        let mut constraint = LuaTable::new();
+        constraint.insert(lua_string!("Type"), lua_string!(kind));
+        constraint.insert(
+            lua_string!("Entity"),
+            lua_array!(vec![lua_table!(attached_1), lua_table!(attached_2)]),
+        );
+
+        lua_table!(constraint)
+    }
+
+    fn dupe(entity_count: usize, constraints: Vec<LuaValue>) -> Dupe {
+        let mut entities = LuaTable::new();
+        for i in 1..=entity_count {
+            entities.insert(LuaValue::Double(i as f64), entity());
+        }
+
+        let mut head_ent = LuaTable::new();
+        head_ent.insert(lua_string!("Index"), LuaValue::Double(1.0));
+
+        let mut dupe = LuaTable::new();
+        dupe.insert(lua_string!("Entities"), lua_table!(entities));
+        dupe.insert(lua_string!("Constraints"), lua_array!(constraints));
+        dupe.insert(lua_string!("HeadEnt"), lua_table!(head_ent));
+
+        Dupe::from_lua(&lua_table!(dupe)).unwrap()
+    }
+
+    #[test]
+    fn test_split_connected_keeps_groups_together() {
+        let mut rope = LuaTable::new();
+        rope.insert(lua_string!("Type"), lua_string!("Rope"));
+        rope.insert(lua_string!("Ent1"), LuaValue::Double(3.0));
+        rope.insert(lua_string!("Ent2"), LuaValue::Double(4.0));
+
+        let dupe = dupe(
+            6,
+            vec![
+                constraint("Weld", 1.0, 2.0),
+                constraint("Weld", 2.0, 5.0),
+                lua_table!(rope),
+            ],
+        );
+
+        let chunks = split_connected(&dupe.entities, &dupe.constraints, 2);
+        assert_eq!(chunks.len(), 2);
+
+        let chunk_of = |index: u32| {
+            chunks
+                .iter()
+                .position(|chunk| chunk.contains_key(&index))
+                .expect("Entity was not placed in a chunk")
+        };
+
+        assert_eq!(chunk_of(1), chunk_of(2));
+        assert_eq!(chunk_of(2), chunk_of(5));
+        assert_eq!(chunk_of(3), chunk_of(4));
+        assert_eq!(chunks.iter().map(Entities::len).sum::<usize>(), 6);
+    }
+
+    #[test]
+    fn test_split_filters_constraints() {
+        let dupe = dupe(
+            4,
+            vec![constraint("Weld", 1.0, 2.0), constraint("Weld", 3.0, 4.0)],
+        );
+
+        let chunks = split_connected(&dupe.entities, &dupe.constraints, 2);
+        let splits = split_dupe(&dupe, &chunks, false);
+
+        for split in splits {
+            assert_eq!(split.constraints.len(), 1);
+            for index in split.constraints[0].attached() {
+                assert!(split.entities.contains_key(&index));
+            }
+
+            // The constraints are still written as an array
+            let split = split.to_lua();
+            let split = split.as_table().unwrap();
+            let entities = split[&lua_string!("Entities")].as_table().unwrap();
+            let constraints = split[&lua_string!("Constraints")].as_array().unwrap();
+
+            assert_eq!(constraints.len(), 1);
+
+            let attached = constraints[0].as_table().unwrap()[&lua_string!("Entity")]
+                .as_array()
+                .unwrap();
+            for entity in attached {
+                let index = &entity.as_table().unwrap()[&lua_string!("Index")];
+                assert!(entities.contains_key(index));
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_map_balances_chunks() {
+        let entities = dupe(11, Vec::new()).entities;
+
+        let mut sizes: Vec<usize> = split_map(&entities, 3).iter().map(Entities::len).collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![3, 4, 4]);
+
+        // More chunks than entities gives one entity per chunk
+        assert_eq!(split_map(&entities, 20).len(), 11);
+    }
+
+    #[test]
+    fn test_split_map_follows_table_order() {
+        let mut entities = Entities::new();
+        for i in [5, 3, 1, 6, 4, 2] {
+            entities.insert(i, Default::default());
+        }
+
+        let chunks: Vec<Vec<u32>> = split_map(&entities, 3)
+            .iter()
+            .map(|chunk| chunk.keys().copied().collect())
+            .collect();
+
+        assert_eq!(chunks, vec![vec![5, 3], vec![1, 6], vec![4, 2]]);
+    }
+
+    #[test]
+    fn test_split_max_props() {
+        let entities = dupe(11, Vec::new()).entities;
+
+        for ungrouped in [false, true] {
+            let chunks = split_max_props(&entities, &[], 4, ungrouped, false).unwrap();
+
+            assert_eq!(chunks.len(), 3);
+            assert!(chunks.iter().all(|chunk| chunk.len() <= 4));
+            assert_eq!(chunks.iter().map(Entities::len).sum::<usize>(), 11);
+        }
+
+        assert!(split_max_props(&entities, &[], 0, false, false).is_err());
+    }
+
+    #[test]
+    fn test_split_max_props_with_groups() {
+        // Three welded pairs with at most three props per part can not fit in two parts
+        let dupe = dupe(
+            6,
+            vec![
+                constraint("Weld", 1.0, 2.0),
+                constraint("Weld", 3.0, 4.0),
+                constraint("Weld", 5.0, 6.0),
+            ],
+        );
+        let entities = &dupe.entities;
+        let constraints = dupe.constraints.as_slice();
+
+        let chunks = split_max_props(entities, constraints, 3, false, false).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.len() == 2));
+
+        // A welded pair can never fit in a single prop part
+        assert!(split_max_props(entities, constraints, 1, false, false).is_err());
+        assert_eq!(
+            split_max_props(entities, constraints, 1, true, false)
+                .unwrap()
+                .len(),
+            6
+        );
+    }
+
+    fn positioned_entities(positions: &[[f64; 3]]) -> Entities {
+        let mut entities = Entities::new();
+
+        for (i, position) in positions.iter().enumerate() {
+            let mut entity = LuaTable::new();
+            entity.insert(lua_string!("Pos"), LuaValue::Vector(position.to_vec()));
+
+            let entity = crate::dupe::Entity::from_lua(&lua_table!(entity)).unwrap();
+            entities.insert((i + 1) as u32, entity);
+        }
+
+        entities
+    }
+
+    #[test]
+    fn test_split_spatial_separates_clusters() {
+        // Two clusters of three entities, far apart along the y axis
+        let entities = positioned_entities(&[
+            [0.0, 0.0, 0.0],
+            [10.0, 1000.0, 0.0],
+            [5.0, 5.0, 0.0],
+            [0.0, 1010.0, 5.0],
+            [3.0, 2.0, 1.0],
+            [8.0, 990.0, 0.0],
+        ]);
+
+        let groups = connected_groups(&entities, &[]);
+        let chunks = split_spatial(&entities, &groups, 2);
+        assert_eq!(chunks.len(), 2);
+
+        for chunk in &chunks {
+            let mut keys: Vec<u32> = chunk.keys().copied().collect();
+            keys.sort();
+
+            assert!(keys == vec![1, 3, 5] || keys == vec![2, 4, 6]);
+        }
+
+        // Every entity in its own region once there are as many regions as entities
+        assert_eq!(split_spatial(&entities, &groups, 10).len(), 6);
+
+        let chunks = split_max_props(&entities, &[], 3, true, true).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.len() == 3));
+    }
+
+    #[test]
+    fn test_split_max_props_spatial_cuts_full_regions() {
+        // A welded triple at x = 0 and welded pairs at x = 1, 2 and 3. The triple fills the
+        // left side of every cut, so the regions always put two of the pairs together
+        let entities = positioned_entities(&[
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [3.0, 0.0, 0.0],
+            [3.0, 0.0, 0.0],
+        ]);
+        let dupe = dupe(
+            9,
+            vec![
+                constraint("Weld", 1.0, 2.0),
+                constraint("Weld", 2.0, 3.0),
+                constraint("Weld", 4.0, 5.0),
+                constraint("Weld", 6.0, 7.0),
+                constraint("Weld", 8.0, 9.0),
+            ],
+        );
+        let constraints = dupe.constraints.as_slice();
+
+        let groups = connected_groups(&entities, constraints);
+        assert!(split_spatial(&entities, &groups, groups.len())
+            .iter()
+            .any(|chunk| chunk.len() > 3));
+
+        // The region holding those pairs is cut again
+        let chunks = split_max_props(&entities, constraints, 3, false, true).unwrap();
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 3));
+    }
+
+    #[test]
+    fn test_split_max_props_spatial_keeps_sections_contiguous() {
+        // A row of 60 welded pairs is cut into contiguous sections rather than a part per pair
+        let positions: Vec<[f64; 3]> = (0..120).map(|i| [(i / 2) as f64, 0.0, 0.0]).collect();
+        let entities = positioned_entities(&positions);
+        let welds = (0..60)
+            .map(|i| constraint("Weld", (2 * i + 1) as f64, (2 * i + 2) as f64))
+            .collect();
+        let dupe = dupe(120, welds);
+        let constraints = dupe.constraints.as_slice();
+
+        let chunks = split_max_props(&entities, constraints, 25, false, true).unwrap();
+        assert!(chunks.len() <= 6);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 25));
+
+        let mut ranges: Vec<(f64, f64)> = chunks
+            .iter()
+            .map(|chunk| {
+                let x = chunk.values().map(|entity| entity.pos.unwrap()[0]);
+                (
+                    x.clone().fold(f64::MAX, f64::min),
+                    x.fold(f64::MIN, f64::max),
+                )
+            })
+            .collect();
+        ranges.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+    }
+
+    #[test]
+    fn test_head_entity_is_closest_to_centroid() {
+        let entities = positioned_entities(&[
+            [0.0, 0.0, 0.0],
+            [100.0, 0.0, 0.0],
+            [40.0, 10.0, 0.0],
+            [75.0, -5.0, 0.0],
+            [200.0, 0.0, 0.0],
+        ]);
+
+        // The centroid is (83, 1, 0), closest to entity 4
+        assert_eq!(head_entity(&entities), Some(4));
+    }
+
+    #[test]
+    fn test_split_recomputes_head_ent() {
+        let mut entities = LuaTable::new();
+        for (i, position) in [[0.0, 0.0, 0.0], [100.0, 50.0, 10.0]].iter().enumerate() {
+            let mut entity = LuaTable::new();
+            entity.insert(lua_string!("Pos"), LuaValue::Vector(position.to_vec()));
+
+            entities.insert(LuaValue::Double((i + 1) as f64), lua_table!(entity));
+        }
+
+        // Give the second entity a physics object which should move with it
+        let mut physics_object = LuaTable::new();
+        physics_object.insert(
+            lua_string!("Pos"),
+            LuaValue::Vector(vec![100.0, 50.0, 12.0]),
+        );
+        entities
+            .get_mut(&LuaValue::Double(2.0))
+            .and_then(LuaValue::as_table_mut)
+            .unwrap()
+            .insert(
+                lua_string!("PhysicsObjects"),
+                lua_array!(vec![lua_table!(physics_object)]),
+            );
+
+        let mut head_ent = LuaTable::new();
+        head_ent.insert(lua_string!("Index"), LuaValue::Double(1.0));
+        head_ent.insert(
+            lua_string!("Pos"),
+            LuaValue::Vector(vec![-500.0, 20.0, 30.0]),
+        );
+        head_ent.insert(lua_string!("Z"), LuaValue::Double(5.0));
+
+        let mut dupe = LuaTable::new();
+        dupe.insert(lua_string!("Entities"), lua_table!(entities));
+        dupe.insert(lua_string!("HeadEnt"), lua_table!(head_ent));
+        let dupe = Dupe::from_lua(&lua_table!(dupe)).unwrap();
+
+        let mut part = dupe.entities.clone();
+        part.shift_remove(&1);
+
+        for recenter in [false, true] {
+            let splits = split_dupe(&dupe, &[part.clone()], recenter);
+            let split = splits[0].to_lua();
+            let split = split.as_table().unwrap();
+
+            let head_ent = split[&lua_string!("HeadEnt")].as_table().unwrap();
+            assert_eq!(head_ent[&lua_string!("Index")], LuaValue::Double(2.0));
+
+            let entity = split[&lua_string!("Entities")].as_table().unwrap()
+                [&LuaValue::Double(2.0)]
+                .as_table()
+                .unwrap();
+            let physics_object = entity[&lua_string!("PhysicsObjects")].as_array().unwrap()[0]
+                .as_table()
+                .unwrap();
+
+            // Pasted at its original position every entity stays where it was in the world
+            let head_position = head_ent[&lua_string!("Pos")].as_vector().unwrap();
+            let position = entity[&lua_string!("Pos")].as_vector().unwrap();
+            let world: Vec<f64> = head_position
+                .iter()
+                .zip(position)
+                .map(|(a, b)| a + b)
+                .collect();
+            assert_eq!(world, [-400.0, 70.0, 40.0]);
+
+            if recenter {
+                assert_eq!(head_ent[&lua_string!("Z")], LuaValue::Double(15.0));
+                assert_eq!(entity[&lua_string!("Pos")], LuaValue::Vector(vec![0.0; 3]));
+                assert_eq!(
+                    physics_object[&lua_string!("Pos")],
+                    LuaValue::Vector(vec![0.0, 0.0, 2.0])
+                );
+            } else {
+                assert_eq!(head_ent[&lua_string!("Z")], LuaValue::Double(5.0));
+                assert_eq!(
+                    entity[&lua_string!("Pos")],
+                    LuaValue::Vector(vec![100.0, 50.0, 10.0])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_renumbers_entities() {
+        let first = dupe(2, vec![constraint("Weld", 1.0, 2.0)]);
+
+        // The second dupe refers to its entities by `Ent` keys as well, and to the world as 0
+        let mut rope = constraint("Rope", 1.0, 0.0).as_table().unwrap().clone();
+        rope.insert(lua_string!("Ent1"), LuaValue::Double(1.0));
+        rope.insert(lua_string!("Ent2"), LuaValue::Double(0.0));
+        let mut second = dupe(2, vec![lua_table!(rope)]);
+        second.entities = positioned_entities(&[[0.0, 0.0, 0.0], [10.0, 0.0, 0.0]]);
+
+        let merged = merge_dupes(&[&first, &second], &[[0.0; 3], [100.0, 0.0, 50.0]]).unwrap();
+        let merged = merged.to_lua();
+        let merged = merged.as_table().unwrap();
+
+        let entities = merged[&lua_string!("Entities")].as_table().unwrap();
+        assert_eq!(entities.len(), 4);
+        assert_eq!(
+            entities[&LuaValue::Double(4.0)].as_table().unwrap()[&lua_string!("Pos")],
+            LuaValue::Vector(vec![110.0, 0.0, 50.0])
+        );
+
+        let constraints = merged[&lua_string!("Constraints")].as_array().unwrap();
+        assert_eq!(constraints.len(), 2);
+
+        let weld = constraints[0].as_table().unwrap();
+        let weld_entities = weld[&lua_string!("Entity")].as_array().unwrap();
+        assert_eq!(
+            weld_entities[1].as_table().unwrap()[&lua_string!("Index")],
+            LuaValue::Double(2.0)
+        );
+
+        let rope = constraints[1].as_table().unwrap();
+        assert_eq!(rope[&lua_string!("Ent1")], LuaValue::Double(3.0));
+        assert_eq!(rope[&lua_string!("Ent2")], LuaValue::Double(0.0));
+        let rope_entities = rope[&lua_string!("Entity")].as_array().unwrap();
+        assert_eq!(
+            rope_entities[0].as_table().unwrap()[&lua_string!("Index")],
+            LuaValue::Double(3.0)
+        );
+
+        let head_ent = merged[&lua_string!("HeadEnt")].as_table().unwrap();
+        assert_eq!(head_ent[&lua_string!("Index")], LuaValue::Double(1.0));
+
+        // Every dupe needs an offset, none are dropped
+        assert!(merge_dupes(&[&first, &second], &[[0.0; 3]]).is_err());
+        assert!(merge_dupes(&[], &[]).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::advanced_dupe;
+use crate::codec::{AdCodec5, LuaValue, MAX_COMPRESSION_LEVEL};
+use crate::dupe::{Dupe, Vec3};
+
+/// What a dupe is made of, to tell why it is heavy before splitting it.
+///
+/// # Example
+///
+/// ```
+/// use adv2_splitter::codec::{LuaTable, LuaValue};
+/// use adv2_splitter::stats::DupeStats;
+/// use adv2_splitter::{lua_string, lua_table};
+///
+/// let mut entity = LuaTable::new();
+/// entity.insert(lua_string!("Class"), lua_string!("gmod_wire_expression2"));
+/// entity.insert(lua_string!("Pos"), LuaValue::Vector(vec![1.0, 2.0, 3.0]));
+///
+/// let mut entities = LuaTable::new();
+/// entities.insert(LuaValue::Double(1.0), lua_table!(entity));
+///
+/// let mut table = LuaTable::new();
+/// table.insert(lua_string!("Entities"), lua_table!(entities));
+///
+/// let stats = DupeStats::from_value(&lua_table!(table), 5).unwrap();
+/// assert_eq!(stats.entities, 1);
+/// assert_eq!(stats.expression2_entities, 1);
+/// assert_eq!(stats.largest[0].path, "Entities");
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DupeStats {
+    /// The number of entities
+    pub entities: usize,
+    /// The number of constraints
+    pub constraints: usize,
+    /// The number of entities of every `Class`, most common first
+    pub classes: Vec<Count>,
+    /// The number of entities of every `Model`, most common first
+    pub models: Vec<Count>,
+    /// The number of constraints of every `Type`, most common first
+    pub constraint_types: Vec<Count>,
+    /// The number of Wiremod entities, whose class starts with `gmod_wire_`
+    pub wire_entities: usize,
+    /// The number of Expression 2 chips, which are also counted as Wiremod entities
+    pub expression2_entities: usize,
+    /// The box around the `Pos` of every entity and physics object, if any has one
+    pub bounds: Option<Bounds>,
+    /// The size of the uncompressed data block in bytes
+    pub serialized_size: usize,
+    /// The size of the compressed data block in bytes
+    pub compressed_size: usize,
+    /// The tables of the data that cost the most bytes, largest first
+    pub largest: Vec<Subtree>,
+}
+
+/// The number of times a name was seen.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+/// A box aligned with the axes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+/// A table or array of the dupe data and the bytes it takes in the data block.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Subtree {
+    /// The keys leading to the value, joined by dots
+    pub path: String,
+    /// The bytes the value takes when written out in full, without back-references
+    pub size: usize,
+}
+
+impl DupeStats {
+    /// Gathers the statistics of a whole dupe file of any supported codec version, with the
+    /// sizes of the data block as it is stored in the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The dupe file.
+    /// * `top` - The number of largest subtrees to keep.
+    pub fn from_file(file: &[u8], top: usize) -> Result<DupeStats, Box<dyn Error>> {
+        let result = advanced_dupe::decode(file)?;
+        let mut stats = Self::gather(result.value(), top)?;
+
+        stats.serialized_size = AdCodec5::decompress_data_block(file)?.len();
+        stats.compressed_size = file.len() - AdCodec5::data_block_offset(file).unwrap_or(0);
+
+        Ok(stats)
+    }
+
+    /// Gathers the statistics of the data of a dupe, with the sizes of the data block it is
+    /// written as by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The dupe data, the table held by a `DuplicationResult`.
+    /// * `top` - The number of largest subtrees to keep.
+    pub fn from_value(value: &LuaValue, top: usize) -> Result<DupeStats, Box<dyn Error>> {
+        let mut stats = Self::gather(value, top)?;
+
+        let mut data = Vec::new();
+        AdCodec5::encode_stream(value, &mut data)?;
+        let compressed = gmod_lzma::compress(&data, MAX_COMPRESSION_LEVEL as i32)
+            .map_err(|_| std::io::Error::other("Unable to compress the data block"))?;
+
+        stats.serialized_size = data.len();
+        stats.compressed_size = compressed.len();
+
+        Ok(stats)
+    }
+
+    /// Gathers everything but the sizes of the data block.
+    fn gather(value: &LuaValue, top: usize) -> Result<DupeStats, Box<dyn Error>> {
+        let dupe = Dupe::from_lua(value)?;
+
+        let classes = dupe.entities.values().map(|entity| entity.class.as_deref());
+        let models = dupe.entities.values().map(|entity| entity.model.as_deref());
+        let constraint_types = dupe
+            .constraints
+            .iter()
+            .map(|constraint| constraint.kind.as_deref());
+
+        let mut wire_entities = 0;
+        let mut expression2_entities = 0;
+        for class in dupe
+            .entities
+            .values()
+            .filter_map(|entity| entity.class.as_deref())
+        {
+            if class.starts_with("gmod_wire_") {
+                wire_entities += 1;
+            }
+            if class == "gmod_wire_expression2" {
+                expression2_entities += 1;
+            }
+        }
+
+        let positions = dupe.entities.values().flat_map(|entity| {
+            let physics_objects = entity
+                .physics_objects
+                .iter()
+                .filter_map(|object| object.pos);
+            entity.pos.into_iter().chain(physics_objects)
+        });
+        let bounds = positions.fold(None, |bounds: Option<Bounds>, pos| match bounds {
+            None => Some(Bounds { min: pos, max: pos }),
+            Some(Bounds { min, max }) => Some(Bounds {
+                min: [0, 1, 2].map(|i| min[i].min(pos[i])),
+                max: [0, 1, 2].map(|i| max[i].max(pos[i])),
+            }),
+        });
+
+        let mut largest = BinaryHeap::new();
+        collect_subtrees(value, &mut Vec::new(), top, &mut largest)?;
+        let largest = largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, Reverse(path)))| Subtree { path, size })
+            .collect();
+
+        Ok(DupeStats {
+            entities: dupe.entities.len(),
+            constraints: dupe.constraints.len(),
+            classes: count(classes),
+            models: count(models),
+            constraint_types: count(constraint_types),
+            wire_entities,
+            expression2_entities,
+            bounds,
+            serialized_size: 0,
+            compressed_size: 0,
+            largest,
+        })
+    }
+}
+
+impl Display for DupeStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} entities, {} constraints",
+            self.entities, self.constraints
+        )?;
+        writeln!(
+            f,
+            "\tData block: {} bytes, {} bytes compressed",
+            self.serialized_size, self.compressed_size
+        )?;
+        writeln!(
+            f,
+            "\tWire entities: {}, of which Expression 2 chips: {}",
+            self.wire_entities, self.expression2_entities
+        )?;
+
+        match &self.bounds {
+            Some(Bounds { min, max }) => writeln!(
+                f,
+                "\tBounds: {:?} to {:?}, {} x {} x {}",
+                min,
+                max,
+                max[0] - min[0],
+                max[1] - min[1],
+                max[2] - min[2]
+            )?,
+            None => writeln!(f, "\tBounds: no positions")?,
+        }
+
+        for (title, counts) in [
+            ("Classes", &self.classes),
+            ("Models", &self.models),
+            ("Constraint types", &self.constraint_types),
+        ] {
+            writeln!(f, "\t{}:", title)?;
+            for Count { name, count } in counts {
+                writeln!(f, "\t\t{}: {}", name, count)?;
+            }
+        }
+
+        write!(f, "\tLargest subtrees:")?;
+        for Subtree { path, size } in &self.largest {
+            write!(f, "\n\t\t{}: {} bytes", path, size)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts every name, with a missing name counted as `(none)`, most common first and then by
+/// name.
+fn count<'a>(names: impl Iterator<Item = Option<&'a str>>) -> Vec<Count> {
+    let mut counts: Vec<Count> = Vec::new();
+
+    for name in names {
+        let name = name.unwrap_or("(none)");
+        match counts.iter_mut().find(|count| count.name == name) {
+            Some(count) => count.count += 1,
+            None => counts.push(Count {
+                name: name.to_string(),
+                count: 1,
+            }),
+        }
+    }
+
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    counts
+}
+
+/// The largest subtrees seen so far, ranked by size and then by path, with the one that ranks
+/// last on top so it can be dropped for a larger one.
+type Largest = BinaryHeap<Reverse<(usize, Reverse<String>)>>;
+
+/// Adds every table and array under `value` to `largest`, keeping only the `top` that cost the
+/// most bytes, and returns the bytes `value` costs. The size of a table is added up from those
+/// of its entries, so every value is only encoded once.
+fn collect_subtrees(
+    value: &LuaValue,
+    path: &mut Vec<String>,
+    top: usize,
+    largest: &mut Largest,
+) -> Result<usize, Box<dyn Error>> {
+    let children: Vec<(String, Option<&LuaValue>, &LuaValue)> = match value {
+        LuaValue::Table(table) => table
+            .iter()
+            .map(|(key, value)| (AdCodec5::path_segment(key), Some(key), value))
+            .collect(),
+        LuaValue::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(i, value)| ((i + 1).to_string(), None, value))
+            .collect(),
+        _ => return encoded_size(value),
+    };
+
+    // The data type of the table and the terminator after its entries
+    let mut size = 2;
+    for (segment, key, child) in children {
+        if let Some(key) = key {
+            size += encoded_size(key)?;
+        }
+
+        if !matches!(child, LuaValue::Table(_) | LuaValue::Array(_)) {
+            size += encoded_size(child)?;
+            continue;
+        }
+
+        path.push(segment);
+        let child_size = collect_subtrees(child, path, top, largest)?;
+        keep_largest(largest, top, child_size, path);
+        path.pop();
+
+        size += child_size;
+    }
+
+    Ok(size)
+}
+
+/// Adds a subtree to `largest` if it is among the `top` largest seen so far.
+fn keep_largest(largest: &mut Largest, top: usize, size: usize, path: &[String]) {
+    if top == 0 {
+        return;
+    }
+
+    // The path is only joined for a subtree that makes it in
+    if largest.len() == top
+        && matches!(largest.peek(), Some(Reverse((smallest, _))) if size < *smallest)
+    {
+        return;
+    }
+
+    largest.push(Reverse((size, Reverse(path.join(".")))));
+    if largest.len() > top {
+        largest.pop();
+    }
+}
+
+/// Returns the bytes a value takes in the data block when written out in full, used for the
+/// values that are not tables or arrays.
+fn encoded_size(value: &LuaValue) -> Result<usize, Box<dyn Error>> {
+    let mut counter = ByteCounter(0);
+    AdCodec5::encode_stream(value, &mut counter)?;
+
+    Ok(counter.0)
+}
+
+/// A writer that only counts the bytes written to it.
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::{AdCodec, AdCodec5, DuplicationInfo, LuaTable, LuaValue};
+    use crate::{lua_array, lua_string, lua_table};
+
+    use super::{Bounds, Count, DupeStats};
+
+    fn entity(class: &str, pos: [f64; 3]) -> LuaValue {
+        let mut entity = LuaTable::new();
+        entity.insert(lua_string!("Class"), lua_string!(class));
+        entity.insert(lua_string!("Model"), lua_string!("models/cube.mdl"));
+        entity.insert(lua_string!("Pos"), LuaValue::Vector(pos.to_vec()));
+
+        lua_table!(entity)
+    }
+
+    fn dupe() -> LuaValue {
+        let mut entities = LuaTable::new();
+        entities.insert(
+            LuaValue::Double(1.0),
+            entity("prop_physics", [0.0, 5.0, -1.0]),
+        );
+        entities.insert(
+            LuaValue::Double(2.0),
+            entity("prop_physics", [2.0, -3.0, 4.0]),
+        );
+        entities.insert(
+            LuaValue::Double(3.0),
+            entity("gmod_wire_expression2", [1.0, 1.0, 1.0]),
+        );
+
+        let mut constraint = LuaTable::new();
+        constraint.insert(lua_string!("Type"), lua_string!("Weld"));
+
+        let mut table = LuaTable::new();
+        table.insert(lua_string!("Entities"), lua_table!(entities));
+        table.insert(
+            lua_string!("Constraints"),
+            lua_array!(vec![lua_table!(constraint)]),
+        );
+
+        lua_table!(table)
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = DupeStats::from_value(&dupe(), 3).unwrap();
+
+        assert_eq!(stats.entities, 3);
+        assert_eq!(stats.constraints, 1);
+        assert_eq!(
+            stats.classes,
+            vec![
+                Count {
+                    name: String::from("prop_physics"),
+                    count: 2,
+                },
+                Count {
+                    name: String::from("gmod_wire_expression2"),
+                    count: 1,
+                },
+            ]
+        );
+        assert_eq!(stats.models.len(), 1);
+        assert_eq!(stats.constraint_types[0].name, "Weld");
+        assert_eq!(stats.wire_entities, 1);
+        assert_eq!(stats.expression2_entities, 1);
+        assert_eq!(
+            stats.bounds,
+            Some(Bounds {
+                min: [0.0, -3.0, -1.0],
+                max: [2.0, 5.0, 4.0],
+            })
+        );
+
+        // The entity with the longest class costs the most after the table holding it, and
+        // entities of the same size are ordered by their path
+        let paths: Vec<&str> = stats.largest.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, ["Entities", "Entities.3", "Entities.1"]);
+        assert!(stats.largest[0].size > stats.largest[1].size);
+
+        // The sizes added up from the entries are those of the encoded tables
+        let value = dupe();
+        let table = value.as_table().unwrap();
+        let entities = &table[&lua_string!("Entities")];
+        for (subtree, value) in stats.largest.iter().zip([
+            entities,
+            &entities.as_table().unwrap()[&LuaValue::Double(3.0)],
+            &entities.as_table().unwrap()[&LuaValue::Double(1.0)],
+        ]) {
+            let mut data = Vec::new();
+            AdCodec5::encode_stream(value, &mut data).unwrap();
+            assert_eq!(subtree.size, data.len());
+        }
+
+        // Keeping fewer only drops the smallest
+        let top = DupeStats::from_value(&dupe(), 1).unwrap();
+        assert_eq!(top.largest, stats.largest[..1]);
+    }
+
+    #[test]
+    fn test_stats_from_file() {
+        let mut info = DuplicationInfo::default();
+        info.set_value("check", String::from("\r\n\t\n"));
+
+        let mut file = Vec::new();
+        AdCodec5::default()
+            .encode(&info, &dupe(), &mut file)
+            .unwrap();
+
+        // The sizes of the file agree with those of the data written by default
+        let from_file = DupeStats::from_file(&file, 3).unwrap();
+        let from_value = DupeStats::from_value(&dupe(), 3).unwrap();
+        assert_eq!(from_file, from_value);
+    }
+}